@@ -0,0 +1,121 @@
+// run with cargo bench. each program is evaluated many times in a fresh interpreter and the fastest run is
+// reported, along with how many evaluations that makes per second. cargo bench -- --save-baseline keeps the
+// timings, and later runs report how they compare to them, e.g. to compare a change against the code before it.
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use kytherust::Interpreter;
+
+// a loop written as recursion, each call in tail position
+const COUNTDOWN: &str = "const down = (n: Int) => { return n == 0 || down(n - 1); };
+down(20000);";
+
+// calls shaped like a naive Fibonacci, none in tail position: 21891 of them
+const CALLS: &str = "const tree = (n: Int) => { return n < 2 || tree(n - 1) == tree(n - 2); };
+tree(20);";
+
+// 10000 iterations of a callback doing arithmetic on locals and captured variables
+const LOOP: &str = "const xs = [XS];
+fold(Int, Int, xs, 0, (total: Int, a: Int) => {
+    return fold(Int, Int, xs, total, (sum: Int, b: Int) => {
+        let x = a * 3;
+        let y = b + x;
+        y = y % 7;
+        x = x - y;
+        return sum + x + y;
+    });
+});";
+
+const FIELDS: &str = "const xs = [XS];
+const Point = { x: Int, y: Int, z: Int };
+fold(Int, Int, xs, 0, (total: Int, a: Int) => {
+    return fold(Int, Int, xs, total, (sum: Int, b: Int) => {
+        let p = { x = a, y = b, z = a + b };
+        let q = { ...p, y = p.z };
+        return sum + q.x + q.y + q.z;
+    });
+});";
+
+// method calls on a struct, each looked up by the type of the value it is called on
+const METHODS: &str = "const xs = [XS];
+const Point = { x: Int, y: Int };
+impl Point { sum = (self: Point) => { return self.x + self.y; } };
+fold(Int, Int, xs, 0, (total: Int, a: Int) => {
+    return fold(Int, Int, xs, total, (sum: Int, b: Int) => {
+        return sum + { x = a, y = b }.sum();
+    });
+});";
+
+const BASELINE: &str = "target/vm-bench-baseline.txt";
+
+// the fastest run of a program, in milliseconds, unless it fails, e.g. in code from before what it uses was added
+fn bench(name: &str, source: &str, baseline: &HashMap<String, f64>) -> Option<f64> {
+    let xs = vec!["1"; 100].join(", ");
+    let source = source.replace("XS", &xs);
+    let mut fastest = Duration::MAX;
+    let mut result = String::new();
+
+    for _ in 0..20 {
+        let mut interpreter = Interpreter::new();
+        let start = Instant::now();
+        result = match interpreter.eval_str(&source) {
+            Ok(val) => val.to_string(),
+            Err(e) => {
+                println!("{:<10} failed: {}", name, e);
+                return None;
+            }
+        };
+        fastest = fastest.min(start.elapsed());
+    }
+
+    let ms = fastest.as_secs_f64() * 1000.0;
+    let change = match baseline.get(name) {
+        Some(before) => format!("  {:>+6.1}% vs baseline", (ms / before - 1.0) * 100.0),
+        None => String::new(),
+    };
+    println!(
+        "{:<10} {:>10.2} ms {:>10.1} runs/s{}  (= {})",
+        name,
+        ms,
+        1.0 / fastest.as_secs_f64(),
+        change,
+        result
+    );
+
+    Some(ms)
+}
+
+fn main() {
+    let save = env::args().any(|arg| arg == "--save-baseline");
+    let baseline: HashMap<String, f64> = match fs::read_to_string(BASELINE) {
+        Ok(saved) if !save => saved
+            .lines()
+            .filter_map(|line| {
+                let (name, ms) = line.split_once(' ')?;
+                Some((name.to_string(), ms.parse().ok()?))
+            })
+            .collect(),
+        _ => HashMap::new(),
+    };
+
+    let programs = [
+        ("countdown", COUNTDOWN),
+        ("calls", CALLS),
+        ("loop", LOOP),
+        ("fields", FIELDS),
+        ("methods", METHODS),
+    ];
+    let mut timings = String::new();
+    for (name, source) in programs {
+        if let Some(ms) = bench(name, source, &baseline) {
+            timings.push_str(&format!("{} {}\n", name, ms));
+        }
+    }
+
+    if save {
+        fs::write(BASELINE, timings).unwrap();
+        println!("saved as the baseline in {}", BASELINE);
+    }
+}
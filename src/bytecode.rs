@@ -0,0 +1,811 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::input_stream::Loc;
+use crate::symbol::Symbol;
+use crate::types::{Fields, Type};
+use crate::verify::{self, VerifyError};
+use crate::vm::{Function, Instruction, InternalVal, KytheraVal};
+
+// compiled programs saved to .kyc files. a file is:
+//
+//   magic        b"KYC\0"
+//   version      u16, which must be VERSION exactly
+//   checksum     u32, FNV-1a of the body
+//   body         the program's function
+//
+// a function is its name, parameter names, slot count, constants, instructions (with the functions made by
+// MakeClosure nested inside), the location of each instruction and its type. integers are little-endian and
+// lengths and strings are prefixed with their length as a u32.
+pub const MAGIC: &[u8; 4] = b"KYC\0";
+
+// bump whenever the instruction set or the encoding changes, so old files are rejected instead of misread
+pub const VERSION: u16 = 3;
+
+// how deeply types, values and functions can nest in a file, so that a malicious one cannot overflow the stack
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, PartialEq)]
+pub enum BytecodeError {
+    NotBytecode,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Truncated,
+    TrailingBytes,
+    InvalidTag { what: &'static str, tag: u8 },
+    // a list constant whose element type is given as a type that is not a list
+    InvalidListType(Type),
+    InvalidUtf8,
+    TooDeep,
+    // the instructions are well formed but could not run
+    Unverifiable(VerifyError),
+    // a constant that cannot be saved, such as a function
+    Unserializable(Type),
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid bytecode: ")?;
+
+        match self {
+            BytecodeError::NotBytecode => write!(f, "not a compiled Kythera file."),
+            BytecodeError::UnsupportedVersion(version) => write!(
+                f,
+                "compiled for bytecode version {} but this is version {}. Compile it again.",
+                version, VERSION
+            ),
+            BytecodeError::ChecksumMismatch => {
+                write!(f, "the file is corrupted (checksum mismatch).")
+            }
+            BytecodeError::Truncated => write!(f, "the file ends unexpectedly."),
+            BytecodeError::TrailingBytes => write!(f, "unexpected data after the program."),
+            BytecodeError::InvalidTag { what, tag } => write!(f, "unknown {} tag {}.", what, tag),
+            BytecodeError::InvalidListType(t) => write!(f, "a list constant has non-list type {}.", t),
+            BytecodeError::InvalidUtf8 => write!(f, "a string is not valid UTF-8."),
+            BytecodeError::TooDeep => write!(f, "nested more than {} levels deep.", MAX_DEPTH),
+            BytecodeError::Unverifiable(e) => write!(f, "{}", e),
+            BytecodeError::Unserializable(t) => write!(f, "cannot save a constant of type {}.", t),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+// the bytes of a .kyc file holding program
+pub fn serialize(program: &Function) -> Result<Vec<u8>, BytecodeError> {
+    let mut body = Writer::default();
+    body.function(program)?;
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&body.bytes).to_le_bytes());
+    bytes.extend_from_slice(&body.bytes);
+    Ok(bytes)
+}
+
+// the program held by the bytes of a .kyc file
+pub fn deserialize(bytes: &[u8]) -> Result<Rc<Function>, BytecodeError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(BytecodeError::NotBytecode);
+    }
+
+    let mut header = Reader::new(&bytes[MAGIC.len()..]);
+    let version = header.u16()?;
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let expected = header.u32()?;
+    let body = &header.bytes[header.pos..];
+    if checksum(body) != expected {
+        return Err(BytecodeError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader::new(body);
+    let program = reader.function()?;
+    if reader.pos != body.len() {
+        return Err(BytecodeError::TrailingBytes);
+    }
+
+    verify::verify(&program).map_err(BytecodeError::Unverifiable)?;
+    Ok(Rc::new(program))
+}
+
+// 32-bit FNV-1a
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn u16(&mut self, n: u16) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn len(&mut self, n: usize) {
+        self.u32(u32::try_from(n).expect("Too large to save."));
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn symbols(&mut self, symbols: &[Symbol]) {
+        self.len(symbols.len());
+        for symbol in symbols {
+            self.str(symbol.as_str());
+        }
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), BytecodeError> {
+        self.str(&function.name);
+        self.len(function.params.len());
+        for param in &function.params {
+            self.str(param);
+        }
+        self.len(function.slots);
+
+        self.len(function.constants.len());
+        for constant in &function.constants {
+            self.value(constant)?;
+        }
+
+        self.len(function.instructions.len());
+        for inst in &function.instructions {
+            self.instruction(inst)?;
+        }
+
+        self.len(function.locs.len());
+        for loc in &function.locs {
+            self.u32(loc.line);
+            self.u32(loc.col);
+        }
+
+        self.type_val(&function.type_val);
+        Ok(())
+    }
+
+    fn instruction(&mut self, inst: &Instruction) -> Result<(), BytecodeError> {
+        self.u8(opcode(inst));
+
+        match inst {
+            Instruction::Constant(index) => self.u16(*index),
+            Instruction::Invoke(n)
+            | Instruction::TailInvoke(n)
+            | Instruction::MakeList(n)
+            | Instruction::MakeFnType(n)
+            | Instruction::Jump(n)
+            | Instruction::JumpIf(n)
+            | Instruction::JumpIfNot(n) => self.len(*n),
+            Instruction::Field(name)
+            | Instruction::MakeVariant(name)
+            | Instruction::IsVariant(name)
+            | Instruction::DeclareGlobal(name)
+            | Instruction::StoreGlobal(name)
+            | Instruction::LoadGlobal(name) => self.str(name.as_str()),
+            Instruction::MakeStruct(names)
+            | Instruction::MakeStructType(names)
+            | Instruction::MakeUnionType(names)
+            | Instruction::Impl(names) => self.symbols(names),
+            Instruction::MakeClosure(function) => self.function(function)?,
+            Instruction::StoreLocal(slot) | Instruction::LoadLocal(slot) => self.u16(*slot),
+            Instruction::StoreCaptured(depth, slot) | Instruction::LoadCaptured(depth, slot) => {
+                self.u16(*depth);
+                self.u16(*slot);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn type_val(&mut self, t: &Type) {
+        match t {
+            Type::Unit => self.u8(0),
+            Type::Int => self.u8(1),
+            Type::Double => self.u8(2),
+            Type::Bool => self.u8(3),
+            Type::String => self.u8(4),
+            Type::Type => self.u8(5),
+            Type::Struct(fields) | Type::Union(fields) => {
+                self.u8(if matches!(t, Type::Struct(_)) { 6 } else { 7 });
+                self.len(fields.len());
+                for (name, field_type) in fields {
+                    self.str(name.as_str());
+                    self.type_val(field_type);
+                }
+            }
+            Type::List(element) => {
+                self.u8(8);
+                self.type_val(element);
+            }
+            Type::Fn { params, returns } => {
+                self.u8(9);
+                self.len(params.len());
+                for param in params {
+                    self.type_val(param);
+                }
+                self.type_val(returns);
+            }
+            Type::TypeParam(name) => {
+                self.u8(10);
+                self.str(name);
+            }
+            Type::Var(name) => {
+                self.u8(11);
+                self.str(name);
+            }
+        }
+    }
+
+    fn value(&mut self, val: &KytheraVal) -> Result<(), BytecodeError> {
+        match &val.val {
+            InternalVal::Unit => self.u8(0),
+            InternalVal::Int(n) => {
+                self.u8(1);
+                self.u32(*n as u32);
+            }
+            InternalVal::Double(d) => {
+                self.u8(2);
+                self.bytes.extend_from_slice(&d.to_bits().to_le_bytes());
+            }
+            InternalVal::String(s) => {
+                self.u8(3);
+                self.str(s);
+            }
+            InternalVal::Bool(b) => {
+                self.u8(4);
+                self.u8(*b as u8);
+            }
+            InternalVal::Type(t) => {
+                self.u8(5);
+                self.type_val(t);
+            }
+            InternalVal::Struct(fields) => {
+                self.u8(6);
+                self.len(fields.len());
+                for (name, field) in fields.iter() {
+                    self.str(name.as_str());
+                    self.value(field)?;
+                }
+            }
+            InternalVal::List(elements) => {
+                self.u8(7);
+                self.type_val(&val.type_val);
+                self.len(elements.len());
+                for element in elements.iter() {
+                    self.value(element)?;
+                }
+            }
+            InternalVal::Variant(tag, value) => {
+                self.u8(8);
+                self.type_val(&val.type_val);
+                self.str(tag.as_str());
+                self.value(value)?;
+            }
+            InternalVal::Fn(_) | InternalVal::Native(_) => {
+                return Err(BytecodeError::Unserializable(val.type_val.as_ref().clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // how many types, values and functions are being read inside each other
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader {
+            bytes,
+            pos: 0,
+            depth: 0,
+        }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.bytes.len());
+        let bytes = &self.bytes[self.pos..end.ok_or(BytecodeError::Truncated)?];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, BytecodeError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, BytecodeError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn len(&mut self) -> Result<usize, BytecodeError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn string(&mut self) -> Result<String, BytecodeError> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| BytecodeError::InvalidUtf8)
+    }
+
+    fn symbol(&mut self) -> Result<Symbol, BytecodeError> {
+        Ok(Symbol::from(self.string()?))
+    }
+
+    // read len items with item. nothing is allocated up front, since a corrupted length could be huge.
+    fn many<T>(
+        &mut self,
+        item: impl Fn(&mut Self) -> Result<T, BytecodeError>,
+    ) -> Result<Vec<T>, BytecodeError> {
+        let len = self.len()?;
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(item(self)?);
+        }
+
+        Ok(items)
+    }
+
+    // read something that can contain more of its kind
+    fn nested<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, BytecodeError>,
+    ) -> Result<T, BytecodeError> {
+        if self.depth == MAX_DEPTH {
+            return Err(BytecodeError::TooDeep);
+        }
+
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn function(&mut self) -> Result<Function, BytecodeError> {
+        self.nested(|reader| {
+            Ok(Function {
+                name: reader.string()?,
+                params: reader.many(Reader::string)?,
+                slots: reader.len()?,
+                constants: reader.many(Reader::value)?,
+                instructions: reader.many(Reader::instruction)?,
+                locs: reader.many(|reader| {
+                    Ok(Loc {
+                        line: reader.u32()?,
+                        col: reader.u32()?,
+                    })
+                })?,
+                type_val: Rc::new(reader.type_val()?),
+            })
+        })
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, BytecodeError> {
+        let tag = self.u8()?;
+
+        Ok(match tag {
+            0 => Instruction::Nop,
+            1 => Instruction::Constant(self.u16()?),
+            2 => Instruction::Add,
+            3 => Instruction::Sub,
+            4 => Instruction::Mul,
+            5 => Instruction::Div,
+            6 => Instruction::Mod,
+            7 => Instruction::Equal,
+            8 => Instruction::NotEqual,
+            9 => Instruction::Less,
+            10 => Instruction::LessEqual,
+            11 => Instruction::Greater,
+            12 => Instruction::GreaterEqual,
+            13 => Instruction::Not,
+            14 => Instruction::Or,
+            15 => Instruction::And,
+            16 => Instruction::Xor,
+            17 => Instruction::ShiftLeft,
+            18 => Instruction::ShiftRight,
+            19 => Instruction::Invoke(self.len()?),
+            20 => Instruction::Field(self.symbol()?),
+            21 => Instruction::MakeStruct(self.many(Reader::symbol)?),
+            22 => Instruction::Spread,
+            23 => Instruction::MakeList(self.len()?),
+            24 => Instruction::Index,
+            25 => Instruction::MakeStructType(self.many(Reader::symbol)?),
+            26 => Instruction::MakeFnType(self.len()?),
+            27 => Instruction::MakeListType,
+            28 => Instruction::MakeUnionType(self.many(Reader::symbol)?),
+            29 => Instruction::MakeVariant(self.symbol()?),
+            30 => Instruction::IsVariant(self.symbol()?),
+            31 => Instruction::Payload,
+            32 => Instruction::Impl(self.many(Reader::symbol)?),
+            33 => Instruction::MakeClosure(Rc::new(self.function()?)),
+            34 => Instruction::Pop,
+            35 => Instruction::Dup,
+            36 => Instruction::Jump(self.len()?),
+            37 => Instruction::JumpIf(self.len()?),
+            38 => Instruction::Return,
+            39 => Instruction::DeclareGlobal(self.symbol()?),
+            40 => Instruction::StoreGlobal(self.symbol()?),
+            41 => Instruction::LoadGlobal(self.symbol()?),
+            42 => Instruction::StoreLocal(self.u16()?),
+            43 => Instruction::LoadLocal(self.u16()?),
+            44 => Instruction::StoreCaptured(self.u16()?, self.u16()?),
+            45 => Instruction::LoadCaptured(self.u16()?, self.u16()?),
+            46 => Instruction::Typeof,
+            47 => Instruction::JumpIfNot(self.len()?),
+            48 => Instruction::TailInvoke(self.len()?),
+            tag => {
+                return Err(BytecodeError::InvalidTag {
+                    what: "instruction",
+                    tag,
+                })
+            }
+        })
+    }
+
+    fn fields<T>(
+        &mut self,
+        field: impl Fn(&mut Self) -> Result<T, BytecodeError>,
+    ) -> Result<Fields<T>, BytecodeError> {
+        let entries = self.many(|reader| Ok((reader.symbol()?, field(reader)?)))?;
+        Ok(entries.into_iter().collect())
+    }
+
+    fn type_val(&mut self) -> Result<Type, BytecodeError> {
+        let tag = self.u8()?;
+
+        self.nested(|reader| {
+            Ok(match tag {
+                0 => Type::Unit,
+                1 => Type::Int,
+                2 => Type::Double,
+                3 => Type::Bool,
+                4 => Type::String,
+                5 => Type::Type,
+                6 => Type::Struct(reader.fields(Reader::type_val)?),
+                7 => Type::Union(reader.fields(Reader::type_val)?),
+                8 => Type::List(Box::from(reader.type_val()?)),
+                9 => Type::Fn {
+                    params: reader.many(Reader::type_val)?,
+                    returns: Box::from(reader.type_val()?),
+                },
+                10 => Type::TypeParam(reader.string()?),
+                11 => Type::Var(reader.string()?),
+                tag => return Err(BytecodeError::InvalidTag { what: "type", tag }),
+            })
+        })
+    }
+
+    fn value(&mut self) -> Result<KytheraVal, BytecodeError> {
+        let tag = self.u8()?;
+
+        self.nested(|reader| {
+            Ok(match tag {
+                0 => KytheraVal::unit(),
+                1 => KytheraVal::int(reader.u32()? as i32),
+                2 => KytheraVal::double(f64::from_bits(reader.u64()?)),
+                3 => KytheraVal::string(reader.string()?),
+                4 => KytheraVal::bool(reader.u8()? != 0),
+                5 => KytheraVal::type_val(reader.type_val()?),
+                6 => KytheraVal::structure(reader.fields(Reader::value)?),
+                7 => {
+                    let element_type = match reader.type_val()? {
+                        Type::List(element_type) => *element_type,
+                        t => return Err(BytecodeError::InvalidListType(t)),
+                    };
+                    KytheraVal::list(element_type, reader.many(Reader::value)?)
+                }
+                8 => {
+                    let union_type = reader.type_val()?;
+                    KytheraVal::variant(union_type, reader.symbol()?, reader.value()?)
+                }
+                tag => return Err(BytecodeError::InvalidTag { what: "value", tag }),
+            })
+        })
+    }
+}
+
+fn opcode(inst: &Instruction) -> u8 {
+    match inst {
+        Instruction::Nop => 0,
+        Instruction::Constant(_) => 1,
+        Instruction::Add => 2,
+        Instruction::Sub => 3,
+        Instruction::Mul => 4,
+        Instruction::Div => 5,
+        Instruction::Mod => 6,
+        Instruction::Equal => 7,
+        Instruction::NotEqual => 8,
+        Instruction::Less => 9,
+        Instruction::LessEqual => 10,
+        Instruction::Greater => 11,
+        Instruction::GreaterEqual => 12,
+        Instruction::Not => 13,
+        Instruction::Or => 14,
+        Instruction::And => 15,
+        Instruction::Xor => 16,
+        Instruction::ShiftLeft => 17,
+        Instruction::ShiftRight => 18,
+        Instruction::Invoke(_) => 19,
+        Instruction::Field(_) => 20,
+        Instruction::MakeStruct(_) => 21,
+        Instruction::Spread => 22,
+        Instruction::MakeList(_) => 23,
+        Instruction::Index => 24,
+        Instruction::MakeStructType(_) => 25,
+        Instruction::MakeFnType(_) => 26,
+        Instruction::MakeListType => 27,
+        Instruction::MakeUnionType(_) => 28,
+        Instruction::MakeVariant(_) => 29,
+        Instruction::IsVariant(_) => 30,
+        Instruction::Payload => 31,
+        Instruction::Impl(_) => 32,
+        Instruction::MakeClosure(_) => 33,
+        Instruction::Pop => 34,
+        Instruction::Dup => 35,
+        Instruction::Jump(_) => 36,
+        Instruction::JumpIf(_) => 37,
+        Instruction::Return => 38,
+        Instruction::DeclareGlobal(_) => 39,
+        Instruction::StoreGlobal(_) => 40,
+        Instruction::LoadGlobal(_) => 41,
+        Instruction::StoreLocal(_) => 42,
+        Instruction::LoadLocal(_) => 43,
+        Instruction::StoreCaptured(_, _) => 44,
+        Instruction::LoadCaptured(_, _) => 45,
+        Instruction::Typeof => 46,
+        Instruction::JumpIfNot(_) => 47,
+        Instruction::TailInvoke(_) => 48,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing::compile;
+    use crate::vm::Vm;
+
+    fn main_ky() -> Vec<u8> {
+        serialize(&compile(&fs::read_to_string("./main.ky").unwrap())).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = fs::read_to_string("./main.ky").unwrap();
+        let bytes = main_ky();
+        let loaded = deserialize(&bytes).unwrap();
+
+        // saving what was loaded gives back the same file
+        assert_eq!(serialize(&loaded).unwrap(), bytes);
+
+        let mut compiled = Vm::with_output(Box::new(std::io::sink()));
+        let mut from_file = Vm::with_output(Box::new(std::io::sink()));
+        assert_eq!(
+            from_file.run(loaded).unwrap(),
+            compiled.run(compile(&source)).unwrap()
+        );
+        assert_eq!(from_file.get_global("sum"), Some(KytheraVal::int(15)));
+    }
+
+    #[test]
+    fn constants_round_trip() {
+        let program = compile("let x = 0.0 - 0.0; { a = 1.5, b = x, c = \"s\", d = Result(Int, String), e = [1, 2] };");
+        let loaded = deserialize(&serialize(&program).unwrap()).unwrap();
+        assert_eq!(loaded.constants.len(), program.constants.len());
+        for (a, b) in loaded.constants.iter().zip(&program.constants) {
+            assert_eq!(format!("{:?}", a), format!("{:?}", b));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let bytes = main_ky();
+
+        assert_eq!(
+            deserialize(b"let x = 1;").unwrap_err(),
+            BytecodeError::NotBytecode
+        );
+
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            deserialize(&newer).unwrap_err(),
+            BytecodeError::UnsupportedVersion(VERSION + 1)
+        );
+
+        let mut corrupted = bytes.clone();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0x40;
+        assert_eq!(
+            deserialize(&corrupted).unwrap_err(),
+            BytecodeError::ChecksumMismatch
+        );
+
+        assert_eq!(
+            deserialize(&bytes[..8]).unwrap_err(),
+            BytecodeError::Truncated
+        );
+        assert_eq!(
+            deserialize(&bytes[..bytes.len() - 1]).unwrap_err(),
+            BytecodeError::ChecksumMismatch
+        );
+    }
+
+    // files with a valid checksum can still be malformed if they were written by something else
+    #[test]
+    fn rejects_malformed_bodies() {
+        let file = |body: &[u8]| {
+            let mut bytes = MAGIC.to_vec();
+            bytes.extend_from_slice(&VERSION.to_le_bytes());
+            bytes.extend_from_slice(&checksum(body).to_le_bytes());
+            bytes.extend_from_slice(body);
+            deserialize(&bytes).unwrap_err()
+        };
+
+        let mut body = Writer::default();
+        body.function(&compile("1;")).unwrap();
+        let valid = body.bytes;
+
+        assert_eq!(file(&valid[..valid.len() - 1]), BytecodeError::Truncated);
+        assert_eq!(
+            file(&[valid.as_slice(), &[0]].concat()),
+            BytecodeError::TrailingBytes
+        );
+        assert_eq!(file(&[1, 0, 0, 0, 0xff]), BytecodeError::InvalidUtf8);
+
+        // a function named "" with no parameters or slots, one constant of an unknown kind
+        let unknown_value = [&[0u8; 12][..], &[1, 0, 0, 0, 99]].concat();
+        assert_eq!(
+            file(&unknown_value),
+            BytecodeError::InvalidTag {
+                what: "value",
+                tag: 99
+            }
+        );
+
+        // a list constant whose type is Int
+        let not_list = [&[0u8; 12][..], &[1, 0, 0, 0, 7, 1]].concat();
+        assert_eq!(file(&not_list), BytecodeError::InvalidListType(Type::Int));
+
+        let deep: Vec<u8> = [&[0u8; 12][..], &[1, 0, 0, 0, 5], &[8; 300]].concat();
+        assert_eq!(file(&deep), BytecodeError::TooDeep);
+    }
+
+    #[test]
+    fn loaded_programs_are_verified() {
+        let program = Function {
+            name: "<main>".to_string(),
+            params: Vec::new(),
+            slots: 0,
+            instructions: vec![Instruction::Jump(7), Instruction::Return],
+            constants: Vec::new(),
+            locs: Vec::new(),
+            type_val: Rc::new(Type::Unit),
+        };
+
+        let e = deserialize(&serialize(&program).unwrap()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid bytecode: <main> at 0000: jump to 0007, past the end of the function."
+        );
+    }
+
+    // programs that verify but hold values of the wrong type fail when they run instead of panicking the VM
+    #[test]
+    fn loaded_programs_fail_safely() {
+        let program = |instructions, constants| {
+            let function = Function {
+                name: "<main>".to_string(),
+                params: Vec::new(),
+                slots: 0,
+                instructions,
+                constants,
+                locs: Vec::new(),
+                type_val: Rc::new(Type::Unit),
+            };
+            deserialize(&serialize(&function).unwrap())
+        };
+
+        let empty_list = program(vec![Instruction::MakeList(0), Instruction::Return], Vec::new());
+        assert_eq!(
+            empty_list.unwrap_err().to_string(),
+            "Invalid bytecode: <main> at 0000: makes a list with no elements."
+        );
+
+        let not_a_method = program(
+            vec![
+                Instruction::LoadGlobal(Symbol::from("Int")),
+                Instruction::Constant(0),
+                Instruction::Impl(vec![Symbol::from("m")]),
+                Instruction::Constant(0),
+                Instruction::Field(Symbol::from("m")),
+                Instruction::Return,
+            ],
+            vec![KytheraVal::int(1)],
+        );
+        let e = Vm::new().run(not_a_method.unwrap()).unwrap_err();
+        assert_eq!(e.kind.to_string(), "Expected method but got Int.");
+
+        let no_params = Function {
+            name: "m".to_string(),
+            params: Vec::new(),
+            slots: 0,
+            instructions: vec![Instruction::Constant(0), Instruction::Return],
+            constants: vec![KytheraVal::int(1)],
+            locs: Vec::new(),
+            type_val: Rc::new(Type::Fn {
+                params: Vec::new(),
+                returns: Box::from(Type::Int),
+            }),
+        };
+        let no_receiver = program(
+            vec![
+                Instruction::LoadGlobal(Symbol::from("Int")),
+                Instruction::MakeClosure(Rc::new(no_params)),
+                Instruction::Impl(vec![Symbol::from("m")]),
+                Instruction::Constant(0),
+                Instruction::Field(Symbol::from("m")),
+                Instruction::Return,
+            ],
+            vec![KytheraVal::int(1)],
+        );
+        let e = Vm::new().run(no_receiver.unwrap()).unwrap_err();
+        assert_eq!(e.kind.to_string(), "Expected method but got () => Int.");
+    }
+
+    #[test]
+    fn functions_cannot_be_saved_as_constants() {
+        let f = crate::prelude::functions().remove(0);
+        let program = Function {
+            name: "test".to_string(),
+            params: Vec::new(),
+            slots: 0,
+            instructions: vec![Instruction::Constant(0), Instruction::Return],
+            constants: vec![KytheraVal::native(f.name, f.type_val.clone(), f.function)],
+            locs: Vec::new(),
+            type_val: Rc::new(Type::Unit),
+        };
+
+        assert_eq!(
+            serialize(&program).unwrap_err(),
+            BytecodeError::Unserializable(f.type_val)
+        );
+    }
+}
@@ -0,0 +1,2464 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem;
+use std::rc::Rc;
+
+use crate::error::{fail, CompileError, ErrorKind};
+use crate::input_stream::Loc;
+use crate::prelude;
+use crate::parser::{AstNode, Literal, Pattern, Statement, StructEntry, WhenArm};
+use crate::tokenizer::Keyword::*;
+use crate::tokenizer::Symbol;
+use crate::tokenizer::Symbol::*;
+use crate::types::{Fields, Methods, Type};
+use crate::verify;
+use crate::vm::{self, Function, Instruction, InternalVal, KytheraVal, Vm};
+
+// what the compiler knows about a variable
+#[derive(Clone)]
+struct Binding {
+    type_val: Type,
+    constant: bool,
+    // if the variable holds a type that is known at compile time, the type it holds
+    known_type: Option<Type>,
+    // if the variable holds a function whose result is a type known at compile time, how to compute it
+    type_fn: Option<TypeFn>,
+    // if the variable is a constant whose value is known at compile time, its value
+    value: Option<KytheraVal>,
+    // the variable's slot in the scope of the function it belongs to. globals have none.
+    slot: Option<u16>,
+}
+
+// a function from types to a type, e.g. (T: Type) => { return { value: T }; }, whose result can be computed
+// at compile time when its arguments can
+#[derive(Clone)]
+struct TypeFn {
+    params: Vec<String>,
+    // the type returned, in terms of the types held by the parameters
+    returns: Type,
+}
+
+// a function literal, with the name it is declared with
+struct FnLiteral<'a> {
+    name: &'a str,
+    param_names: &'a [String],
+    param_types: &'a [AstNode],
+    body: &'a AstNode,
+}
+
+// compiles a program to VM instructions, checking types along the way
+#[derive(Clone)]
+pub struct Compiler {
+    // innermost scope last
+    scopes: Vec<HashMap<String, Binding>>,
+    // instructions for the function currently being compiled
+    instructions: Vec<Instruction>,
+    // where in the source each instruction came from
+    locs: Vec<Loc>,
+    // the start of the statement being compiled
+    loc: Loc,
+    // constants used by the function currently being compiled
+    constants: Vec<KytheraVal>,
+    // how many slots each function currently being compiled needs, innermost function last
+    slots: Vec<usize>,
+    // types returned by each function currently being compiled, innermost function last
+    return_types: Vec<Vec<Type>>,
+    // errors propagated with ? in each function currently being compiled: the type of the error, and the
+    // instruction to patch with the function's return type once it is known
+    propagated: Vec<Vec<(Type, usize)>>,
+    // types of the functions attached to types with impl
+    methods: Methods<Type>,
+    // whether a function whose return type is still being inferred has been called, see compile_recursive
+    placeholder_called: bool,
+}
+
+impl Default for Compiler {
+    fn default() -> Compiler {
+        Compiler::new()
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        let mut globals = HashMap::new();
+
+        // built-in types
+        for t in [Type::Unit, Type::Int, Type::Double, Type::Bool, Type::String, Type::Type] {
+            globals.insert(
+                t.to_string(),
+                Binding {
+                    type_val: Type::Type,
+                    constant: true,
+                    known_type: Some(t.clone()),
+                    type_fn: None,
+                    value: Some(KytheraVal::type_val(t)),
+                    slot: None,
+                },
+            );
+        }
+
+        // built-in type constructors
+        globals.insert(
+            "List".to_string(),
+            Binding {
+                type_val: Type::Fn {
+                    params: vec![Type::Type],
+                    returns: Box::from(Type::Type),
+                },
+                constant: true,
+                known_type: None,
+                type_fn: Some(TypeFn {
+                    params: vec!["T".to_string()],
+                    returns: Type::List(Box::from(Type::Var("T".to_string()))),
+                }),
+                value: None,
+                slot: None,
+            },
+        );
+        globals.insert(
+            "Result".to_string(),
+            Binding {
+                type_val: Type::Fn {
+                    params: vec![Type::Type, Type::Type],
+                    returns: Box::from(Type::Type),
+                },
+                constant: true,
+                known_type: None,
+                type_fn: Some(TypeFn {
+                    params: vec!["T".to_string(), "E".to_string()],
+                    returns: Type::result(Type::Var("T".to_string()), Type::Var("E".to_string())),
+                }),
+                value: None,
+                slot: None,
+            },
+        );
+
+        let mut methods = Methods::new();
+
+        for builtin in prelude::functions() {
+            globals.insert(
+                builtin.name.to_string(),
+                Binding {
+                    type_val: builtin.type_val,
+                    constant: true,
+                    known_type: None,
+                    type_fn: None,
+                    value: None,
+                    slot: None,
+                },
+            );
+        }
+        for (target, builtin) in prelude::methods() {
+            methods.insert(target, builtin.name.to_string(), builtin.type_val);
+        }
+
+        Compiler {
+            scopes: vec![globals],
+            instructions: Vec::new(),
+            locs: Vec::new(),
+            loc: Loc::default(),
+            constants: Vec::new(),
+            slots: Vec::new(),
+            return_types: Vec::new(),
+            propagated: Vec::new(),
+            methods,
+            placeholder_called: false,
+        }
+    }
+
+    // a compiler for programs to be run on vm, which can use the globals the host has defined there
+    pub fn for_vm(vm: &Vm) -> Compiler {
+        let mut compiler = Compiler::new();
+        for (name, type_val) in vm.defined() {
+            compiler.define(&name, type_val);
+        }
+
+        compiler
+    }
+
+    // tell the compiler about a constant global that the VM will provide
+    pub fn define(&mut self, name: &str, type_val: Type) {
+        self.scopes.first_mut().expect("No global scope.").insert(
+            name.to_string(),
+            Binding {
+                type_val,
+                constant: true,
+                known_type: None,
+                type_fn: None,
+                value: None,
+                slot: None,
+            },
+        );
+    }
+
+    // compile a whole program, which returns the value of its last statement. if it fails, the compiler
+    // forgets everything the program declared.
+    pub fn compile(&mut self, program: &[Statement]) -> Result<Rc<Function>, CompileError> {
+        let globals = self.scopes[0].clone();
+        let methods = self.methods.clone();
+
+        self.compile_program(program).inspect_err(|_| {
+            self.scopes = vec![globals];
+            self.methods = methods;
+            self.instructions.clear();
+            self.locs.clear();
+            self.constants.clear();
+            self.slots.clear();
+            self.return_types.clear();
+            self.propagated.clear();
+            self.placeholder_called = false;
+        })
+    }
+
+    fn compile_program(&mut self, program: &[Statement]) -> Result<Rc<Function>, CompileError> {
+        let returns = if program.is_empty() {
+            self.emit_constant(KytheraVal::unit())?;
+            Type::Unit
+        } else {
+            self.compile_statements(program)?
+        };
+
+        self.emit(Instruction::Return);
+
+        let function = Function {
+            name: "<main>".to_string(),
+            params: Vec::new(),
+            slots: 0,
+            instructions: mem::take(&mut self.instructions),
+            constants: mem::take(&mut self.constants),
+            locs: mem::take(&mut self.locs),
+            type_val: Rc::new(Type::Fn {
+                params: Vec::new(),
+                returns: Box::from(returns),
+            }),
+        };
+
+        // bytecode that does not verify is a bug in the compiler
+        if cfg!(debug_assertions) {
+            if let Err(e) = verify::verify(&function) {
+                panic!("Compiler bug: invalid bytecode in {}", e);
+            }
+        }
+
+        Ok(Rc::new(function))
+    }
+
+    fn emit(&mut self, inst: Instruction) -> usize {
+        self.instructions.push(inst);
+        self.locs.push(self.loc);
+        self.instructions.len() - 1
+    }
+
+    // compile the statements of a program or block, which evaluate to the last one. consecutive function
+    // declarations are compiled together, so that they can call each other.
+    fn compile_statements(&mut self, body: &[Statement]) -> Result<Type, CompileError> {
+        let outer_loc = self.loc;
+        let mut result = None;
+        let mut rest = body;
+
+        while let Some(statement) = rest.first() {
+            self.loc = statement.loc;
+            let group = self.fn_declarations(rest);
+
+            if group.is_empty() {
+                match rest.len() {
+                    1 => result = Some(self.compile_exp(&statement.node)?),
+                    _ => self.compile_statement(&statement.node)?,
+                }
+                rest = &rest[1..];
+            } else {
+                rest = &rest[group.len()..];
+                self.compile_fn_declarations(group)?;
+
+                if rest.is_empty() {
+                    self.emit_constant(KytheraVal::unit())?;
+                    result = Some(Type::Unit);
+                }
+            }
+        }
+
+        self.loc = outer_loc;
+        Ok(result.expect("Empty block."))
+    }
+
+    // emit an instruction pushing a constant, sharing the function's existing copy of it if there is one
+    fn emit_constant(&mut self, val: KytheraVal) -> Result<usize, CompileError> {
+        let index = match self.constants.iter().position(|constant| same_constant(constant, &val)) {
+            Some(index) => index,
+            None => self.add_constant(val)?,
+        };
+
+        Ok(self.emit(Instruction::Constant(index as u16)))
+    }
+
+    // add a constant that nothing else shares, returning its index
+    fn add_constant(&mut self, val: KytheraVal) -> Result<usize, CompileError> {
+        if self.constants.len() > u16::MAX as usize {
+            fail!("Too many constants in one function.");
+        }
+
+        self.constants.push(val);
+        Ok(self.constants.len() - 1)
+    }
+
+    // the value of code that just pushes a constant, if that is all it does
+    fn constant(&self, code: &[Instruction]) -> Option<KytheraVal> {
+        match code {
+            [Instruction::Constant(index)] => Some(self.constants[*index as usize].clone()),
+            _ => None,
+        }
+    }
+
+    // point the jump at index jump to the next instruction to be emitted
+    fn patch_jump(&mut self, jump: usize) {
+        let next = self.instructions.len();
+
+        match &mut self.instructions[jump] {
+            Instruction::Jump(target) | Instruction::JumpIf(target) => *target = next,
+            inst => panic!("Cannot patch non-jump instruction {:?}.", inst),
+        }
+    }
+
+    // replace the code from start onwards with the constant it evaluates to. an error evaluating a constant is
+    // reported at compile time instead of when it would have run.
+    fn fold(&mut self, start: usize, result: Result<KytheraVal, ErrorKind>) -> Result<(), CompileError> {
+        let val = match result {
+            Ok(val) => val,
+            Err(e) => fail!("Error in constant expression: {}", e),
+        };
+
+        self.instructions.truncate(start);
+        self.locs.truncate(start);
+        self.emit_constant(val)?;
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<&Binding, CompileError> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .ok_or_else(|| undefined(name))
+    }
+
+    // add a variable to the innermost scope, returning its slot if it is not a global. declaring a local again
+    // gives it a new slot, so closures over the old one keep it. globals are looked up by name, so a global
+    // can only be declared again with the same type.
+    fn declare(&mut self, name: &str, mut binding: Binding) -> Result<Option<u16>, CompileError> {
+        let scope = self.scopes.last_mut().expect("No scope to declare in.");
+
+        match self.slots.last_mut() {
+            Some(slots) => {
+                let slot = match u16::try_from(*slots) {
+                    Ok(slot) => slot,
+                    Err(_) => fail!("Too many variables in one function."),
+                };
+                *slots += 1;
+                binding.slot = Some(slot);
+            }
+            None => {
+                if let Some(existing) = scope.get(name) {
+                    expect_redeclared(name, &existing.type_val, &binding.type_val)?;
+                }
+            }
+        }
+
+        let slot = binding.slot;
+        scope.insert(name.to_string(), binding);
+        Ok(slot)
+    }
+
+    // how many functions out from the one being compiled the variable name belongs to, and its slot there
+    fn resolve(&self, name: &str) -> Result<(u16, Option<u16>), CompileError> {
+        let (depth, binding) = self
+            .scopes
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| scope.get(name).map(|binding| (depth, binding)))
+            .ok_or_else(|| undefined(name))?;
+
+        Ok((depth as u16, binding.slot))
+    }
+
+    fn emit_load(&mut self, name: &str) -> Result<usize, CompileError> {
+        Ok(match self.resolve(name)? {
+            (_, None) => self.emit(Instruction::LoadGlobal(name.into())),
+            (0, Some(slot)) => self.emit(Instruction::LoadLocal(slot)),
+            (depth, Some(slot)) => self.emit(Instruction::LoadCaptured(depth, slot)),
+        })
+    }
+
+    fn emit_store(&mut self, name: &str) -> Result<usize, CompileError> {
+        Ok(match self.resolve(name)? {
+            (_, None) => self.emit(Instruction::StoreGlobal(name.into())),
+            (0, Some(slot)) => self.emit(Instruction::StoreLocal(slot)),
+            (depth, Some(slot)) => self.emit(Instruction::StoreCaptured(depth, slot)),
+        })
+    }
+
+    // compile a node whose value is not used, leaving the stack as it was
+    fn compile_statement(&mut self, node: &AstNode) -> Result<(), CompileError> {
+        match node {
+            AstNode::Declaration { op, pattern, value } => {
+                let start = self.instructions.len();
+                let (type_val, type_fn) = match value.as_ref() {
+                    AstNode::Literal(Literal::Fn {
+                        param_names,
+                        param_types,
+                        body,
+                    }) => match pattern {
+                        Pattern::Identifier(name) => {
+                            let literal = FnLiteral {
+                                name,
+                                param_names,
+                                param_types,
+                                body,
+                            };
+
+                            return self.compile_fn_declarations(vec![(literal, *op == Const)]);
+                        }
+                        _ => self.compile_fn("<fn>", param_names, param_types, body)?,
+                    },
+                    _ => (self.compile_exp(value)?, None),
+                };
+                let known_type = if type_val == Type::Type {
+                    self.known_type(value)
+                } else {
+                    None
+                };
+
+                // only constants keep their value, since variables may be assigned before they are used
+                let value = match op {
+                    Const => self.constant(&self.instructions[start..]),
+                    _ => None,
+                };
+
+                self.compile_pattern(
+                    pattern,
+                    Binding {
+                        type_val,
+                        constant: *op == Const,
+                        known_type,
+                        type_fn,
+                        value,
+                        slot: None,
+                    },
+                )?;
+            }
+            _ => {
+                self.compile_exp(node)?;
+                self.emit(Instruction::Pop);
+            }
+        }
+
+        Ok(())
+    }
+
+    // bind the value on top of the stack to the names in pattern, consuming it. binding describes the whole
+    // value; the names in a destructuring pattern only share whether it is constant.
+    fn compile_pattern(&mut self, pattern: &Pattern, binding: Binding) -> Result<(), CompileError> {
+        let constant = binding.constant;
+        let part = |type_val| Binding {
+            type_val,
+            constant,
+            known_type: None,
+            type_fn: None,
+            value: None,
+            slot: None,
+        };
+
+        match pattern {
+            Pattern::Identifier(name) => {
+                match self.declare(name, binding)? {
+                    Some(slot) => self.emit(Instruction::StoreLocal(slot)),
+                    None => self.emit(Instruction::DeclareGlobal(name.into())),
+                };
+            }
+            Pattern::Struct(fields) => {
+                let field_types = match binding.type_val {
+                    Type::Struct(field_types) => field_types,
+                    t => fail!("Type error: cannot destructure {} as a struct.", t),
+                };
+
+                for (name, field_pattern) in fields {
+                    let field_type = match field_types.get(name) {
+                        Some(field_type) => field_type,
+                        None => fail!(
+                            "Type error: {} has no field {} to destructure.",
+                            Type::Struct(field_types.clone()),
+                            name
+                        ),
+                    };
+
+                    self.emit(Instruction::Dup);
+                    self.emit(Instruction::Field(name.into()));
+                    self.compile_pattern(field_pattern, part(field_type.clone()))?;
+                }
+
+                self.emit(Instruction::Pop);
+            }
+            Pattern::List(elements) => {
+                let element_type = match binding.type_val {
+                    Type::List(element_type) => *element_type,
+                    t => fail!("Type error: cannot destructure {} as a list.", t),
+                };
+
+                for (i, element_pattern) in elements.iter().enumerate() {
+                    self.emit(Instruction::Dup);
+                    self.emit_constant(KytheraVal::int(i as i32))?;
+                    self.emit(Instruction::Index);
+                    self.compile_pattern(element_pattern, part(element_type.clone()))?;
+                }
+
+                self.emit(Instruction::Pop);
+            }
+        }
+
+        Ok(())
+    }
+
+    // compile a node that leaves its value on top of the stack, returning its type
+    fn compile_exp(&mut self, node: &AstNode) -> Result<Type, CompileError> {
+        Ok(match node {
+            AstNode::Literal(literal) => {
+                let start = self.instructions.len();
+                let literal_type = self.compile_literal(literal)?;
+
+                // type literals are evaluated at compile time, unless they depend on a type parameter
+                if let Some(t) = self.known_type(node).filter(|t| !t.is_generic()) {
+                    self.fold(start, Ok(KytheraVal::type_val(t)))?;
+                }
+
+                literal_type
+            }
+            AstNode::Identifier(name) => {
+                let binding = self.lookup(name)?;
+                let type_val = binding.type_val.clone();
+
+                // constants known at compile time are inlined
+                match binding.value.clone() {
+                    Some(val) => self.emit_constant(val)?,
+                    None => self.emit_load(name)?,
+                };
+
+                type_val
+            }
+            AstNode::Unary { op, operand } => {
+                let start = self.instructions.len();
+                let operand_type = self.compile_exp(operand)?;
+
+                let result_type = match op {
+                    Bang => {
+                        expect_type(&Type::Bool, &operand_type, "operand of !")?;
+                        Type::Bool
+                    }
+                    Tilde => {
+                        expect_type(&Type::Int, &operand_type, "operand of ~")?;
+                        Type::Int
+                    }
+                    op => fail!("Unknown unary operator {:?}.", op),
+                };
+
+                if let Some(operand) = self.constant(&self.instructions[start..]) {
+                    self.fold(start, vm::not(operand))?;
+                } else {
+                    self.emit(Instruction::Not);
+                }
+
+                result_type
+            }
+            AstNode::Binary { lhs, op, rhs } => match op {
+                Equal | PlusEqual | MinusEqual | StarEqual | SlashEqual | PercentEqual => {
+                    self.compile_assignment(lhs, *op, rhs)?
+                }
+                AndAnd | BarBar => self.compile_short_circuit(lhs, *op, rhs)?,
+                op => {
+                    let start = self.instructions.len();
+                    let lhs_type = self.compile_exp(lhs)?;
+                    let rhs_start = self.instructions.len();
+                    let rhs_type = self.compile_exp(rhs)?;
+                    let result_type = self.emit_binary(*op, &lhs_type, &rhs_type)?;
+
+                    // fold operators whose operands are both constant
+                    let (inst, operands) = self.instructions[start..].split_last().expect("Operator was emitted.");
+                    let (lhs_code, rhs_code) = operands.split_at(rhs_start - start);
+                    if let (Some(a), Some(b)) = (self.constant(lhs_code), self.constant(rhs_code)) {
+                        let result = vm::binary(inst, a, b);
+                        self.fold(start, result)?;
+                    }
+
+                    result_type
+                }
+            },
+            // arguments are evaluated before the function being called
+            AstNode::Call { target, arguments } => {
+                let arg_types = arguments
+                    .iter()
+                    .map(|arg| self.compile_exp(arg))
+                    .collect::<Result<Vec<Type>, CompileError>>()?;
+
+                match self.compile_exp(target)? {
+                    Type::Fn { params, returns } => {
+                        if params.len() != arg_types.len() {
+                            fail!(
+                                "Type error: expected {} arguments but got {}.",
+                                params.len(),
+                                arg_types.len()
+                            );
+                        }
+
+                        // the types given for type parameters, which later parameters and the return type refer to
+                        let mut type_args = Vec::new();
+                        for ((param, arg_type), arg) in params.iter().zip(arg_types.iter()).zip(arguments) {
+                            match param.substitute(&type_args) {
+                                Type::TypeParam(name) => {
+                                    expect_type(&Type::Type, arg_type, "argument")?;
+                                    let t = match self.known_type(arg) {
+                                        Some(t) => t,
+                                        None => fail!(
+                                            "Type error: the type argument for {} must be known at compile time.",
+                                            name
+                                        ),
+                                    };
+                                    type_args.push((name, t));
+                                }
+                                param => expect_type(&param, arg_type, "argument")?,
+                            }
+                        }
+
+                        if is_placeholder(&returns) {
+                            self.placeholder_called = true;
+                        }
+
+                        self.emit(Instruction::Invoke(arg_types.len()));
+                        returns.substitute(&type_args)
+                    }
+                    t => fail!("Type error: cannot call value of type {}.", t),
+                }
+            }
+            AstNode::Block { body } => self.compile_statements(body)?,
+            AstNode::Declaration { .. } => {
+                self.compile_statement(node)?;
+                self.emit_constant(KytheraVal::unit())?;
+                Type::Unit
+            }
+            AstNode::Jump { op, result } => match op {
+                Return => {
+                    let result_type = self.compile_exp(result)?;
+
+                    match self.return_types.last_mut() {
+                        Some(return_types) => return_types.push(result_type),
+                        None => fail!("'return' outside of a function."),
+                    }
+
+                    // returning the result of a call: make the call in place of this one. the Return is kept
+                    // for jumps that land after the call, e.g. from a short-circuiting operator.
+                    if let Some(Instruction::Invoke(n)) = self.instructions.last() {
+                        let n = *n;
+                        *self.instructions.last_mut().expect("No call to return.") = Instruction::TailInvoke(n);
+                    }
+                    self.emit(Instruction::Return);
+
+                    // control never continues past a return, so it has no meaningful type
+                    Type::Unit
+                }
+                op => fail!("{:?} is not yet implemented.", op),
+            },
+            AstNode::Propagate { operand } => self.compile_propagate(operand)?,
+            AstNode::Typeof { operand } => {
+                self.compile_exp(operand)?;
+                self.emit(Instruction::Typeof);
+                Type::Type
+            }
+            AstNode::Access { target, field } => {
+                let target_type = self.compile_exp(target)?;
+
+                let field_type = match &target_type {
+                    Type::Struct(fields) if fields.get(field).is_some() => fields.get(field).cloned(),
+                    // a variant of a union type or a function constructing one, or a function attached to the
+                    // type itself, e.g. Point.new
+                    Type::Type => self.known_type(target).and_then(|t| {
+                        variant_constructor_type(&t, field).or_else(|| self.methods.get(&t, field).cloned())
+                    }),
+                    // a method, which is passed the value it was accessed through as its first argument
+                    t => self.methods.get(t, field).map(|method| match method {
+                        Type::Fn { params, returns } => Type::Fn {
+                            params: params[1..].to_vec(),
+                            returns: returns.clone(),
+                        },
+                        _ => unreachable!(),
+                    }),
+                };
+
+                self.emit(Instruction::Field(field.into()));
+                match field_type {
+                    Some(field_type) => field_type,
+                    None => fail!("Type error: {} has no field or method {}.", target_type, field),
+                }
+            }
+            AstNode::Impl { target, methods } => {
+                let target_type = self.eval_type(target)?;
+                self.compile_exp(target)?;
+
+                if let Type::Struct(fields) = &target_type {
+                    if let Some((name, _)) = methods.iter().find(|(name, _)| fields.get(name).is_some()) {
+                        fail!("Type error: method {} conflicts with a field of {}.", name, target_type);
+                    }
+                }
+
+                // function literals are compiled last, together, so that they can call each other and any other
+                // method. the Impl takes the methods in the order they were pushed in.
+                let mut names = Vec::new();
+                let mut literals = Vec::new();
+                for (name, method) in methods {
+                    match method {
+                        AstNode::Literal(Literal::Fn {
+                            param_names,
+                            param_types,
+                            body,
+                        }) => literals.push(FnLiteral {
+                            name,
+                            param_names,
+                            param_types,
+                            body,
+                        }),
+                        _ => {
+                            let method_type = self.compile_exp(method)?;
+                            self.declare_method(&target_type, name, method_type)?;
+                            names.push(name.into());
+                        }
+                    }
+                }
+
+                let compiled = self.compile_recursive(&literals, |compiler, i, type_val| {
+                    compiler.methods.insert(target_type.clone(), literals[i].name, type_val);
+                })?;
+                for (literal, (method_type, _)) in literals.iter().zip(compiled) {
+                    self.declare_method(&target_type, literal.name, method_type)?;
+                    names.push(literal.name.into());
+                }
+
+                self.emit(Instruction::Impl(names));
+                self.emit_constant(KytheraVal::unit())?;
+                Type::Unit
+            }
+            AstNode::Index { target, index } => {
+                let target_type = self.compile_exp(target)?;
+                let index_type = self.compile_exp(index)?;
+
+                match target_type {
+                    Type::List(element_type) => {
+                        expect_type(&Type::Int, &index_type, "list index")?;
+                        self.emit(Instruction::Index);
+                        *element_type
+                    }
+                    t => fail!("Type error: cannot index {}.", t),
+                }
+            }
+            AstNode::When {
+                subject,
+                arms,
+                else_body,
+            } => self.compile_when(subject, arms, else_body.as_deref())?,
+            AstNode::If { .. } | AstNode::While { .. } => {
+                fail!("{:?} is not yet implemented.", node)
+            }
+        })
+    }
+
+    fn compile_literal(&mut self, literal: &Literal) -> Result<Type, CompileError> {
+        Ok(match literal {
+            Literal::Unit => {
+                self.emit_constant(KytheraVal::unit())?;
+                Type::Unit
+            }
+            Literal::Int(n) => {
+                self.emit_constant(KytheraVal::int(*n))?;
+                Type::Int
+            }
+            Literal::Double(d) => {
+                self.emit_constant(KytheraVal::double(*d))?;
+                Type::Double
+            }
+            Literal::String(s) => {
+                self.emit_constant(KytheraVal::string(s.clone()))?;
+                Type::String
+            }
+            Literal::Bool(b) => {
+                self.emit_constant(KytheraVal::bool(*b))?;
+                Type::Bool
+            }
+            Literal::Struct(entries) => {
+                // each run of fields written out is built as a struct of its own, then each part is spread
+                // over the parts before it
+                let mut struct_type: Option<Fields<Type>> = None;
+                let mut entries = entries.iter().peekable();
+
+                while let Some(entry) = entries.next() {
+                    let part_type = match entry {
+                        StructEntry::Spread(exp) => match self.compile_exp(exp)? {
+                            Type::Struct(fields) => fields,
+                            t => fail!("Type error: cannot spread value of type {}.", t),
+                        },
+                        StructEntry::Field(name, value) => {
+                            let mut names = vec![name.into()];
+                            let mut field_types = Fields::new();
+                            field_types.insert(name.clone(), self.compile_exp(value)?);
+
+                            while let Some(StructEntry::Field(name, value)) = entries.peek() {
+                                field_types.insert(name.clone(), self.compile_exp(value)?);
+                                names.push(name.into());
+                                entries.next();
+                            }
+
+                            self.emit(Instruction::MakeStruct(names));
+                            field_types
+                        }
+                    };
+
+                    struct_type = Some(match struct_type {
+                        None => part_type,
+                        Some(mut field_types) => {
+                            self.emit(Instruction::Spread);
+
+                            for (name, field_type) in part_type.iter() {
+                                field_types.insert(name.clone(), field_type.clone());
+                            }
+
+                            field_types
+                        }
+                    });
+                }
+
+                Type::Struct(struct_type.expect("Empty struct literal."))
+            }
+            Literal::List(elements) => {
+                let (first, rest) = match elements.split_first() {
+                    Some(split) => split,
+                    None => fail!("Type error: cannot infer the element type of an empty list."),
+                };
+
+                let element_type = self.compile_exp(first)?;
+                for element in rest {
+                    let t = self.compile_exp(element)?;
+                    expect_type(&element_type, &t, "list element")?;
+                }
+
+                self.emit(Instruction::MakeList(elements.len()));
+                Type::List(Box::from(element_type))
+            }
+            Literal::StructType(fields) => {
+                let mut names = Vec::new();
+
+                for (name, field_type) in fields {
+                    let t = self.compile_exp(field_type)?;
+                    expect_type(&Type::Type, &t, "struct field type")?;
+                    names.push(name.into());
+                }
+
+                self.emit(Instruction::MakeStructType(names));
+                Type::Type
+            }
+            Literal::UnionType(variants) => {
+                let mut tags = Vec::new();
+
+                for (tag, variant_type) in variants {
+                    let t = self.compile_exp(variant_type)?;
+                    expect_type(&Type::Type, &t, "variant type")?;
+                    tags.push(tag.into());
+                }
+
+                self.emit(Instruction::MakeUnionType(tags));
+                Type::Type
+            }
+            Literal::FnType { param_types, returns } => {
+                for param_type in param_types {
+                    let t = self.compile_exp(param_type)?;
+                    expect_type(&Type::Type, &t, "parameter type")?;
+                }
+
+                let t = self.compile_exp(returns)?;
+                expect_type(&Type::Type, &t, "return type")?;
+
+                self.emit(Instruction::MakeFnType(param_types.len()));
+                Type::Type
+            }
+            Literal::Fn {
+                param_names,
+                param_types,
+                body,
+            } => self.compile_fn("<fn>", param_names, param_types, body)?.0,
+        })
+    }
+
+    // compile a function literal, returning its type and, if it returns a type that can be computed at compile
+    // time from its arguments, how to compute it
+    fn compile_fn(
+        &mut self,
+        name: &str,
+        param_names: &[String],
+        param_types: &[AstNode],
+        body: &AstNode,
+    ) -> Result<(Type, Option<TypeFn>), CompileError> {
+        let outer_instructions = mem::take(&mut self.instructions);
+        let outer_locs = mem::take(&mut self.locs);
+        let outer_constants = mem::take(&mut self.constants);
+        self.scopes.push(HashMap::new());
+        self.slots.push(0);
+        self.return_types.push(Vec::new());
+        self.propagated.push(Vec::new());
+
+        let params = self.declare_params(param_names, param_types)?;
+
+        self.compile_exp(body)?;
+
+        // falling off the end of a function returns unit
+        self.emit(Instruction::Pop);
+        self.emit_constant(KytheraVal::unit())?;
+        self.emit(Instruction::Return);
+
+        let mut return_types = self.return_types.pop().expect("No function being compiled.");
+
+        let last_return = match body {
+            AstNode::Block { body } => match body.last().map(|statement| &statement.node) {
+                Some(AstNode::Jump { op: Return, result }) => Some(result),
+                _ => None,
+            },
+            _ => None,
+        };
+        if last_return.is_none() {
+            return_types.push(Type::Unit);
+        }
+
+        // the result of a call to a function whose return type is not known yet says nothing about this one's
+        if let Some(i) = return_types.iter().rposition(|t| !is_placeholder(t)) {
+            let known = return_types.remove(i);
+            return_types.push(known);
+        }
+
+        let returns = return_types.pop().unwrap_or(Type::Unit);
+        for other in return_types.iter() {
+            expect_type(&returns, other, "return value")?;
+        }
+
+        // errors propagated with ? are returned as the err variant of whatever Result the function returns
+        for (error_type, push) in self.propagated.pop().expect("No function being compiled.") {
+            match &returns {
+                Type::Union(variants) if variants.get("err") == Some(&error_type) && !returns.is_generic() => {
+                    self.constants[push] = KytheraVal::type_val(returns.clone());
+                }
+                _ => fail!(
+                    "Type error: ? propagates an error of type {} but the function returns {}.",
+                    error_type, returns
+                ),
+            }
+        }
+
+        // the type returned can only be computed from the arguments if it is the only thing returned
+        let type_fn = match last_return {
+            Some(result) if returns == Type::Type && return_types.is_empty() => {
+                self.known_type(result).map(|returns| TypeFn {
+                    params: param_names.to_vec(),
+                    returns,
+                })
+            }
+            _ => None,
+        };
+
+        self.scopes.pop();
+        let slots = self.slots.pop().expect("No function being compiled.");
+        let instructions = mem::replace(&mut self.instructions, outer_instructions);
+        let locs = mem::replace(&mut self.locs, outer_locs);
+        let constants = mem::replace(&mut self.constants, outer_constants);
+
+        let type_val = fn_type(&params, param_names, returns);
+
+        self.emit(Instruction::MakeClosure(Rc::new(Function {
+            name: name.to_string(),
+            params: param_names.to_vec(),
+            slots,
+            instructions,
+            constants,
+            locs,
+            type_val: Rc::new(type_val.clone()),
+        })));
+
+        Ok((type_val, type_fn))
+    }
+
+    // declare the parameters of the function being compiled, returning their types. the types of parameters
+    // may refer to earlier parameters holding types.
+    fn declare_params(&mut self, param_names: &[String], param_types: &[AstNode]) -> Result<Vec<Type>, CompileError> {
+        let mut params = Vec::new();
+        for (name, param_type) in param_names.iter().zip(param_types) {
+            let param_type = self.eval_type(param_type)?;
+
+            if self.scopes.last().expect("No function being compiled.").contains_key(name) {
+                fail!("Duplicate parameter {}.", name);
+            }
+
+            let known_type = if param_type == Type::Type {
+                Some(Type::Var(name.clone()))
+            } else {
+                None
+            };
+
+            self.declare(
+                name,
+                Binding {
+                    type_val: param_type.clone(),
+                    constant: false,
+                    known_type,
+                    type_fn: None,
+                    value: None,
+                    slot: None,
+                },
+            )?;
+            params.push(param_type);
+        }
+
+        Ok(params)
+    }
+
+    // the function declarations at the start of statements that can be declared together. one that declares a
+    // name again, or whose parameter types can't be known until the ones before it are compiled, starts the
+    // next group, e.g. a parameter of type Option(T) where Option is one of the functions.
+    fn fn_declarations<'a>(&mut self, statements: &'a [Statement]) -> Vec<(FnLiteral<'a>, bool)> {
+        let mut group: Vec<(FnLiteral, bool)> = Vec::new();
+
+        for statement in statements {
+            let (op, name, value) = match &statement.node {
+                AstNode::Declaration {
+                    op,
+                    pattern: Pattern::Identifier(name),
+                    value,
+                } => (op, name, value),
+                _ => break,
+            };
+            let literal = match value.as_ref() {
+                AstNode::Literal(Literal::Fn {
+                    param_names,
+                    param_types,
+                    body,
+                }) => FnLiteral {
+                    name,
+                    param_names,
+                    param_types,
+                    body,
+                },
+                _ => break,
+            };
+
+            if !group.is_empty() {
+                self.scopes.push(HashMap::new());
+                self.slots.push(0);
+                let params = self.declare_params(literal.param_names, literal.param_types);
+                self.scopes.pop();
+                self.slots.pop();
+
+                if params.is_err() || group.iter().any(|(declared, _)| declared.name == name) {
+                    break;
+                }
+            }
+
+            group.push((literal, *op == Const));
+        }
+
+        group
+    }
+
+    // declare functions by name before compiling any of them, so that they can call themselves and each other
+    fn compile_fn_declarations(&mut self, group: Vec<(FnLiteral<'_>, bool)>) -> Result<(), CompileError> {
+        let binding = |type_val, constant, type_fn| Binding {
+            type_val,
+            constant,
+            known_type: None,
+            type_fn,
+            value: None,
+            slot: None,
+        };
+
+        let mut redeclared = Vec::new();
+        let mut slots = Vec::new();
+        for (literal, constant) in &group {
+            let name = literal.name;
+            redeclared.push(match self.slots.last() {
+                Some(_) => None,
+                None => self.lookup(name).ok().map(|existing| existing.type_val.clone()),
+            });
+
+            slots.push(match self.slots.last_mut() {
+                // a global can't be declared with the placeholder's type, so it is added without checking
+                None => {
+                    self.scopes[0].insert(name.to_string(), binding(Type::Unit, *constant, None));
+                    None
+                }
+                Some(_) => self.declare(name, binding(Type::Unit, *constant, None))?,
+            });
+        }
+
+        let (literals, constants): (Vec<FnLiteral>, Vec<bool>) = group.into_iter().unzip();
+        let retype = |compiler: &mut Compiler, i: usize, type_val| {
+            let scope = compiler.scopes.last_mut().expect("No scope to declare in.");
+            scope.get_mut(literals[i].name).expect("Function was declared.").type_val = type_val;
+        };
+        let compiled = self.compile_recursive(&literals, retype)?;
+
+        for ((literal, existing), (type_val, _)) in literals.iter().zip(&redeclared).zip(&compiled) {
+            if let Some(existing) = existing {
+                expect_redeclared(literal.name, existing, type_val)?;
+            }
+        }
+
+        // the functions were pushed in order, so they are stored last first
+        for (i, (type_val, type_fn)) in compiled.into_iter().enumerate().rev() {
+            let name = literals[i].name;
+            let scope = self.scopes.last_mut().expect("No scope to declare in.");
+            scope.insert(name.to_string(), Binding { slot: slots[i], ..binding(type_val, constants[i], type_fn) });
+
+            match slots[i] {
+                Some(slot) => self.emit(Instruction::StoreLocal(slot)),
+                None => self.emit(Instruction::DeclareGlobal(name.into())),
+            };
+        }
+
+        Ok(())
+    }
+
+    // compile function literals that may call themselves and each other through the names declare gives them.
+    // nothing says what a function returns but its body, so each is declared to return a placeholder until
+    // compiling it gives its type, which the functions compiled after it then see. placeholders type check as
+    // anything, so the functions are compiled again until every body was compiled against the types the
+    // functions turned out to have and no placeholder is left.
+    fn compile_recursive(
+        &mut self,
+        literals: &[FnLiteral],
+        declare: impl Fn(&mut Compiler, usize, Type),
+    ) -> Result<Vec<(Type, Option<TypeFn>)>, CompileError> {
+        let placeholders: Vec<String> = literals.iter().map(|literal| placeholder(literal.name)).collect();
+        let unsettled = |t: &Type| placeholders.iter().any(|p| t.mentions(p));
+
+        let mut declared = Vec::new();
+        for (literal, placeholder) in literals.iter().zip(&placeholders) {
+            self.scopes.push(HashMap::new());
+            self.slots.push(0);
+            let params = self.declare_params(literal.param_names, literal.param_types);
+            self.scopes.pop();
+            self.slots.pop();
+
+            declared.push(fn_type(&params?, literal.param_names, Type::Var(placeholder.clone())));
+        }
+        for (i, type_val) in declared.iter().enumerate() {
+            declare(self, i, type_val.clone());
+        }
+
+        let start = self.instructions.len();
+        let methods = self.methods.clone();
+        let outer_called = mem::replace(&mut self.placeholder_called, false);
+
+        // each round settles the return type of at least one more function, plus one to check them
+        for round in 0..=literals.len() {
+            let seen = declared.clone();
+            self.placeholder_called = false;
+
+            let mut compiled = Vec::new();
+            for (i, literal) in literals.iter().enumerate() {
+                let (type_val, type_fn) =
+                    self.compile_fn(literal.name, literal.param_names, literal.param_types, literal.body)?;
+
+                if !unsettled(&type_val) {
+                    declared[i] = type_val.clone();
+                    declare(self, i, type_val.clone());
+                }
+                compiled.push((type_val, type_fn));
+            }
+
+            // in the first round, functions that called none of the placeholders saw only final types
+            let settled = compiled.iter().all(|(type_val, _)| !unsettled(type_val));
+            if settled && (declared == seen || round == 0 && !self.placeholder_called) {
+                self.placeholder_called = outer_called || self.placeholder_called;
+                return Ok(compiled);
+            }
+
+            // a function that only returns the result of calling these functions has nothing to settle on
+            if !settled && declared == seen {
+                break;
+            }
+
+            self.instructions.truncate(start);
+            self.locs.truncate(start);
+            self.methods = methods.clone();
+        }
+
+        let names: Vec<&str> = literals.iter().map(|literal| literal.name).collect();
+        fail!("Type error: cannot infer the return type of {}.", names.join(", "))
+    }
+
+    // attach a method of the given type to a type, checking it takes a value of that type first
+    fn declare_method(&mut self, target_type: &Type, name: &str, method_type: Type) -> Result<(), CompileError> {
+        match method_type {
+            Type::Fn { params, returns } if params.first() == Some(target_type) => {
+                self.methods.insert(target_type.clone(), name, Type::Fn { params, returns });
+                Ok(())
+            }
+            t => fail!(
+                "Type error: method {} of {} must take a {} as its first parameter but has type {}.",
+                name, target_type, target_type, t
+            ),
+        }
+    }
+
+    // if the operand holds an err, return it from the enclosing function, otherwise unwrap its ok value
+    fn compile_propagate(&mut self, operand: &AstNode) -> Result<Type, CompileError> {
+        let operand_type = self.compile_exp(operand)?;
+        let (ok_type, error_type) = match &operand_type {
+            Type::Union(variants) if variants.len() == 2 => match (variants.get("ok"), variants.get("err")) {
+                (Some(ok_type), Some(error_type)) => (ok_type.clone(), error_type.clone()),
+                _ => fail!("Type error: ? needs a Result but got {}.", operand_type),
+            },
+            t => fail!("Type error: ? needs a Result but got {}.", t),
+        };
+
+        self.emit(Instruction::Dup);
+        self.emit(Instruction::IsVariant("err".into()));
+        let propagate = self.emit(Instruction::JumpIf(0));
+        self.emit(Instruction::Payload);
+        let end = self.emit(Instruction::Jump(0));
+
+        self.patch_jump(propagate);
+        self.emit(Instruction::Payload);
+        // the function's return type, which is not known until all of it has been compiled
+        let push = self.add_constant(KytheraVal::unit())?;
+        self.emit(Instruction::Constant(push as u16));
+        self.emit(Instruction::MakeVariant("err".into()));
+        self.emit(Instruction::Return);
+        self.patch_jump(end);
+
+        match self.propagated.last_mut() {
+            Some(propagated) => propagated.push((error_type, push)),
+            None => fail!("'?' outside of a function."),
+        }
+
+        Ok(ok_type)
+    }
+
+    // each arm checks whether the subject is its variant and jumps to the next arm if not. the subject stays
+    // on the stack until an arm takes it.
+    fn compile_when(
+        &mut self,
+        subject: &AstNode,
+        arms: &[WhenArm],
+        else_body: Option<&AstNode>,
+    ) -> Result<Type, CompileError> {
+        let union_type = self.compile_exp(subject)?;
+        let variants = match &union_type {
+            Type::Union(variants) => variants,
+            t => fail!("Type error: cannot match on {}.", t),
+        };
+
+        for arm in arms {
+            if variants.get(&arm.tag).is_none() {
+                fail!("Type error: {} has no variant {}.", union_type, arm.tag);
+            }
+        }
+
+        if else_body.is_none() {
+            let missing: Vec<&str> = variants
+                .iter()
+                .map(|(tag, _)| tag.as_str())
+                .filter(|tag| !arms.iter().any(|arm| arm.tag == *tag))
+                .collect();
+
+            if !missing.is_empty() {
+                fail!("Non-exhaustive when on {}: missing {}.", union_type, missing.join(", "));
+            }
+        }
+
+        let mut result_type: Option<Type> = None;
+        let mut end_jumps = Vec::new();
+
+        for (i, arm) in arms.iter().enumerate() {
+            // without an else, the last arm is the only variant left, so it needs no check
+            let next_arm = if else_body.is_some() || i < arms.len() - 1 {
+                self.emit(Instruction::Dup);
+                self.emit(Instruction::IsVariant((&arm.tag).into()));
+                self.emit(Instruction::Not);
+                Some(self.emit(Instruction::JumpIf(0)))
+            } else {
+                None
+            };
+
+            match &arm.pattern {
+                Some(pattern) => {
+                    self.emit(Instruction::Payload);
+                    self.compile_pattern(
+                        pattern,
+                        Binding {
+                            type_val: variants.get(&arm.tag).expect("Arm was checked.").clone(),
+                            constant: false,
+                            known_type: None,
+                            type_fn: None,
+                            value: None,
+                            slot: None,
+                        },
+                    )?;
+                }
+                None => {
+                    self.emit(Instruction::Pop);
+                }
+            }
+
+            let arm_type = self.compile_exp(&arm.body)?;
+            match &result_type {
+                Some(t) => expect_type(t, &arm_type, "when arm")?,
+                None => result_type = Some(arm_type),
+            }
+
+            end_jumps.push(self.emit(Instruction::Jump(0)));
+
+            if let Some(next_arm) = next_arm {
+                self.patch_jump(next_arm);
+            }
+        }
+
+        if let Some(else_body) = else_body {
+            self.emit(Instruction::Pop);
+
+            let else_type = self.compile_exp(else_body)?;
+            match &result_type {
+                Some(t) => expect_type(t, &else_type, "when arm")?,
+                None => result_type = Some(else_type),
+            }
+        }
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+
+        Ok(result_type.expect("Exhaustive when without arms."))
+    }
+
+    fn compile_assignment(&mut self, lhs: &AstNode, op: Symbol, rhs: &AstNode) -> Result<Type, CompileError> {
+        let name = match lhs {
+            AstNode::Identifier(name) => name,
+            _ => fail!("Cannot assign to {:?}.", lhs),
+        };
+
+        let binding = self.lookup(name)?;
+        if binding.constant {
+            fail!("Cannot assign to constant {}.", name);
+        }
+        let type_val = binding.type_val.clone();
+
+        let rhs_type = if op == Equal {
+            self.compile_exp(rhs)?
+        } else {
+            // compound assignment, e.g. x += 1 is x = x + 1
+            self.emit_load(name)?;
+            let rhs_type = self.compile_exp(rhs)?;
+
+            let arithmetic_op = match op {
+                PlusEqual => Plus,
+                MinusEqual => Minus,
+                StarEqual => Star,
+                SlashEqual => Slash,
+                PercentEqual => Percent,
+                _ => unreachable!(),
+            };
+
+            self.emit_binary(arithmetic_op, &type_val, &rhs_type)?
+        };
+
+        expect_type(&type_val, &rhs_type, "assigned value")?;
+
+        // the assigned value may not be known at compile time anymore
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some(binding) = scope.get_mut(name) {
+                binding.known_type = None;
+                binding.type_fn = None;
+                break;
+            }
+        }
+
+        self.emit(Instruction::Dup);
+        self.emit_store(name)?;
+
+        Ok(type_val)
+    }
+
+    // && and || only evaluate their right-hand side if the left-hand side does not decide the result
+    fn compile_short_circuit(&mut self, lhs: &AstNode, op: Symbol, rhs: &AstNode) -> Result<Type, CompileError> {
+        let start = self.instructions.len();
+        let lhs_type = self.compile_exp(lhs)?;
+        expect_type(&Type::Bool, &lhs_type, &format!("operand of {:?}", op))?;
+
+        // a constant left-hand side either decides the result or leaves it to the right-hand side, which is
+        // still checked even if it will never run
+        if let Some(lhs) = self.constant(&self.instructions[start..]) {
+            self.instructions.truncate(start);
+            self.locs.truncate(start);
+
+            let rhs_type = self.compile_exp(rhs)?;
+            expect_type(&Type::Bool, &rhs_type, &format!("operand of {:?}", op))?;
+
+            if lhs.val == InternalVal::Bool(op == BarBar) {
+                self.fold(start, Ok(lhs))?;
+            }
+
+            return Ok(Type::Bool);
+        }
+
+        // keep the left-hand side as the result if it decides the result, otherwise discard it
+        self.emit(Instruction::Dup);
+        if op == AndAnd {
+            self.emit(Instruction::Not);
+        }
+        let jump = self.emit(Instruction::JumpIf(0));
+        self.emit(Instruction::Pop);
+
+        let rhs_type = self.compile_exp(rhs)?;
+        expect_type(&Type::Bool, &rhs_type, &format!("operand of {:?}", op))?;
+
+        self.patch_jump(jump);
+
+        Ok(Type::Bool)
+    }
+
+    // emit a binary operator whose operands are already on the stack, returning its result type
+    fn emit_binary(&mut self, op: Symbol, lhs: &Type, rhs: &Type) -> Result<Type, CompileError> {
+        // a result whose type is still being inferred is taken to suit the other operand
+        let (lhs, rhs) = match (is_placeholder(lhs), is_placeholder(rhs)) {
+            (true, false) => (rhs, rhs),
+            (false, true) => (lhs, lhs),
+            _ => (lhs, rhs),
+        };
+
+        let (inst, result) = match (op, lhs, rhs) {
+            (Plus, Type::String, Type::String) => (Instruction::Add, Type::String),
+            (Plus, Type::Int, Type::Int) | (Plus, Type::Double, Type::Double) => {
+                (Instruction::Add, lhs.clone())
+            }
+            (Minus, Type::Int, Type::Int) | (Minus, Type::Double, Type::Double) => {
+                (Instruction::Sub, lhs.clone())
+            }
+            (Star, Type::Int, Type::Int) | (Star, Type::Double, Type::Double) => {
+                (Instruction::Mul, lhs.clone())
+            }
+            (Slash, Type::Int, Type::Int) | (Slash, Type::Double, Type::Double) => {
+                (Instruction::Div, lhs.clone())
+            }
+            (Percent, Type::Int, Type::Int) | (Percent, Type::Double, Type::Double) => {
+                (Instruction::Mod, lhs.clone())
+            }
+            (EqualEqual, _, _) if lhs == rhs => (Instruction::Equal, Type::Bool),
+            (BangEqual, _, _) if lhs == rhs => (Instruction::NotEqual, Type::Bool),
+            (Less, _, _) | (LessEqual, _, _) | (Greater, _, _) | (GreaterEqual, _, _)
+                if lhs == rhs && [Type::Int, Type::Double, Type::String].contains(lhs) =>
+            {
+                let inst = match op {
+                    Less => Instruction::Less,
+                    LessEqual => Instruction::LessEqual,
+                    Greater => Instruction::Greater,
+                    _ => Instruction::GreaterEqual,
+                };
+
+                (inst, Type::Bool)
+            }
+            // |, & and ^ on Bool are logical operators that always evaluate both sides
+            (Bar, Type::Bool, Type::Bool) | (Bar, Type::Int, Type::Int) => (Instruction::Or, lhs.clone()),
+            (And, Type::Bool, Type::Bool) | (And, Type::Int, Type::Int) => (Instruction::And, lhs.clone()),
+            (Caret, Type::Bool, Type::Bool) | (Caret, Type::Int, Type::Int) => (Instruction::Xor, lhs.clone()),
+            (LessLess, Type::Int, Type::Int) => (Instruction::ShiftLeft, Type::Int),
+            (GreaterGreater, Type::Int, Type::Int) => (Instruction::ShiftRight, Type::Int),
+            _ => fail!(
+                "Type error: invalid operands for {:?}: {} and {}.",
+                op, lhs, rhs
+            ),
+        };
+
+        self.emit(inst);
+        Ok(result)
+    }
+
+    // the type held by a type expression, which must be known at compile time
+    fn eval_type(&self, node: &AstNode) -> Result<Type, CompileError> {
+        match self.known_type(node) {
+            Some(t) => Ok(t),
+            None => {
+                if let AstNode::Identifier(name) = node {
+                    self.lookup(name)?;
+                }
+
+                fail!("Expected a type known at compile time but got {:?}.", node)
+            }
+        }
+    }
+
+    // the type held by a type expression, if it can be determined at compile time
+    fn known_type(&self, node: &AstNode) -> Option<Type> {
+        match node {
+            AstNode::Identifier(name) => self.lookup(name).ok()?.known_type.clone(),
+            AstNode::Literal(Literal::StructType(fields)) => {
+                let mut field_types = Fields::new();
+                for (name, field_type) in fields {
+                    field_types.insert(name.clone(), self.known_type(field_type)?);
+                }
+
+                Some(Type::Struct(field_types))
+            }
+            AstNode::Literal(Literal::UnionType(variants)) => {
+                let mut variant_types = Fields::new();
+                for (tag, variant_type) in variants {
+                    variant_types.insert(tag.clone(), self.known_type(variant_type)?);
+                }
+
+                Some(Type::Union(variant_types))
+            }
+            AstNode::Literal(Literal::FnType { param_types, returns }) => Some(Type::Fn {
+                params: param_types
+                    .iter()
+                    .map(|t| self.known_type(t))
+                    .collect::<Option<Vec<Type>>>()?,
+                returns: Box::from(self.known_type(returns)?),
+            }),
+            AstNode::Call { target, arguments } => {
+                let type_fn = match target.as_ref() {
+                    AstNode::Identifier(name) => self.lookup(name).ok()?.type_fn.as_ref()?,
+                    _ => return None,
+                };
+
+                let mut args = Vec::new();
+                for (name, arg) in type_fn.params.iter().zip(arguments) {
+                    match self.known_type(arg) {
+                        Some(t) => args.push((name.clone(), t)),
+                        None if type_fn.returns.mentions(name) => return None,
+                        None => {}
+                    }
+                }
+
+                Some(type_fn.returns.substitute(&args))
+            }
+            _ => None,
+        }
+    }
+}
+
+// the type of accessing a variant through its union type: the union itself for a variant holding unit,
+// otherwise a function constructing the variant
+fn variant_constructor_type(union_type: &Type, tag: &str) -> Option<Type> {
+    match union_type {
+        Type::Union(variants) => match variants.get(tag)? {
+            Type::Unit => Some(union_type.clone()),
+            variant_type => Some(Type::Fn {
+                params: vec![variant_type.clone()],
+                returns: Box::from(union_type.clone()),
+            }),
+        },
+        _ => None,
+    }
+}
+
+fn undefined(name: &str) -> CompileError {
+    CompileError::new(format!("Undefined variable {}.", name))
+}
+
+// whether two constants can share a slot in the constant pool. 0.0 and -0.0 are equal but not the same.
+fn same_constant(a: &KytheraVal, b: &KytheraVal) -> bool {
+    match (&a.val, &b.val) {
+        (InternalVal::Double(a), InternalVal::Double(b)) => a.to_bits() == b.to_bits(),
+        _ => a == b,
+    }
+}
+
+fn expect_type(expected: &Type, got: &Type, context: &str) -> Result<(), CompileError> {
+    if expected != got && !is_placeholder(expected) && !is_placeholder(got) {
+        fail!("Type error: expected {} but got {} for {}.", expected, got, context);
+    }
+
+    Ok(())
+}
+
+// a global is looked up by name, so code compiled against its old type must still be right
+fn expect_redeclared(name: &str, existing: &Type, new: &Type) -> Result<(), CompileError> {
+    if existing != new {
+        fail!(
+            "Type error: cannot declare {} again as {} since it is {}.",
+            name, new, existing
+        );
+    }
+
+    Ok(())
+}
+
+// the type variable a function whose return type is being inferred is taken to return, see
+// Compiler::compile_recursive. it can't be confused with a type parameter, since it isn't a name.
+fn placeholder(name: &str) -> String {
+    format!("<result of {}>", name)
+}
+
+fn is_placeholder(t: &Type) -> bool {
+    matches!(t, Type::Var(name) if name.starts_with('<'))
+}
+
+// the type of a function, where parameters holding types that the rest of the type refers to become type
+// parameters
+fn fn_type(params: &[Type], param_names: &[String], returns: Type) -> Type {
+    let params = params
+        .iter()
+        .zip(param_names)
+        .enumerate()
+        .map(|(i, (param_type, name))| {
+            let referred_to = params[i + 1..].iter().any(|t| t.mentions(name)) || returns.mentions(name);
+
+            if *param_type == Type::Type && referred_to {
+                Type::TypeParam(name.clone())
+            } else {
+                param_type.clone()
+            }
+        })
+        .collect();
+
+    Type::Fn {
+        params,
+        returns: Box::from(returns),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::error::TraceFrame;
+    use crate::testing::{compile, run, try_run};
+    use crate::vm::{InternalVal, Limits};
+
+    // the value pushed by the last statement of a program, if it was folded to a constant
+    fn folded(input: &str) -> Option<KytheraVal> {
+        let function = compile(input);
+        match function.instructions.as_slice() {
+            [.., Instruction::Constant(index), Instruction::Return] => Some(function.constants[*index as usize].clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn and_short_circuits() {
+        let result = run("let called = false;
+let f = () => { called = true; return true; };
+let a = false && f();
+let b = true && f();
+{ !a && b && called; };");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+
+        let result = run("let called = false;
+let f = () => { called = true; return true; };
+false && f();
+called;");
+
+        assert_eq!(result.val, InternalVal::Bool(false));
+    }
+
+    #[test]
+    fn or_short_circuits() {
+        let result = run("let called = false;
+let f = () => { called = true; return false; };
+let a = true || f();
+{ a && !called; };");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+
+        let result = run("let called = false;
+let f = () => { called = true; return false; };
+let a = false || f();
+{ !a && called; };");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+    }
+
+    #[test]
+    fn bar_and_evaluate_both_sides() {
+        let result = run("let calls = 0;
+let f = (b: Bool,) => { calls += 1; return b; };
+let a = f(false,) & f(true,);
+let b = f(true,) | f(false,);
+{ !a && b && calls == 4; };");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+    }
+
+    #[test]
+    fn de_morgans() {
+        let result = run("let testDeMorgans = (a: Bool, b: Bool,) => {
+    return (!(a || b) == (!a && !b)) && (!(a && b) == (!a || !b));
+};
+testDeMorgans(true, true,) && testDeMorgans(true, false,) && testDeMorgans(false, false,) && testDeMorgans(false, true,);");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected Bool but got Int for operand of AndAnd.")]
+    fn logical_operands_must_be_bool() {
+        run("1 && true;");
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let result = run("let flags = 12 | 3;
+let masked = flags & ~4;
+let toggled = masked ^ 1;
+let packed = (5 << 8) | 9;
+{ flags == 15 && masked == 11 && toggled == 10 && packed >> 8 == 5 && (packed & 255) == 9 && (true ^ false); };");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+    }
+
+    #[test]
+    fn shifts_out_of_range() {
+        let result = run("{ 1 << 31 == ~2147483647 && 1 << 32 == 0 && 1 << 100 == 0 && ~0 >> 40 == ~0 && 1 >> 40 == 0; };");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Negative shift amount -1.")]
+    fn negative_shift() {
+        run("1 << (0 - 1);");
+    }
+
+    #[test]
+    fn struct_type_ignores_field_order() {
+        let result = run("let myStruct = { x = 1, y = 1.01 };
+let myStruct2 = { y = 2.01, x = 2 };
+let s = myStruct;
+s = myStruct2;
+{ typeof myStruct == typeof myStruct2 && typeof myStruct == { y: Double, x: Int } && s.x == 2; };");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+    }
+
+    #[test]
+    fn struct_display_keeps_field_order() {
+        assert_eq!(run("{ y = 2.01, x = 2 };").to_string(), "{ y = 2.01, x = 2 }");
+        assert_eq!(run("typeof { y = 2.01, x = 2 };").to_string(), "{ y: Double, x: Int }");
+    }
+
+    #[test]
+    fn struct_spread() {
+        let result = run("let myStruct = { x = 1, y = 1.01 };
+let updated = { ...myStruct, x = 3 };
+{ typeof updated == typeof myStruct && updated.x == 3 && updated.y == 1.01 && myStruct.x == 1; };");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+
+        // overridden fields keep their place, new fields go at the end, and later entries win
+        let result = run("let a = { x = 1, y = 2 };
+let b = { z = true, x = 4 };
+{ w = 0, ...a, y = 3.5, ...b };");
+
+        assert_eq!(result.to_string(), "{ w = 0, x = 4, y = 3.5, z = true }");
+        assert_eq!(result.type_val.to_string(), "{ w: Int, x: Int, y: Double, z: Bool }");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: cannot spread value of type Int.")]
+    fn spread_non_struct() {
+        run("{ ...1, x = 2 };");
+    }
+
+    #[test]
+    fn lists() {
+        let result = run("let xs = [1, 2, 3];
+xs[0] + xs[2];");
+
+        assert_eq!(result.val, InternalVal::Int(4));
+        assert_eq!(run("typeof [[1.5], [2.5]];").to_string(), "List(List(Double))");
+    }
+
+    #[test]
+    #[should_panic(expected = "Index 3 out of bounds for list of length 3.")]
+    fn index_out_of_bounds() {
+        run("[1, 2, 3][3];");
+    }
+
+    #[test]
+    fn destructure_struct() {
+        let result = run("let myStruct = { x = 1, y = 1.01 };
+let { x, y = py } = myStruct;
+{ x == 1 && py == 1.01; };");
+
+        assert_eq!(result.val, InternalVal::Bool(true));
+    }
+
+    #[test]
+    fn destructure_nested() {
+        let result = run("let shape = { name = 10, points = [{ x = 1, y = 2 }, { x = 3, y = 4 }] };
+let { points = [{ x = x1, y = y1 }, second], name } = shape;
+let [[a, b], [c]] = [[5, 6], [7]];
+let { x = x2 } = second;
+{ x1 + y1 + x2 + name + a + b + c; };");
+
+        assert_eq!(result.val, InternalVal::Int(34));
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: { x: Int, y: Double } has no field z to destructure.")]
+    fn destructure_missing_field() {
+        run("let { x, z } = { x = 1, y = 1.01 };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Index 2 out of bounds for list of length 2.")]
+    fn destructure_short_list() {
+        run("let [a, b, c] = [1, 2];");
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot assign to constant x.")]
+    fn destructured_constants() {
+        run("const { x } = { x = 1 }; x = 2;");
+    }
+
+    #[test]
+    fn methods() {
+        let result = run("const Point = { x: Int, y: Int };
+impl Point {
+    sum = (self: Point) => { return self.x + self.y; },
+    scaled = (self: Point, by: Int) => { return { x = self.x * by, y = self.y * by }; },
+};
+let p = { x = 1, y = 2 };
+let f = p.scaled;
+[p.sum(), p.scaled(3).sum(), Point.sum(p), f(2).x];");
+
+        assert_eq!(result.to_string(), "[3, 9, 3, 2]");
+    }
+
+    #[test]
+    fn methods_on_builtin_types() {
+        let result = run("impl Int { double = (self: Int) => { return self * 2; } };
+let n = 4;
+n.double();");
+
+        assert_eq!(result.val, InternalVal::Int(8));
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: method sum of Int must take a Int as its first parameter")]
+    fn method_first_param_must_match() {
+        run("impl Int { sum = (self: Double) => { return self; } };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: { x: Int } has no field or method sum.")]
+    fn unknown_method() {
+        run("let p = { x = 1 }; p.sum();");
+    }
+
+    #[test]
+    fn generic_functions() {
+        let result = run("const id = (T: Type, x: T) => { return x; };
+const twice = (T: Type, x: T) => { return [id(T, x), x]; };
+{ a = id(Int, 1) + 1, b = twice(Int, 3)[1], c = id(String, \"a\") == \"a\" };");
+
+        assert_eq!(result.to_string(), "{ a = 2, b = 3, c = true }");
+    }
+
+    #[test]
+    fn generic_function_types() {
+        let result = run("const id = (T: Type, x: T) => { return x; };
+const f = (x: Int) => { return x; };
+[typeof id, typeof f, typeof (T: Type) => { return T; }];");
+
+        assert_eq!(result.to_string(), "[(T: Type, T) => T, (Int,) => Int, (Type,) => Type]");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected Int but got String for argument.")]
+    fn generic_argument_mismatch() {
+        run("const id = (T: Type, x: T) => { return x; }; id(Int, \"a\");");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: invalid operands for Plus: String and Int.")]
+    fn generic_result_type() {
+        run("const id = (T: Type, x: T) => { return x; }; id(String, \"a\") + 1;");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: the type argument for T must be known at compile time.")]
+    fn generic_type_argument_must_be_known() {
+        run("const id = (T: Type, x: T) => { return x; }; let t = typeof 1; id(t, 1);");
+    }
+
+    #[test]
+    fn generic_list() {
+        let result = run("const first = (T: Type, xs: List(T)) => { return xs[0]; };
+const Ints = List(Int);
+const sum = (xs: Ints) => { return xs[0] + xs[1]; };
+let xs = [3, 4];
+{ a = first(Int, xs) + sum(xs), b = first(String, [\"a\"]) == \"a\", c = typeof xs == Ints };");
+
+        assert_eq!(result.to_string(), "{ a = 10, b = true, c = true }");
+    }
+
+    #[test]
+    fn generic_option() {
+        let result = run("const Option = (T: Type) => { return { some: Bool, value: T }; };
+const some = (T: Type, value: T) => { return { some = true, value = value }; };
+const unwrap = (T: Type, option: Option(T)) => { return option.value; };
+const IntOption = Option(Int);
+let o = some(Int, 5);
+const unwrapInt = (option: IntOption) => { return unwrap(Int, option); };
+[unwrap(Int, o) + 1, unwrapInt(some(Int, 2))];");
+
+        assert_eq!(result.to_string(), "[6, 2]");
+
+        let result = run("const Option = (T: Type) => { return { some: Bool, value: T }; };
+const some = (T: Type, value: T) => { return { some = true, value = value }; };
+[typeof some(Int, 5) == Option(Int), typeof some(Int, 5) == Option(Double)];");
+
+        assert_eq!(result.to_string(), "[true, false]");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected { some: Bool, value: Int } but got { some: Bool, value: Double }")]
+    fn generic_option_mismatch() {
+        run("const Option = (T: Type) => { return { some: Bool, value: T }; };
+const unwrap = (T: Type, option: Option(T)) => { return option.value; };
+unwrap(Int, { some = true, value = 1.5 });");
+    }
+
+    #[test]
+    fn unions() {
+        let result = run("const Shape = { circle: Int | square: { side: Int } | point: Unit };
+const area = (shape: Shape) => {
+    return when shape {
+        circle(r) => 3 * r * r,
+        square({ side }) => side * side,
+        point => 0,
+    };
+};
+let shapes = [Shape.circle(2), Shape.square({ side = 3 }), Shape.point];
+{ a = area(shapes[0]), b = area(shapes[1]), c = area(shapes[2]), shapes = shapes };");
+
+        assert_eq!(result.to_string(), "{ a = 12, b = 9, c = 0, shapes = [circle(2), square({ side = 3 }), point] }");
+    }
+
+    #[test]
+    fn union_types() {
+        let result = run("const Option = (T: Type) => { return { some: T | none: Unit }; };
+let a = Option(Int).some(1);
+let b = Option(Int).none;
+{
+    a = typeof a == Option(Int),
+    b = typeof b == { none: Unit | some: Int },
+    c = a == Option(Int).some(1),
+    d = a == b,
+    e = typeof a,
+};");
+
+        assert_eq!(
+            result.to_string(),
+            "{ a = true, b = true, c = true, d = false, e = { some: Int | none: Unit } }"
+        );
+    }
+
+    #[test]
+    fn when_else() {
+        let result = run("const Light = { red: Unit | yellow: Unit | green: Int };
+const canGo = (light: Light) => {
+    return when light { green(seconds) => seconds > 2, else => false };
+};
+{ a = canGo(Light.green(5)), b = canGo(Light.green(1)), c = canGo(Light.red) };");
+
+        assert_eq!(result.to_string(), "{ a = true, b = false, c = false }");
+    }
+
+    #[test]
+    #[should_panic(expected = "Non-exhaustive when on { red: Unit | yellow: Unit | green: Int }: missing red, yellow.")]
+    fn when_must_be_exhaustive() {
+        run("const Light = { red: Unit | yellow: Unit | green: Int };
+when Light.red { green(seconds) => 1 };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: { a: Unit | b: Unit } has no variant c.")]
+    fn when_unknown_variant() {
+        run("const T = { a: Unit | b: Unit }; when T.a { a => 1, b => 2, c => 3 };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected Int but got Bool for when arm.")]
+    fn when_arms_must_agree() {
+        run("const T = { a: Unit | b: Unit }; when T.a { a => 1, b => true };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected Int but got String for argument.")]
+    fn variant_constructor_checks_type() {
+        run("const T = { a: Int | b: Unit }; T.a(\"one\");");
+    }
+
+    #[test]
+    fn propagate_errors() {
+        let result = run("const IntResult = Result(Int, String);
+const BoolResult = Result(Bool, String);
+const double = (r: IntResult) => {
+    let n = r?;
+    return IntResult.ok(n * 2);
+};
+const isBig = (r: IntResult) => {
+    let n = double(r)?;
+    return BoolResult.ok(n > 10);
+};
+const describe = (r: IntResult) => {
+    return when isBig(r) {
+        ok(big) => \"big: \" + (when BoolResult.ok(big) { ok(b) => \"yes\", err(e) => e }),
+        err(message) => \"caught: \" + message,
+    };
+};
+let failed = isBig(IntResult.err(\"bad input\"));
+{
+    a = isBig(IntResult.ok(6)),
+    b = failed,
+    c = typeof failed == BoolResult,
+    d = describe(IntResult.ok(1)),
+    e = describe(IntResult.err(\"bad input\")),
+};");
+
+        assert_eq!(
+            result.to_string(),
+            "{ a = ok(true), b = err(bad input), c = true, d = big: yes, e = caught: bad input }"
+        );
+    }
+
+    #[test]
+    fn propagate_through_methods() {
+        let result = run("const Parsed = Result(Int, String);
+impl Parsed {
+    unwrapOr = (self: Parsed, default: Int) => {
+        return when self { ok(n) => n, err(e) => default };
+    },
+    plus = (self: Parsed, other: Parsed) => {
+        return Parsed.ok(self? + other?);
+    },
+};
+[Parsed.ok(1).plus(Parsed.ok(2)).unwrapOr(0), Parsed.ok(1).plus(Parsed.err(\"x\")).unwrapOr(0)];");
+
+        assert_eq!(result.to_string(), "[3, 0]");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: ? needs a Result but got { some: Int | none: Unit }.")]
+    fn propagate_needs_result() {
+        run("const Option = { some: Int | none: Unit };
+const f = (o: Option) => { return o?; };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: ? propagates an error of type String but the function returns Int.")]
+    fn propagate_needs_matching_return() {
+        run("const f = (r: Result(Int, String)) => { return r? + 1; };");
+    }
+
+    #[test]
+    #[should_panic(expected = "'?' outside of a function.")]
+    fn propagate_outside_function() {
+        run("Result(Int, String).ok(1)?;");
+    }
+
+    #[test]
+    fn constant_folding() {
+        assert_eq!(folded("1 + 2 * 3 + 4;"), Some(KytheraVal::int(11)));
+        assert_eq!(folded("!(1 < 2) || 2.5 * 2.0 == 5.0;"), Some(KytheraVal::bool(true)));
+        assert_eq!(folded("~0 ^ 1 << 3;"), Some(KytheraVal::int(-9)));
+        assert_eq!(folded("\"a\" + \"b\";"), Some(KytheraVal::string("ab".to_string())));
+
+        // the whole program is a single push
+        assert_eq!(compile("1 + 2 * 3 + 4;").instructions.len(), 2);
+    }
+
+    #[test]
+    fn constants_are_inlined() {
+        assert_eq!(folded("const a = 2; const b = a * 3; b + 1;"), Some(KytheraVal::int(7)));
+        assert_eq!(folded("let a = 2; a + 1;"), None);
+    }
+
+    #[test]
+    fn constant_short_circuit() {
+        let f = "let f = () => { return true; };";
+
+        assert_eq!(folded(&format!("{} false && f();", f)), Some(KytheraVal::bool(false)));
+        assert_eq!(folded(&format!("{} true || f();", f)), Some(KytheraVal::bool(true)));
+        assert_eq!(folded(&format!("{} true && f();", f)), None);
+        assert_eq!(run(&format!("{} true && f();", f)).val, InternalVal::Bool(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected Bool but got Int for operand of AndAnd.")]
+    fn unreachable_operand_is_checked() {
+        compile("false && 1;");
+    }
+
+    #[test]
+    fn type_literals_are_evaluated_at_compile_time() {
+        let fn_type = Type::Fn {
+            params: vec![Type::Int, Type::Int],
+            returns: Box::from(Type::Int),
+        };
+
+        assert_eq!(folded("(Int, Int) => Int;"), Some(KytheraVal::type_val(fn_type)));
+        assert_eq!(
+            folded("const P = { x: Int }; { p: P, l: List(Int) };").map(|t| t.to_string()),
+            Some("{ p: { x: Int }, l: List(Int) }".to_string()),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Error in constant expression: Division by zero.")]
+    fn constant_division_by_zero() {
+        compile("const x = 1 / (2 - 2);");
+    }
+
+    #[test]
+    fn recursive_functions() {
+        // the return type is inferred from the returns that are not recursive calls
+        let result = run("const sum = (n: Int, total: Int) => { return n == 0 && total == 15 || sum(n - 1, total + n); };
+sum(5, 0);");
+        assert_eq!(result.val, InternalVal::Bool(true));
+
+        // local functions capture the slot they are stored in
+        let result = run("const outer = (n: Int) => {
+    const down = (m: Int) => { return m == 0 || down(m - 1); };
+    const within = (m: Int) => { return m > 0 && (outer(m - 1) || within(m - 1)); };
+    return down(n) && !within(0);
+};
+outer(5);");
+        assert_eq!(result.val, InternalVal::Bool(true));
+
+        // consecutive declarations can call each other, wherever they are
+        let result = run("const parity = (n: Int) => {
+    const even = (m: Int) => { return m == 0 || odd(m - 1); };
+    const odd = (m: Int) => { return m != 0 && even(m - 1); };
+    return odd(n);
+};
+parity(7);");
+        assert_eq!(result.val, InternalVal::Bool(true));
+    }
+
+    #[test]
+    fn nested_recursive_functions() {
+        let all = "const all = (n: Int) => {
+    const inner = (m: Int) => {
+        const small = (k: Int) => { return k == 0 || k < 5 && small(k - 1); };
+        return m == 0 || small(m) && inner(m - 1);
+    };
+    return n == 0 || inner(n) && all(n - 1);
+};";
+        assert_eq!(run(&format!("{} all(4);", all)).val, InternalVal::Bool(true));
+        assert_eq!(run(&format!("{} all(6);", all)).val, InternalVal::Bool(false));
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected Bool but got Int for operand of BarBar.")]
+    fn recursive_functions_are_type_checked() {
+        compile("const f = (n: Int) => { return n == 0 || f(n - 1) + 1; };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: invalid operands for Plus: Bool and Int.")]
+    fn recursive_function_with_wrong_return_type() {
+        // the placeholder for f's result type checks as an Int until f turns out to return a Bool
+        compile("const f = (n: Int) => { let next = f(n - 1) + 1; return n == next; };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: cannot infer the return type of forever.")]
+    fn recursive_function_that_never_returns() {
+        compile("const forever = (n: Int) => { return forever(n + 1); };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: cannot declare x again as String since it is Int.")]
+    fn global_redeclared_with_another_type() {
+        compile("let x = 1; const g = () => { return x; }; let x = \"s\"; g() + 1;");
+    }
+
+    #[test]
+    fn local_redeclaration_keeps_captured_binding() {
+        let result = run("const h = () => {
+    let x = 1;
+    const g = () => { return x; };
+    let x = \"s\";
+    return g() + 1;
+};
+h();");
+
+        assert_eq!(result.val, InternalVal::Int(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Error in constant expression: Integer overflow.")]
+    fn constant_overflow() {
+        compile("const big = 2147483647; let f = () => { return big + 1; };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Error in constant expression: Negative shift amount -1.")]
+    fn constant_negative_shift() {
+        compile("1 << (0 - 1);");
+    }
+
+    #[test]
+    fn main_ky() {
+        run(&fs::read_to_string("./main.ky").unwrap());
+    }
+
+    fn frame(function: &str, line: u32, col: u32) -> TraceFrame {
+        TraceFrame {
+            function: function.to_string(),
+            loc: Some(Loc { line, col }),
+        }
+    }
+
+    #[test]
+    fn runtime_error_trace() {
+        let err = try_run("let f = (a: Int, b: Int) => {
+  let q = a / b;
+  return q;
+};
+let g = (x: Int) => { return f(x, 0); };
+g(1);").unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::DivisionByZero);
+        // g called f in tail position, so f took the place of its frame
+        assert_eq!(err.trace, vec![frame("f", 2, 3), frame("<main>", 6, 1)]);
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: Division by zero.\n    at f (2:3)\n    at <main> (6:1)"
+        );
+    }
+
+    #[test]
+    fn runtime_error_in_method() {
+        let err = try_run("impl Int { at = (self: Int, list: List(Int)) => { return list[self]; } };
+let get = (i: Int) => { return i.at([1, 2]); };
+get(5);").unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::IndexOutOfBounds { index: 5, len: 2 });
+        // get called at in tail position, so at took the place of its frame
+        assert_eq!(err.trace, vec![frame("at", 1, 51), frame("<main>", 3, 1)]);
+    }
+
+    #[test]
+    fn runtime_errors_stop_the_vm() {
+        let err = try_run("let x = 1;\nlet y = x << (x - 2);").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::NegativeShift(-1));
+        assert_eq!(err.trace, vec![frame("<main>", 2, 1)]);
+
+        let err = try_run("let x = 2147483647;\nx + 1;").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::IntegerOverflow);
+    }
+
+    // errors the type checker rules out can only come from hand-written bytecode
+    #[test]
+    fn malformed_bytecode_errors() {
+        let function = |instructions: Vec<Instruction>| {
+            Rc::new(Function {
+                name: "test".to_string(),
+                params: Vec::new(),
+                slots: 0,
+                locs: vec![Loc::default(); instructions.len()],
+                instructions,
+                constants: vec![KytheraVal::int(1)],
+                type_val: Rc::new(Type::Fn {
+                    params: Vec::new(),
+                    returns: Box::from(Type::Unit),
+                }),
+            })
+        };
+        let run = |instructions| Vm::new().run(function(instructions)).unwrap_err().kind;
+
+        assert_eq!(run(vec![Instruction::Constant(0), Instruction::Add]), ErrorKind::StackUnderflow);
+        assert_eq!(run(vec![Instruction::LoadGlobal("x".into())]), ErrorKind::UndefinedVariable("x".to_string()));
+        assert_eq!(run(vec![Instruction::LoadLocal(0)]), ErrorKind::InvalidSlot(0));
+        assert_eq!(run(vec![Instruction::LoadCaptured(1, 2)]), ErrorKind::InvalidSlot(2));
+        assert_eq!(run(vec![Instruction::Constant(1)]), ErrorKind::InvalidConstant(1));
+        assert_eq!(run(vec![Instruction::Constant(0)]), ErrorKind::MissingReturn);
+        assert_eq!(
+            run(vec![Instruction::Constant(0), Instruction::Invoke(0)]),
+            ErrorKind::TypeMismatch {
+                expected: "function".to_string(),
+                got: Type::Int,
+            }
+        );
+        assert_eq!(
+            run(vec![
+                Instruction::LoadGlobal("List".into()),
+                Instruction::Invoke(0),
+            ]),
+            ErrorKind::ArityMismatch { expected: 1, got: 0 }
+        );
+    }
+
+    // a program of hand-written instructions, with each instruction on the line of its index + 1
+    fn program(constants: Vec<KytheraVal>, instructions: Vec<Instruction>) -> Rc<Function> {
+        let locs = (0..instructions.len())
+            .map(|i| Loc {
+                line: i as u32 + 1,
+                col: 1,
+            })
+            .collect();
+        let program = Function {
+            name: "test".to_string(),
+            params: Vec::new(),
+            slots: 0,
+            instructions,
+            constants,
+            locs,
+            type_val: Rc::new(Type::Fn {
+                params: Vec::new(),
+                returns: Box::from(Type::Int),
+            }),
+        };
+        assert_eq!(verify::verify(&program), Ok(()));
+
+        Rc::new(program)
+    }
+
+    #[test]
+    fn jumps_are_absolute() {
+        use Instruction::*;
+
+        let ints = || vec![KytheraVal::int(1), KytheraVal::int(2)];
+
+        // execution continues at the target itself, not the instruction after it
+        let skip = program(ints(), vec![Jump(2), Constant(0), Constant(1), Return]);
+        assert_eq!(Vm::new().run(skip).unwrap(), KytheraVal::int(2));
+
+        // targets count from the start of the function, whether they are before or after the jump
+        let back = program(
+            ints(),
+            vec![Jump(3), Constant(0), Return, Nop, Nop, Constant(1), Jump(1)],
+        );
+        assert_eq!(Vm::new().run(back).unwrap(), KytheraVal::int(1));
+
+        // a jump to the next instruction changes nothing
+        let next = program(ints(), vec![Constant(0), Jump(2), Return]);
+        assert_eq!(Vm::new().run(next).unwrap(), KytheraVal::int(1));
+    }
+
+    #[test]
+    fn conditional_programs() {
+        use Instruction::*;
+
+        // n < 5 ? 10 * n : n
+        let clamp = |n| {
+            let constants = vec![KytheraVal::int(n), KytheraVal::int(5), KytheraVal::int(10)];
+            let instructions = vec![
+                Constant(0),
+                Constant(1),
+                Less,
+                JumpIf(6),
+                Constant(0),
+                Return,
+                Constant(2),
+                Constant(0),
+                Mul,
+                Return,
+            ];
+            Vm::new().run(program(constants, instructions)).unwrap()
+        };
+
+        assert_eq!(clamp(3), KytheraVal::int(30));
+        assert_eq!(clamp(5), KytheraVal::int(5));
+
+        // JumpIf consumes its condition whichever way it goes
+        let both = program(
+            vec![KytheraVal::bool(false), KytheraVal::int(7)],
+            vec![Constant(1), Constant(0), JumpIf(3), Constant(0), Not, JumpIf(7), Return, Return],
+        );
+        assert_eq!(Vm::new().run(both).unwrap(), KytheraVal::int(7));
+    }
+
+    #[test]
+    fn loop_programs() {
+        use Instruction::*;
+
+        // let sum = 0; let n = 10; while n != 0 { sum = sum + n; n = n - 1; } sum
+        let sum = program(
+            vec![KytheraVal::int(0), KytheraVal::int(10), KytheraVal::int(1)],
+            vec![
+                Constant(0),
+                DeclareGlobal("sum".into()),
+                Constant(1),
+                DeclareGlobal("n".into()),
+                LoadGlobal("n".into()),
+                Constant(0),
+                Equal,
+                JumpIf(17),
+                LoadGlobal("sum".into()),
+                LoadGlobal("n".into()),
+                Add,
+                StoreGlobal("sum".into()),
+                LoadGlobal("n".into()),
+                Constant(2),
+                Sub,
+                StoreGlobal("n".into()),
+                Jump(4),
+                LoadGlobal("sum".into()),
+                Return,
+            ],
+        );
+        assert_eq!(Vm::new().run(sum).unwrap(), KytheraVal::int(55));
+
+        // returning from the middle of a loop, with a value left on the stack by each pass
+        let early = program(
+            vec![KytheraVal::int(0), KytheraVal::int(1), KytheraVal::int(3)],
+            vec![
+                Constant(0),
+                Constant(1),
+                Add,
+                Dup,
+                Constant(2),
+                Equal,
+                JumpIf(8),
+                Jump(1),
+                Return,
+            ],
+        );
+        assert_eq!(Vm::new().run(early).unwrap(), KytheraVal::int(3));
+
+        let mut vm = Vm::new();
+        vm.set_limits(Limits {
+            fuel: Some(100),
+            ..Limits::default()
+        });
+        let forever = program(Vec::new(), vec![Nop, Jump(0)]);
+        assert_eq!(vm.run(forever).unwrap_err().kind, ErrorKind::OutOfFuel(100));
+    }
+
+    #[test]
+    fn call_and_return_programs() {
+        use Instruction::*;
+
+        // a call returns to the instruction after the Invoke
+        let forty = program(vec![KytheraVal::int(40)], vec![Constant(0), Return]);
+        let caller = program(
+            vec![KytheraVal::int(2)],
+            vec![MakeClosure(Rc::clone(&forty)), Invoke(0), Constant(0), Add, Return],
+        );
+        assert_eq!(Vm::new().run(caller).unwrap(), KytheraVal::int(42));
+
+        // errors are reported at the instruction that failed, not where a jump would have gone
+        let failing = program(
+            vec![KytheraVal::int(1)],
+            vec![Jump(2), Return, Constant(0), Constant(0), Invoke(1), Return],
+        );
+        let e = Vm::new().run(failing).unwrap_err();
+        assert_eq!(e.trace[0].loc, Some(Loc { line: 5, col: 1 }));
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::vm::{Function, Instruction, InternalVal, KytheraVal};
+
+// a listing of a compiled function followed by the functions it makes, for debugging the compiler. each
+// instruction is shown with its offset, where in the source it came from, its opcode and its operands, with
+// the values of constants and where jumps go. instructions that are jumped to are marked with >. given the
+// source, each source line is printed above the instructions compiled from it.
+pub fn disassemble(function: &Function, source: Option<&str>) -> String {
+    let lines: Vec<&str> = source.map(|source| source.lines().collect()).unwrap_or_default();
+    let mut listing = String::new();
+    let mut pending = vec![function];
+
+    while let Some(function) = pending.pop() {
+        if !listing.is_empty() {
+            listing.push('\n');
+        }
+        list(&mut listing, function, &lines);
+
+        // list nested functions in the order they are made
+        let nested = function.instructions.iter().rev().filter_map(|inst| match inst {
+            Instruction::MakeClosure(f) => Some(f.as_ref()),
+            _ => None,
+        });
+        pending.extend(nested);
+    }
+
+    listing
+}
+
+fn list(listing: &mut String, function: &Function, lines: &[&str]) {
+    let _ = writeln!(
+        listing,
+        "fn {}: {} ({} slots, {} constants)",
+        function.name,
+        function.type_val,
+        function.slots,
+        function.constants.len()
+    );
+
+    let targets: HashSet<usize> = function.instructions.iter().filter_map(jump_target).collect();
+    let mut line = None;
+
+    for (offset, inst) in function.instructions.iter().enumerate() {
+        // instructions the compiler adds itself have no location
+        let loc = function.locs.get(offset).filter(|loc| loc.line > 0);
+
+        // show the source line when it first has code
+        if let Some(loc) = loc.filter(|loc| Some(loc.line) != line) {
+            line = Some(loc.line);
+            if let Some(text) = lines.get(loc.line as usize - 1) {
+                let _ = writeln!(listing, "{:>5} | {}", loc.line, text.trim_end());
+            }
+        }
+
+        let marker = if targets.contains(&offset) { '>' } else { ' ' };
+        let loc = loc.map_or_else(|| "-".to_string(), |loc| format!("{}:{}", loc.line, loc.col));
+        let (opcode, operands) = describe(inst, function);
+        let text = format!("{}{:04} {:>7}  {:<15}{}", marker, offset, loc, opcode, operands);
+        let _ = writeln!(listing, "{}", text.trim_end());
+    }
+}
+
+fn jump_target(inst: &Instruction) -> Option<usize> {
+    match inst {
+        Instruction::Jump(target) | Instruction::JumpIf(target) | Instruction::JumpIfNot(target) => Some(*target),
+        _ => None,
+    }
+}
+
+// the name of an instruction and its operands as they should be listed
+fn describe(inst: &Instruction, function: &Function) -> (&'static str, String) {
+    let names = |names: &[crate::symbol::Symbol]| {
+        let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        names.join(", ")
+    };
+
+    match inst {
+        Instruction::Nop => ("Nop", String::new()),
+        Instruction::Constant(index) => {
+            let val = match function.constants.get(*index as usize) {
+                Some(val) => constant(val),
+                None => "invalid".to_string(),
+            };
+            ("Constant", format!("{:<8}; {}", index, val))
+        }
+        Instruction::Add => ("Add", String::new()),
+        Instruction::Sub => ("Sub", String::new()),
+        Instruction::Mul => ("Mul", String::new()),
+        Instruction::Div => ("Div", String::new()),
+        Instruction::Mod => ("Mod", String::new()),
+        Instruction::Equal => ("Equal", String::new()),
+        Instruction::NotEqual => ("NotEqual", String::new()),
+        Instruction::Less => ("Less", String::new()),
+        Instruction::LessEqual => ("LessEqual", String::new()),
+        Instruction::Greater => ("Greater", String::new()),
+        Instruction::GreaterEqual => ("GreaterEqual", String::new()),
+        Instruction::Not => ("Not", String::new()),
+        Instruction::Or => ("Or", String::new()),
+        Instruction::And => ("And", String::new()),
+        Instruction::Xor => ("Xor", String::new()),
+        Instruction::ShiftLeft => ("ShiftLeft", String::new()),
+        Instruction::ShiftRight => ("ShiftRight", String::new()),
+        Instruction::Invoke(n) => ("Invoke", n.to_string()),
+        Instruction::TailInvoke(n) => ("TailInvoke", n.to_string()),
+        Instruction::Field(name) => ("Field", name.to_string()),
+        Instruction::MakeStruct(fields) => ("MakeStruct", names(fields)),
+        Instruction::Spread => ("Spread", String::new()),
+        Instruction::MakeList(n) => ("MakeList", n.to_string()),
+        Instruction::Index => ("Index", String::new()),
+        Instruction::MakeStructType(fields) => ("MakeStructType", names(fields)),
+        Instruction::MakeFnType(n) => ("MakeFnType", n.to_string()),
+        Instruction::MakeListType => ("MakeListType", String::new()),
+        Instruction::MakeUnionType(tags) => ("MakeUnionType", names(tags)),
+        Instruction::MakeVariant(tag) => ("MakeVariant", tag.to_string()),
+        Instruction::IsVariant(tag) => ("IsVariant", tag.to_string()),
+        Instruction::Payload => ("Payload", String::new()),
+        Instruction::Impl(methods) => ("Impl", names(methods)),
+        Instruction::MakeClosure(f) => ("MakeClosure", format!("fn {}", f.name)),
+        Instruction::Pop => ("Pop", String::new()),
+        Instruction::Dup => ("Dup", String::new()),
+        Instruction::Jump(target) => ("Jump", format!("-> {:04}", target)),
+        Instruction::JumpIf(target) => ("JumpIf", format!("-> {:04}", target)),
+        Instruction::JumpIfNot(target) => ("JumpIfNot", format!("-> {:04}", target)),
+        Instruction::Return => ("Return", String::new()),
+        Instruction::DeclareGlobal(name) => ("DeclareGlobal", name.to_string()),
+        Instruction::StoreGlobal(name) => ("StoreGlobal", name.to_string()),
+        Instruction::LoadGlobal(name) => ("LoadGlobal", name.to_string()),
+        Instruction::StoreLocal(slot) => ("StoreLocal", local(function, *slot)),
+        Instruction::LoadLocal(slot) => ("LoadLocal", local(function, *slot)),
+        Instruction::StoreCaptured(depth, slot) => ("StoreCaptured", format!("{} {}", depth, slot)),
+        Instruction::LoadCaptured(depth, slot) => ("LoadCaptured", format!("{} {}", depth, slot)),
+        Instruction::Typeof => ("Typeof", String::new()),
+    }
+}
+
+// a slot, named if it holds a parameter. the slots of methods start with their receiver.
+fn local(function: &Function, slot: u16) -> String {
+    match function.params.get(slot as usize) {
+        Some(param) => format!("{:<8}; {}", slot, param),
+        None => slot.to_string(),
+    }
+}
+
+// strings are quoted so they can be told apart from other values
+fn constant(val: &KytheraVal) -> String {
+    match &val.val {
+        InternalVal::String(s) => format!("{:?}", s),
+        _ => format!("{}: {}", val, val.type_val),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::compile;
+
+    fn listing(source: &str) -> String {
+        disassemble(&compile(source), Some(source))
+    }
+
+    #[test]
+    fn lists_instructions() {
+        let source = "let greeting = \"hi\";
+let twice = (n: Int) => {
+    let m = n * 2;
+    return m;
+};
+twice(3) == 6 || false;";
+
+        assert_eq!(
+            listing(source),
+            "\
+fn <main>: () => Bool (0 slots, 4 constants)
+    1 | let greeting = \"hi\";
+ 0000     1:1  Constant       0       ; \"hi\"
+ 0001     1:1  DeclareGlobal  greeting
+    2 | let twice = (n: Int) => {
+ 0002     2:1  MakeClosure    fn twice
+ 0003     2:1  DeclareGlobal  twice
+    6 | twice(3) == 6 || false;
+ 0004     6:1  Constant       1       ; 3: Int
+ 0005     6:1  LoadGlobal     twice
+ 0006     6:1  Invoke         1
+ 0007     6:1  Constant       2       ; 6: Int
+ 0008     6:1  Equal
+ 0009     6:1  Dup
+ 0010     6:1  JumpIf         -> 0013
+ 0011     6:1  Pop
+ 0012     6:1  Constant       3       ; false: Bool
+>0013       -  Return
+
+fn twice: (Int,) => Int (2 slots, 2 constants)
+    3 |     let m = n * 2;
+ 0000     3:5  LoadLocal      0       ; n
+ 0001     3:5  Constant       0       ; 2: Int
+ 0002     3:5  Mul
+ 0003     3:5  StoreLocal     1
+    4 |     return m;
+ 0004     4:5  LoadLocal      1
+ 0005     4:5  Return
+    2 | let twice = (n: Int) => {
+ 0006     2:1  Pop
+ 0007     2:1  Constant       1       ; unit: Unit
+ 0008     2:1  Return
+"
+        );
+    }
+
+    #[test]
+    fn marks_jump_targets() {
+        let function = Function {
+            name: "test".to_string(),
+            params: vec!["b".to_string()],
+            slots: 1,
+            instructions: vec![
+                Instruction::LoadLocal(0),
+                Instruction::JumpIf(3),
+                Instruction::Jump(0),
+                Instruction::Constant(0),
+                Instruction::Return,
+            ],
+            constants: vec![KytheraVal::string("done".to_string())],
+            locs: Vec::new(),
+            type_val: std::rc::Rc::new(crate::types::Type::String),
+        };
+
+        assert_eq!(
+            disassemble(&function, None),
+            "\
+fn test: String (1 slots, 1 constants)
+>0000       -  LoadLocal      0       ; b
+ 0001       -  JumpIf         -> 0003
+ 0002       -  Jump           -> 0000
+>0003       -  Constant       0       ; \"done\"
+ 0004       -  Return
+"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::fmt;
+use std::time::Duration;
+
+use crate::input_stream::Loc;
+use crate::types::Type;
+
+// what went wrong when running a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    TypeMismatch { expected: String, got: Type },
+    InvalidOperands { op: String, lhs: Type, rhs: Type },
+    UndefinedVariable(String),
+    MissingField { target: Type, field: String },
+    DivisionByZero,
+    IntegerOverflow,
+    NegativeShift(i32),
+    IndexOutOfBounds { index: i32, len: usize },
+    // an index into a string, counted in characters
+    StringIndexOutOfBounds { index: i32, len: usize },
+    StackUnderflow,
+    ArityMismatch { expected: usize, got: usize },
+    // the pc ran past the last instruction of a function without returning
+    MissingReturn,
+    // malformed bytecode: a variable slot or constant that does not exist
+    InvalidSlot(u16),
+    InvalidConstant(u16),
+    // malformed bytecode: a list literal with no elements, whose element type is unknown
+    EmptyList,
+    // raised by a native function
+    Native(String),
+    // the limits a program was run under, see vm::Limits
+    OutOfFuel(u64),
+    CallDepthExceeded(usize),
+    StackOverflow(usize),
+    HeapExceeded(usize),
+    DeadlineExceeded(Duration),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::TypeMismatch { expected, got } => write!(f, "Expected {} but got {}.", expected, got),
+            ErrorKind::InvalidOperands { op, lhs, rhs } => {
+                write!(f, "Invalid operands for {}: {} and {}.", op, lhs, rhs)
+            }
+            ErrorKind::UndefinedVariable(name) => write!(f, "Undefined variable {}.", name),
+            ErrorKind::MissingField { target, field } => write!(f, "{} has no field or method {}.", target, field),
+            ErrorKind::DivisionByZero => write!(f, "Division by zero."),
+            ErrorKind::IntegerOverflow => write!(f, "Integer overflow."),
+            ErrorKind::NegativeShift(amount) => write!(f, "Negative shift amount {}.", amount),
+            ErrorKind::IndexOutOfBounds { index, len } => {
+                write!(f, "Index {} out of bounds for list of length {}.", index, len)
+            }
+            ErrorKind::StringIndexOutOfBounds { index, len } => {
+                write!(f, "Index {} out of bounds for string of length {}.", index, len)
+            }
+            ErrorKind::StackUnderflow => write!(f, "Stack underflow."),
+            ErrorKind::ArityMismatch { expected, got } => {
+                write!(f, "Expected {} arguments but got {}.", expected, got)
+            }
+            ErrorKind::MissingReturn => write!(f, "Execution ended without returning."),
+            ErrorKind::InvalidSlot(slot) => write!(f, "No variable in slot {}.", slot),
+            ErrorKind::InvalidConstant(index) => write!(f, "No constant at index {}.", index),
+            ErrorKind::EmptyList => write!(f, "Cannot make a list with no elements."),
+            ErrorKind::Native(message) => write!(f, "{}", message),
+            ErrorKind::OutOfFuel(fuel) => write!(f, "Ran out of fuel after {} instructions.", fuel),
+            ErrorKind::CallDepthExceeded(max) => write!(f, "Call depth exceeded the limit of {}.", max),
+            ErrorKind::StackOverflow(max) => write!(f, "Stack exceeded the limit of {} values.", max),
+            ErrorKind::HeapExceeded(max) => write!(f, "Heap exceeded the limit of {} bytes.", max),
+            ErrorKind::DeadlineExceeded(timeout) => write!(f, "Ran for longer than the limit of {:?}.", timeout),
+        }
+    }
+}
+
+// why a program could not be compiled: it could not be parsed, or did not type check
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub message: String,
+}
+
+impl CompileError {
+    pub fn new(message: impl Into<String>) -> CompileError {
+        CompileError {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// return a CompileError with a message formatted like format!
+macro_rules! fail {
+    ($($arg:tt)*) => {
+        return Err($crate::error::CompileError::new(format!($($arg)*)))
+    };
+}
+
+pub(crate) use fail;
+
+// one function call that was in progress when an error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub function: String,
+    // where in the source the function was when the error happened, if known
+    pub loc: Option<Loc>,
+}
+
+// an error that stopped a program, with the calls in progress at the time, innermost first
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub trace: Vec<TraceFrame>,
+}
+
+// an error raised outside of any frame, which the VM adds the trace to
+impl From<ErrorKind> for RuntimeError {
+    fn from(kind: ErrorKind) -> RuntimeError {
+        RuntimeError { kind, trace: Vec::new() }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Runtime error: {}", self.kind)?;
+
+        for frame in &self.trace {
+            match frame.loc {
+                Some(loc) => write!(f, "\n    at {} ({})", frame.function, loc)?,
+                None => write!(f, "\n    at {}", frame.function)?,
+            }
+        }
+
+        Ok(())
+    }
+}
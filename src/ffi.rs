@@ -0,0 +1,293 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::error::{ErrorKind, RuntimeError};
+use crate::types::Type;
+use crate::vm::{InternalVal, KytheraVal};
+
+// a Rust type with a Kythera counterpart
+pub trait KytheraType {
+    fn kythera_type() -> Type;
+}
+
+// a Rust value that can be passed to Kythera code
+pub trait IntoKythera: KytheraType {
+    fn into_kythera(self) -> KytheraVal;
+}
+
+// a Rust value that can be taken from Kythera code
+pub trait FromKythera: KytheraType + Sized {
+    fn from_kythera(val: &KytheraVal) -> Result<Self, ErrorKind>;
+}
+
+macro_rules! primitive {
+    ($rust:ty, $kythera:ident, $make:expr, $take:expr) => {
+        impl KytheraType for $rust {
+            fn kythera_type() -> Type {
+                Type::$kythera
+            }
+        }
+
+        impl IntoKythera for $rust {
+            fn into_kythera(self) -> KytheraVal {
+                $make(self)
+            }
+        }
+
+        impl FromKythera for $rust {
+            fn from_kythera(val: &KytheraVal) -> Result<$rust, ErrorKind> {
+                $take(val)
+            }
+        }
+    };
+}
+
+primitive!((), Unit, |_| KytheraVal::unit(), |val: &KytheraVal| match val.val {
+    InternalVal::Unit => Ok(()),
+    _ => Err(val.mismatch("Unit")),
+});
+primitive!(i32, Int, KytheraVal::int, KytheraVal::as_int);
+primitive!(f64, Double, KytheraVal::double, KytheraVal::as_double);
+primitive!(bool, Bool, KytheraVal::bool, KytheraVal::as_bool);
+primitive!(String, String, KytheraVal::string, |val: &KytheraVal| val.as_str().map(str::to_string));
+
+impl KytheraType for &str {
+    fn kythera_type() -> Type {
+        Type::String
+    }
+}
+
+impl IntoKythera for &str {
+    fn into_kythera(self) -> KytheraVal {
+        KytheraVal::string(self.to_string())
+    }
+}
+
+// Vec is a List of the element type
+impl<T: KytheraType> KytheraType for Vec<T> {
+    fn kythera_type() -> Type {
+        Type::List(Box::from(T::kythera_type()))
+    }
+}
+
+impl<T: IntoKythera> IntoKythera for Vec<T> {
+    fn into_kythera(self) -> KytheraVal {
+        KytheraVal::list(T::kythera_type(), self.into_iter().map(T::into_kythera).collect())
+    }
+}
+
+impl<T: FromKythera> FromKythera for Vec<T> {
+    fn from_kythera(val: &KytheraVal) -> Result<Vec<T>, ErrorKind> {
+        val.as_list()?.iter().map(T::from_kythera).collect()
+    }
+}
+
+// Option is the union { some: T | none: Unit }
+impl<T: KytheraType> KytheraType for Option<T> {
+    fn kythera_type() -> Type {
+        Type::Union(
+            vec![("some".to_string(), T::kythera_type()), ("none".to_string(), Type::Unit)]
+                .into_iter()
+                .collect(),
+        )
+    }
+}
+
+impl<T: IntoKythera> IntoKythera for Option<T> {
+    fn into_kythera(self) -> KytheraVal {
+        match self {
+            Some(val) => KytheraVal::variant(Self::kythera_type(), "some".to_string(), val.into_kythera()),
+            None => KytheraVal::variant(Self::kythera_type(), "none".to_string(), KytheraVal::unit()),
+        }
+    }
+}
+
+impl<T: FromKythera> FromKythera for Option<T> {
+    fn from_kythera(val: &KytheraVal) -> Result<Option<T>, ErrorKind> {
+        match &val.val {
+            InternalVal::Variant(tag, value) if tag == "some" => T::from_kythera(value).map(Some),
+            InternalVal::Variant(tag, _) if tag == "none" => Ok(None),
+            _ => Err(val.mismatch(&Self::kythera_type().to_string())),
+        }
+    }
+}
+
+// what a host function returns. an Err is raised as a runtime error in the Kythera code that called it.
+pub trait HostResult {
+    fn ok_type() -> Type;
+    fn into_result(self) -> Result<KytheraVal, ErrorKind>;
+}
+
+impl<T: IntoKythera> HostResult for T {
+    fn ok_type() -> Type {
+        T::kythera_type()
+    }
+
+    fn into_result(self) -> Result<KytheraVal, ErrorKind> {
+        Ok(self.into_kythera())
+    }
+}
+
+impl<T: IntoKythera, E: fmt::Display> HostResult for Result<T, E> {
+    fn ok_type() -> Type {
+        T::kythera_type()
+    }
+
+    fn into_result(self) -> Result<KytheraVal, ErrorKind> {
+        self.map(T::into_kythera).map_err(|e| ErrorKind::Native(e.to_string()))
+    }
+}
+
+// a Rust function that can be called from Kythera code. Args is the tuple of its argument types, which only
+// exists to tell implementations for different numbers of arguments apart.
+pub trait HostFn<Args>: 'static {
+    fn kythera_type() -> Type;
+    fn call(&self, args: &[KytheraVal]) -> Result<KytheraVal, ErrorKind>;
+
+    // wrap the function as a Kythera value of its Kythera type
+    fn into_native(self, name: &str) -> KytheraVal
+    where
+        Self: Sized,
+    {
+        KytheraVal::native(
+            name,
+            Self::kythera_type(),
+            Rc::new(move |_, args| self.call(&args).map_err(RuntimeError::from)),
+        )
+    }
+}
+
+macro_rules! host_fn {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> HostFn<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: HostResult,
+            $($arg: FromKythera),*
+        {
+            fn kythera_type() -> Type {
+                Type::Fn {
+                    params: vec![$(<$arg as KytheraType>::kythera_type()),*],
+                    returns: Box::from(R::ok_type()),
+                }
+            }
+
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn call(&self, args: &[KytheraVal]) -> Result<KytheraVal, ErrorKind> {
+                let mut args = args.iter();
+                $(let $arg = $arg::from_kythera(args.next().expect("Arity was checked."))?;)*
+                self($($arg),*).into_result()
+            }
+        }
+    };
+}
+
+host_fn!();
+host_fn!(A);
+host_fn!(A, B);
+host_fn!(A, B, C);
+host_fn!(A, B, C, D);
+host_fn!(A, B, C, D, E);
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::error::TraceFrame;
+    use crate::input_stream::Loc;
+    use crate::testing::try_run_on;
+    use crate::vm::Vm;
+
+    fn host() -> Vm {
+        let mut vm = Vm::new();
+        vm.define_fn("add", |a: i32, b: i32| a + b);
+        vm.define_fn("sum", |xs: Vec<i32>| xs.iter().sum::<i32>());
+        vm.define_fn("find", |xs: Vec<String>, x: String| {
+            xs.iter().position(|y| *y == x).map(|i| i as i32)
+        });
+        vm.define_fn("shout", |s: String| {
+            if s.is_empty() {
+                Err("Nothing to shout.")
+            } else {
+                Ok(s.to_uppercase())
+            }
+        });
+        vm.define_value("limit", 10);
+        vm.define_value("names", vec!["ann", "bo"]);
+        vm
+    }
+
+    #[test]
+    fn host_functions_and_values() {
+        let result = try_run_on(&mut host(), "{
+    a = add(limit, 2),
+    b = sum([1, 2, 3]),
+    c = find(names, \"bo\"),
+    d = when find(names, \"cy\") { some(i) => i, none => 0 - 1 },
+    e = shout(\"hi\"),
+    f = typeof add == (Int, Int) => Int,
+    g = typeof find,
+};");
+
+        assert_eq!(
+            result.unwrap().to_string(),
+            "{ a = 12, b = 6, c = some(1), d = -1, e = HI, f = true, g = (List(String), String) => { some: Int | none: Unit } }"
+        );
+    }
+
+    #[test]
+    fn host_functions_capture_state() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = Vm::new();
+        let host_log = Rc::clone(&log);
+        vm.define_fn("log", move |line: String| host_log.borrow_mut().push(line));
+
+        try_run_on(&mut vm, "log(\"one\"); map(Int, Unit, [2, 3], (n: Int) => { return log(toString(Int, n)); });").unwrap();
+        assert_eq!(*log.borrow(), vec!["one", "2", "3"]);
+        assert_eq!(vm.get_global("log").map(|log| log.type_val.to_string()), Some("(String,) => Unit".to_string()));
+    }
+
+    #[test]
+    fn host_errors_become_runtime_errors() {
+        let err = try_run_on(&mut host(), "let quiet = \"\";\nshout(quiet);").unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::Native("Nothing to shout.".to_string()));
+        assert_eq!(
+            err.trace,
+            vec![
+                TraceFrame {
+                    function: "shout".to_string(),
+                    loc: None,
+                },
+                TraceFrame {
+                    function: "<main>".to_string(),
+                    loc: Some(Loc { line: 2, col: 1 }),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected Int but got String for argument.")]
+    fn host_functions_are_type_checked() {
+        let _ = try_run_on(&mut host(), "add(1, \"2\");");
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(i32::from_kythera(&KytheraVal::int(3)), Ok(3));
+        assert_eq!(
+            i32::from_kythera(&KytheraVal::bool(true)),
+            Err(ErrorKind::TypeMismatch {
+                expected: "Int".to_string(),
+                got: Type::Bool,
+            })
+        );
+        assert_eq!(Vec::<f64>::from_kythera(&vec![1.5, 2.0].into_kythera()), Ok(vec![1.5, 2.0]));
+        assert_eq!(Option::<bool>::from_kythera(&Some(true).into_kythera()), Ok(Some(true)));
+        assert_eq!(Option::<bool>::from_kythera(&None::<bool>.into_kythera()), Ok(None));
+        assert_eq!(Vec::<String>::kythera_type(), Type::List(Box::from(Type::String)));
+        assert_eq!(<()>::from_kythera(&().into_kythera()), Ok(()));
+    }
+}
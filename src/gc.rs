@@ -0,0 +1,266 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use crate::symbol::Symbol;
+use crate::types::Fields;
+use crate::vm::{Closure, InternalVal, KytheraVal, Native, Scope};
+
+// values are reference counted, which frees them as soon as nothing holds them, except when they hold each
+// other in a cycle. scopes are the only things that change after they are made, so every cycle passes
+// through one: a closure holds the scope it was made in, which can hold the closure (or a struct or list
+// holding it). the heap tracks every scope so that those cycles can be found and broken.
+//
+// a collection is a mark and sweep over everything reachable from the tracked scopes. its roots are the
+// objects held from outside that graph (by frame stacks and scopes, globals, methods, and natives or the
+// host), found by comparing how many references each object has with how many come from inside the graph.
+// sweeping empties the scopes that were not marked, which lets reference counting free their cycles.
+pub struct Heap {
+    scopes: Vec<Weak<RefCell<Scope>>>,
+    // how many scopes can be tracked before the next collection
+    threshold: usize,
+    stats: GcStats,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    // collections run so far, including automatic ones
+    pub collections: u64,
+    // scopes alive after the last collection
+    pub live: usize,
+    // scopes freed by the last collection
+    pub freed: usize,
+    // scopes freed by every collection so far
+    pub total_freed: u64,
+}
+
+// the least number of scopes to track before collecting, so small programs never collect
+const MIN_THRESHOLD: usize = 1024;
+
+// hashes the addresses objects are identified by. they are already unique, so mixing their bits is enough,
+// and much faster than the default hasher.
+#[derive(Default)]
+struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8 | *byte as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_usize(&mut self, address: usize) {
+        self.0 = (address as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+type Addresses<V> = HashMap<*const (), V, BuildHasherDefault<AddressHasher>>;
+
+// something that can be part of a cycle
+#[derive(Clone)]
+enum Object {
+    Scope(Rc<RefCell<Scope>>),
+    Closure(Rc<Closure>),
+    Native(Rc<Native>),
+    Struct(Rc<Fields<KytheraVal>>),
+    List(Rc<Vec<KytheraVal>>),
+    Variant(Rc<KytheraVal>),
+}
+
+impl Object {
+    fn of(val: &KytheraVal) -> Option<Object> {
+        match &val.val {
+            InternalVal::Fn(closure) => Some(Object::Closure(Rc::clone(closure))),
+            InternalVal::Native(native) => Some(Object::Native(Rc::clone(native))),
+            InternalVal::Struct(fields) => Some(Object::Struct(Rc::clone(fields))),
+            InternalVal::List(elements) => Some(Object::List(Rc::clone(elements))),
+            InternalVal::Variant(_, value) => Some(Object::Variant(Rc::clone(value))),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> *const () {
+        match self {
+            Object::Scope(scope) => Rc::as_ptr(scope) as *const (),
+            Object::Closure(closure) => Rc::as_ptr(closure) as *const (),
+            Object::Native(native) => Rc::as_ptr(native) as *const (),
+            Object::Struct(fields) => Rc::as_ptr(fields) as *const (),
+            Object::List(elements) => Rc::as_ptr(elements) as *const (),
+            Object::Variant(value) => Rc::as_ptr(value) as *const (),
+        }
+    }
+
+    fn references(&self) -> usize {
+        match self {
+            Object::Scope(scope) => Rc::strong_count(scope),
+            Object::Closure(closure) => Rc::strong_count(closure),
+            Object::Native(native) => Rc::strong_count(native),
+            Object::Struct(fields) => Rc::strong_count(fields),
+            Object::List(elements) => Rc::strong_count(elements),
+            Object::Variant(value) => Rc::strong_count(value),
+        }
+    }
+
+    fn children(&self) -> Vec<Object> {
+        match self {
+            Object::Scope(scope) => {
+                let scope = scope.borrow();
+                let parent = scope.parent.iter().map(|parent| Object::Scope(Rc::clone(parent)));
+                let vars = scope.vars.values().chain(&scope.slots);
+                parent.chain(vars.filter_map(Object::of)).collect()
+            }
+            Object::Closure(closure) => {
+                let scope = Object::Scope(Rc::clone(&closure.scope));
+                closure.receiver.iter().filter_map(Object::of).chain([scope]).collect()
+            }
+            Object::Native(native) => native.receiver.iter().filter_map(Object::of).collect(),
+            Object::Struct(fields) => fields.iter().filter_map(|(_, field)| Object::of(field)).collect(),
+            Object::List(elements) => elements.iter().filter_map(Object::of).collect(),
+            Object::Variant(value) => Object::of(value).into_iter().collect(),
+        }
+    }
+
+    // roughly how many bytes the object takes, with the strings it holds but not the objects
+    fn size(&self) -> usize {
+        fn strings<'a>(vals: impl Iterator<Item = &'a KytheraVal>) -> usize {
+            vals.map(string_size).sum()
+        }
+
+        match self {
+            Object::Scope(scope) => {
+                let scope = scope.borrow();
+                mem::size_of::<Scope>()
+                    + scope.vars.len() * mem::size_of::<(Symbol, KytheraVal)>()
+                    + scope.slots.len() * mem::size_of::<KytheraVal>()
+                    + strings(scope.vars.values().chain(&scope.slots))
+            }
+            Object::Closure(closure) => mem::size_of::<Closure>() + strings(closure.receiver.iter()),
+            Object::Native(native) => mem::size_of::<Native>() + strings(native.receiver.iter()),
+            Object::Struct(fields) => {
+                fields.len() * mem::size_of::<(Symbol, KytheraVal)>() + strings(fields.iter().map(|(_, field)| field))
+            }
+            Object::List(elements) => elements.len() * mem::size_of::<KytheraVal>() + strings(elements.iter()),
+            Object::Variant(value) => mem::size_of::<KytheraVal>() + string_size(value),
+        }
+    }
+}
+
+fn string_size(val: &KytheraVal) -> usize {
+    match &val.val {
+        InternalVal::String(s) => s.len(),
+        _ => 0,
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap {
+            scopes: Vec::new(),
+            threshold: MIN_THRESHOLD,
+            stats: GcStats::default(),
+        }
+    }
+
+    // start tracking a scope. returns whether enough have been made since the last collection to collect.
+    pub fn track(&mut self, scope: &Rc<RefCell<Scope>>) -> bool {
+        self.scopes.push(Rc::downgrade(scope));
+        self.scopes.len() >= self.threshold
+    }
+
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    // roughly how many bytes the tracked scopes and the given values hold, counting each object once. this
+    // is only all the memory values take after a collection, when every scope left is still in use.
+    pub fn size<'a>(&self, roots: impl IntoIterator<Item = &'a KytheraVal>) -> usize {
+        let mut size = 0;
+        let mut pending: Vec<Object> = self.scopes.iter().filter_map(Weak::upgrade).map(Object::Scope).collect();
+        for root in roots {
+            match Object::of(root) {
+                Some(object) => pending.push(object),
+                None => size += string_size(root),
+            }
+        }
+
+        let mut seen: HashSet<*const (), BuildHasherDefault<AddressHasher>> = HashSet::default();
+        while let Some(object) = pending.pop() {
+            if seen.insert(object.id()) {
+                size += object.size();
+                pending.extend(object.children());
+            }
+        }
+        size
+    }
+
+    pub fn collect(&mut self) -> GcStats {
+        // find everything reachable from the tracked scopes, counting the references between them. the
+        // table holds a reference to each object itself.
+        let mut objects: Addresses<(Object, usize)> = Addresses::default();
+        let mut pending: Vec<Object> = Vec::new();
+        for scope in self.scopes.iter().filter_map(Weak::upgrade) {
+            let scope = Object::Scope(scope);
+            pending.push(scope.clone());
+            objects.insert(scope.id(), (scope, 0));
+        }
+        while let Some(object) = pending.pop() {
+            for child in object.children() {
+                let (_, internal) = objects.entry(child.id()).or_insert_with(|| {
+                    pending.push(child.clone());
+                    (child, 0)
+                });
+                *internal += 1;
+            }
+        }
+
+        // mark from the objects that have more references than the graph accounts for
+        let mut pending: Vec<*const ()> = objects
+            .iter()
+            .filter(|(_, (object, internal))| object.references() - 1 > *internal)
+            .map(|(id, _)| *id)
+            .collect();
+        let mut marked: HashSet<*const (), BuildHasherDefault<AddressHasher>> = HashSet::default();
+        while let Some(id) = pending.pop() {
+            if marked.insert(id) {
+                pending.extend(objects[&id].0.children().iter().map(Object::id));
+            }
+        }
+
+        // sweep. the contents of unmarked scopes are only dropped once every scope has been emptied, so none
+        // is freed while another is borrowed.
+        let mut garbage = Vec::new();
+        for (id, (object, _)) in &objects {
+            if let Object::Scope(scope) = object {
+                if !marked.contains(id) {
+                    let mut scope = scope.borrow_mut();
+                    garbage.push((mem::take(&mut scope.vars), mem::take(&mut scope.slots), scope.parent.take()));
+                }
+            }
+        }
+        let freed = garbage.len();
+        drop(garbage);
+        drop(objects);
+
+        self.scopes.retain(|scope| scope.strong_count() > 0);
+        self.threshold = MIN_THRESHOLD.max(self.scopes.len() * 2);
+        self.stats = GcStats {
+            collections: self.stats.collections + 1,
+            live: self.scopes.len(),
+            freed,
+            total_freed: self.stats.total_freed + freed as u64,
+        };
+        self.stats
+    }
+}
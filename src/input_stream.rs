@@ -1,7 +1,23 @@
+use std::fmt;
 use std::fs;
 use std::io::Error;
 use unicode_segmentation::UnicodeSegmentation;
 
+use crate::error::{fail, CompileError};
+
+// a position in the source, with lines and columns counted from 1
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Loc {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
 pub struct InputStream {
     body: Vec<String>,
     pos: usize,
@@ -21,7 +37,6 @@ impl InputStream {
         })
     }
 
-    // used for testing, takes a string
     pub fn new_from_string(contents: &str) -> InputStream {
         let body: Vec<String> = contents.to_string().graphemes(true).map(|s| s.to_string()).collect();
         InputStream {
@@ -47,15 +62,13 @@ impl InputStream {
         next
     }
 
-    // like consume, but panics if expected doesn't match
-    pub fn consume_expect(&mut self, expected: &str) -> String {
-        let next = self.consume();
-
-        if next != expected.to_string() {
-            panic!("Expected {} but got {} at {}", expected, next, self.loc());
+    // like consume, but fails if expected doesn't match
+    pub fn consume_expect(&mut self, expected: &str) -> Result<String, CompileError> {
+        match self.peek() {
+            Some(next) if next == expected => Ok(self.consume()),
+            Some(next) => fail!("Expected {} but got {} at {}", expected, next, self.loc()),
+            None => fail!("Expected {} but got EOF at {}", expected, self.loc()),
         }
-
-        next
     }
 
     pub fn peek(&self) -> Option<String> {
@@ -78,23 +91,24 @@ impl InputStream {
         self.pos >= self.body.len()
     }
 
-    pub fn read_while(&mut self, condition: impl Fn(&String) -> bool) -> String {
+    pub fn read_while(&mut self, condition: impl Fn(&String) -> bool) -> Result<String, CompileError> {
         let mut output: Vec<String> = Vec::new();
 
-        while !self.eof()
-            && condition(
-                &self
-                    .peek()
-                    .expect(format!("Unexpected EOF at {}", self.loc()).as_str()),
-            )
-        {
-            output.push(self.consume());
+        while !self.eof() {
+            match self.peek() {
+                Some(next) if condition(&next) => output.push(self.consume()),
+                Some(_) => break,
+                None => fail!("Unexpected EOF at {}", self.loc()),
+            }
         }
 
-        output.join("")
+        Ok(output.join(""))
     }
 
-    pub fn loc(&self) -> String {
-        format!("{}:{}", self.line, self.col)
+    pub fn loc(&self) -> Loc {
+        Loc {
+            line: self.line,
+            col: self.col,
+        }
     }
 }
@@ -0,0 +1,575 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::bytecode::{self, BytecodeError};
+use crate::compiler::Compiler;
+use crate::disasm;
+use crate::error::{CompileError, ErrorKind, RuntimeError};
+use crate::ffi::{HostFn, IntoKythera};
+use crate::gc::GcStats;
+use crate::input_stream::InputStream;
+use crate::optimize;
+use crate::parser;
+use crate::types::Type;
+use crate::vm::{Function, KytheraVal, Limits, Vm};
+
+// why evaluating Kythera code failed
+#[derive(Debug)]
+pub enum Error {
+    // the source could not be read
+    Io(io::Error),
+    // the source could not be parsed or did not type check
+    Compile(CompileError),
+    // a .kyc file could not be loaded
+    Bytecode(BytecodeError),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Compile(e) => write!(f, "{}", e),
+            Error::Bytecode(e) => write!(f, "{}", e),
+            Error::Runtime(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<CompileError> for Error {
+    fn from(e: CompileError) -> Error {
+        Error::Compile(e)
+    }
+}
+
+impl From<BytecodeError> for Error {
+    fn from(e: BytecodeError) -> Error {
+        Error::Bytecode(e)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(e: RuntimeError) -> Error {
+        Error::Runtime(e)
+    }
+}
+
+// compiles and runs Kythera code. globals declared by one piece of code are visible to the code evaluated
+// after it.
+pub struct Interpreter {
+    compiler: Compiler,
+    vm: Vm,
+    // whether compiled code goes through the optimizer
+    optimize: bool,
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter::with_vm(Vm::new())
+    }
+
+    // an interpreter whose print and println write to output
+    pub fn with_output(output: Box<dyn Write>) -> Interpreter {
+        Interpreter::with_vm(Vm::with_output(output))
+    }
+
+    fn with_vm(vm: Vm) -> Interpreter {
+        Interpreter {
+            compiler: Compiler::for_vm(&vm),
+            vm,
+            optimize: false,
+        }
+    }
+
+    // bound what the code evaluated from now on may use. a runaway program fails with a runtime error.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.vm.set_limits(limits);
+    }
+
+    // optimize the code compiled from now on
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    // free every value only kept alive by reference cycles
+    pub fn gc(&mut self) -> GcStats {
+        self.vm.gc()
+    }
+
+    // evaluate a program, returning the value of its last statement
+    pub fn eval_str(&mut self, source: &str) -> Result<KytheraVal, Error> {
+        self.eval(InputStream::new_from_string(source))
+    }
+
+    // evaluate a source file, or a file compiled by compile_bytecode
+    pub fn eval_file<P: AsRef<Path>>(&mut self, path: P) -> Result<KytheraVal, Error> {
+        let bytes = fs::read(path)?;
+        if bytes.starts_with(bytecode::MAGIC) {
+            return self.eval_bytecode(&bytes);
+        }
+
+        let source = String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.eval_str(&source)
+    }
+
+    // compile a program to the contents of a .kyc file, which can use the globals defined on this
+    // interpreter. nothing is run.
+    pub fn compile_bytecode(&self, source: &str) -> Result<Vec<u8>, Error> {
+        let function = self.compile_only(source)?;
+        Ok(bytecode::serialize(&function)?)
+    }
+
+    // a listing of the instructions a program compiles to, like compile_bytecode
+    pub fn disassemble(&self, source: &str) -> Result<String, Error> {
+        let function = self.compile_only(source)?;
+        Ok(disasm::disassemble(&function, Some(source)))
+    }
+
+    // compile a program without declaring anything it declares
+    fn compile_only(&self, source: &str) -> Result<Rc<Function>, Error> {
+        let program = parser::parse(InputStream::new_from_string(source))?;
+        let function = self.compiler.clone().compile(&program)?;
+
+        Ok(self.optimized(function))
+    }
+
+    fn optimized(&self, function: Rc<Function>) -> Rc<Function> {
+        if self.optimize {
+            Rc::new(optimize::optimize(&function))
+        } else {
+            function
+        }
+    }
+
+    // run a program compiled by compile_bytecode. the compiler does not see what it declares, so code
+    // evaluated afterwards cannot refer to its globals.
+    pub fn eval_bytecode(&mut self, bytes: &[u8]) -> Result<KytheraVal, Error> {
+        let function = bytecode::deserialize(bytes)?;
+        Ok(self.vm.run(function)?)
+    }
+
+    fn eval(&mut self, input: InputStream) -> Result<KytheraVal, Error> {
+        let function = self.compiler.compile(&parser::parse(input)?)?;
+
+        Ok(self.vm.run(self.optimized(function))?)
+    }
+
+    // call the function held by a global with the given arguments
+    pub fn call_function(&mut self, name: &str, args: Vec<KytheraVal>) -> Result<KytheraVal, Error> {
+        let f = self
+            .get_global(name)
+            .ok_or_else(|| RuntimeError::from(ErrorKind::UndefinedVariable(name.to_string())))?;
+
+        // Kythera code is checked before it runs, but values from the host are not
+        if let Type::Fn { params, .. } = f.type_val.as_ref() {
+            for (param, arg) in params.iter().zip(&args) {
+                let checkable = !matches!(param, Type::TypeParam(_)) && !param.is_generic();
+                if checkable && param != arg.type_val.as_ref() {
+                    return Err(RuntimeError::from(arg.mismatch(&param.to_string())).into());
+                }
+            }
+        }
+
+        Ok(self.vm.call(&f, args)?)
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<KytheraVal> {
+        self.vm.get_global(name)
+    }
+
+    // make a value available to Kythera code as a constant global
+    pub fn define(&mut self, name: &str, val: KytheraVal) {
+        self.compiler.define(name, val.type_val.as_ref().clone());
+        self.vm.define(name, val);
+    }
+
+    pub fn define_value<T: IntoKythera>(&mut self, name: &str, val: T) {
+        self.define(name, val.into_kythera());
+    }
+
+    // make a Rust function available to Kythera code, with a Kythera type following from its Rust type
+    pub fn define_fn<Args, F: HostFn<Args>>(&mut self, name: &str, f: F) {
+        self.define(name, f.into_native(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn globals_persist_between_evals() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("const double = (x: Int) => { return x * 2; }; let total = 1;")
+            .unwrap();
+        interpreter.eval_str("total = double(total + 1);").unwrap();
+
+        assert_eq!(interpreter.get_global("total"), Some(KytheraVal::int(4)));
+        assert_eq!(interpreter.eval_str("double(total);").unwrap(), KytheraVal::int(8));
+        assert_eq!(interpreter.get_global("missing"), None);
+    }
+
+    #[test]
+    fn call_function() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("const Point = { x: Int, y: Int };
+impl Point { sum = (self: Point) => { return self.x + self.y; } };
+const add = (a: Int, b: Int) => { return a + b; };
+const sumOf = (p: Point) => { return p.sum(); };
+const first = (T: Type, xs: List(T)) => { return xs[0]; };")
+            .unwrap();
+
+        assert_eq!(
+            interpreter.call_function("add", vec![2.into_kythera(), 3.into_kythera()]).unwrap(),
+            KytheraVal::int(5)
+        );
+
+        let point = interpreter.eval_str("{ x = 1, y = 2 };").unwrap();
+        assert_eq!(interpreter.call_function("sumOf", vec![point]).unwrap(), KytheraVal::int(3));
+
+        let args = vec![KytheraVal::type_val(Type::String), vec!["a", "b"].into_kythera()];
+        assert_eq!(interpreter.call_function("first", args).unwrap(), "a".into_kythera());
+
+        let natives = interpreter.call_function("max", vec![2.into_kythera(), 7.into_kythera()]).unwrap();
+        assert_eq!(natives, KytheraVal::int(7));
+    }
+
+    #[test]
+    fn call_function_errors() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("const divide = (a: Int, b: Int) => { return a / b; }; let notFn = 1;")
+            .unwrap();
+
+        let kind = |result: Result<KytheraVal, Error>| match result {
+            Err(Error::Runtime(e)) => e.kind,
+            result => panic!("Expected runtime error but got {:?}.", result),
+        };
+
+        assert_eq!(
+            kind(interpreter.call_function("divide", vec![1.into_kythera(), 0.into_kythera()])),
+            ErrorKind::DivisionByZero
+        );
+        assert_eq!(
+            kind(interpreter.call_function("divide", vec![1.into_kythera()])),
+            ErrorKind::ArityMismatch { expected: 2, got: 1 }
+        );
+        assert_eq!(
+            kind(interpreter.call_function("divide", vec![1.into_kythera(), true.into_kythera()])),
+            ErrorKind::TypeMismatch {
+                expected: "Int".to_string(),
+                got: Type::Bool,
+            }
+        );
+        assert_eq!(
+            kind(interpreter.call_function("nothing", vec![])),
+            ErrorKind::UndefinedVariable("nothing".to_string())
+        );
+        assert_eq!(
+            kind(interpreter.call_function("notFn", vec![])),
+            ErrorKind::TypeMismatch {
+                expected: "function".to_string(),
+                got: Type::Int,
+            }
+        );
+
+        // a failed call leaves the interpreter usable
+        assert_eq!(interpreter.eval_str("divide(6, 3);").unwrap(), KytheraVal::int(2));
+    }
+
+    #[test]
+    fn compile_errors_are_returned() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("let x = 1;").unwrap();
+
+        match interpreter.eval_str("let y = 2; let z = x + true;") {
+            Err(Error::Compile(e)) => {
+                assert_eq!(e.message, "Type error: invalid operands for Plus: Int and Bool.")
+            }
+            result => panic!("Expected compile error but got {:?}.", result),
+        }
+        assert!(matches!(interpreter.eval_str("let = ;"), Err(Error::Compile(_))));
+
+        // declarations from the failed program are forgotten, so y can be declared again with another type
+        assert_eq!(interpreter.eval_str("let y = \"two\"; x;").unwrap(), KytheraVal::int(1));
+    }
+
+    #[test]
+    fn eval_file() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_file("./main.ky").unwrap();
+        assert_eq!(interpreter.get_global("secondsPerWeek"), Some(KytheraVal::int(604800)));
+
+        assert!(matches!(interpreter.eval_file("./missing.ky"), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn eval_compiled_file() {
+        let mut interpreter = Interpreter::new();
+        interpreter.define_fn("double", |n: i32| n * 2);
+        let bytes = interpreter.compile_bytecode("let x = double(4); { x = x, y = [x, 1] };").unwrap();
+
+        let path = std::env::temp_dir().join(format!("kytherust-{}.kyc", std::process::id()));
+        fs::write(&path, &bytes).unwrap();
+        let result = interpreter.eval_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap().to_string(), "{ x = 8, y = [8, 1] }");
+        assert_eq!(interpreter.get_global("x"), Some(KytheraVal::int(8)));
+
+        assert!(matches!(
+            interpreter.eval_bytecode(&bytes[..bytes.len() - 1]),
+            Err(Error::Bytecode(BytecodeError::ChecksumMismatch))
+        ));
+        assert!(matches!(interpreter.compile_bytecode("let x = ;"), Err(Error::Compile(_))));
+    }
+
+    #[test]
+    fn compile_after_eval() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("const limit = 10; impl Int { capped = (self: Int) => { return min(self, limit); } };")
+            .unwrap();
+
+        let bytes = interpreter.compile_bytecode("(12).capped() + limit;").unwrap();
+        assert!(interpreter.disassemble("(12).capped();").unwrap().contains("capped"));
+        assert_eq!(interpreter.eval_bytecode(&bytes).unwrap(), KytheraVal::int(20));
+
+        // nothing compiled without running is declared
+        interpreter.compile_bytecode("const other = 1;").unwrap();
+        assert!(matches!(interpreter.eval_str("other;"), Err(Error::Compile(_))));
+    }
+
+    fn limited(limits: Limits, source: &str) -> ErrorKind {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("const down = (n: Int) => { return n == 0 || down(n - 1); };
+const deep = (n: Int) => { return n == 0 || true == deep(n - 1); };
+const churn = (n: Int) => { let xs = [n, n, n, n]; return n == 0 || churn(n - 1); };
+const hoard = (n: Int) => { let xs = [n, n, n, n]; return n == 0 || true == hoard(n - 1); };")
+            .unwrap();
+        interpreter.set_limits(limits);
+
+        match interpreter.eval_str(source) {
+            Err(Error::Runtime(e)) => e.kind,
+            result => panic!("Expected runtime error but got {:?}.", result),
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let fuel = Limits {
+            fuel: Some(1000),
+            ..Limits::default()
+        };
+        assert_eq!(limited(fuel, "down(1000000);"), ErrorKind::OutOfFuel(1000));
+
+        let depth = Limits {
+            max_call_depth: Some(50),
+            ..Limits::default()
+        };
+        assert_eq!(limited(depth, "deep(100);"), ErrorKind::CallDepthExceeded(50));
+
+        let stack = Limits {
+            max_stack: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(limited(stack, "deep(1000);"), ErrorKind::StackOverflow(100));
+
+        // every call holds on to its list until they all return
+        let heap = Limits {
+            max_heap: Some(100000),
+            ..Limits::default()
+        };
+        assert_eq!(limited(heap, "hoard(1000);"), ErrorKind::HeapExceeded(100000));
+
+        let timeout = Limits {
+            timeout: Some(Duration::ZERO),
+            ..Limits::default()
+        };
+        assert_eq!(limited(timeout, "down(1000000);"), ErrorKind::DeadlineExceeded(Duration::ZERO));
+    }
+
+    #[test]
+    fn programs_within_limits() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits {
+            fuel: Some(100000),
+            max_call_depth: Some(200),
+            max_stack: Some(1000),
+            max_heap: Some(100000),
+            timeout: Some(Duration::from_secs(60)),
+        });
+        interpreter
+            .eval_str("const deep = (n: Int) => { return n == 0 || true == deep(n - 1); };")
+            .unwrap();
+
+        // the limits apply to each evaluation separately
+        for _ in 0..3 {
+            assert_eq!(
+                interpreter.eval_str("map(Int, Int, [1, 2, 3], (n: Int) => { return n * 2; });").unwrap().to_string(),
+                "[2, 4, 6]"
+            );
+        }
+
+        // a failed evaluation leaves the interpreter usable
+        assert!(interpreter.eval_str("deep(1000);").is_err());
+        assert_eq!(interpreter.eval_str("1 + 2;").unwrap(), KytheraVal::int(3));
+    }
+
+    // freed memory can be used again, so a run can allocate far more than the limit over all
+    #[test]
+    fn heap_is_reused() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits {
+            max_heap: Some(100000),
+            ..Limits::default()
+        });
+
+        // each call leaves a closure and its scope holding each other, which only a collection frees
+        let source = "const churn = (n: Int) => {
+    let xs = [n, n, n, n];
+    let f = (x: Int) => { return x == 0 || f(x - 1); };
+    return n == 0 || churn(n - 1);
+};
+churn(100000);";
+        assert_eq!(interpreter.eval_str(source).unwrap(), KytheraVal::bool(true));
+        assert!(interpreter.vm.gc_stats().total_freed > 0);
+    }
+
+    // a few frames are enough for any depth of calls in tail position
+    fn shallow() -> Interpreter {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits {
+            max_call_depth: Some(8),
+            ..Limits::default()
+        });
+        interpreter
+    }
+
+    #[test]
+    fn tail_recursion() {
+        let mut interpreter = shallow();
+        let result = interpreter.eval_str("const count = (n: Int, total: Int) => {
+    return n == 0 && total == 20000 || n > 0 && count(n - 1, total + 2);
+};
+count(10000, 0);");
+
+        assert_eq!(result.unwrap(), KytheraVal::bool(true));
+    }
+
+    #[test]
+    fn mutual_tail_recursion() {
+        let mut interpreter = shallow();
+        interpreter
+            .eval_str("impl Int {
+    isEven = (self: Int) => { return self == 0 || (self - 1).isOdd(); },
+    isOdd = (self: Int) => { return self != 0 && (self - 1).isEven(); },
+};")
+            .unwrap();
+
+        assert_eq!(interpreter.eval_str("(10001).isOdd();").unwrap(), KytheraVal::bool(true));
+        assert_eq!(interpreter.eval_str("(10001).isEven();").unwrap(), KytheraVal::bool(false));
+
+        interpreter
+            .eval_str("const even = (n: Int) => { return n == 0 || odd(n - 1); };
+const odd = (n: Int) => { return n != 0 && even(n - 1); };")
+            .unwrap();
+        assert_eq!(interpreter.eval_str("odd(10001);").unwrap(), KytheraVal::bool(true));
+        assert_eq!(interpreter.eval_str("even(10001);").unwrap(), KytheraVal::bool(false));
+
+        // natives called in tail position return to the right frame
+        let result = interpreter.eval_str("let smaller = (a: Int, b: Int) => { return min(a, b); };
+let twice = (a: Int) => { return smaller(a, 10) * 2; };
+twice(7);");
+        assert_eq!(result.unwrap(), KytheraVal::int(14));
+
+        // calls that are not in tail position still need a frame each
+        let result = interpreter.eval_str("const deep = (n: Int) => { return n == 0 || deep(n - 1) == true; };
+deep(100);");
+        assert!(matches!(result, Err(Error::Runtime(e)) if e.kind == ErrorKind::CallDepthExceeded(8)));
+    }
+
+    #[test]
+    fn gc_frees_cycles() {
+        let mut interpreter = Interpreter::new();
+        let zeros = vec!["0"; 100].join(", ");
+        interpreter
+            .eval_str(&format!("const xs = [{}];
+// each call leaves behind a scope holding a struct holding a closure holding the scope
+const churn = (n: Int) => {{
+    let p = {{ f = () => {{ return n; }} }};
+    return n;
+}};", zeros))
+            .unwrap();
+
+        let mut live = Vec::new();
+        for _ in 0..3 {
+            interpreter
+                .eval_str("map(Int, List(Int), xs, (a: Int) => { return map(Int, Int, xs, churn); });")
+                .unwrap();
+            live.push(interpreter.gc().live);
+        }
+
+        let stats = interpreter.gc();
+        assert!(stats.collections > 3, "{:?}", stats);
+        assert!(stats.total_freed >= 30000, "{:?}", stats);
+        assert_eq!(stats.freed, 0);
+        // the cycles from one run are gone before the next, so nothing builds up
+        assert!(live.iter().all(|&n| n == stats.live), "{:?}", live);
+    }
+
+    #[test]
+    fn gc_keeps_reachable_values() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .eval_str("const makeAdder = (n: Int) => {
+    let self = { get = () => { return n; } };
+    return (x: Int) => { return x + self.get(); };
+};
+const addTwo = makeAdder(2);")
+            .unwrap();
+
+        // held only by the host
+        let add_five = interpreter.eval_str("makeAdder(5);").unwrap();
+        let stats = interpreter.gc();
+        assert_eq!(stats.freed, 0);
+
+        interpreter.define("addFive", add_five);
+        assert_eq!(interpreter.eval_str("addTwo(1) + addFive(1);").unwrap(), KytheraVal::int(9));
+
+        // once the host lets go, the adder's scope is a cycle nothing else holds
+        interpreter.eval_str("makeAdder(7);").unwrap();
+        assert_eq!(interpreter.gc().freed, 1);
+    }
+
+    #[test]
+    fn host_definitions() {
+        let mut interpreter = Interpreter::new();
+        interpreter.define_fn("greet", |name: String| format!("hello, {}", name));
+        interpreter.define_value("who", "kythera");
+
+        assert_eq!(interpreter.eval_str("greet(who);").unwrap(), "hello, kythera".into_kythera());
+    }
+}
@@ -0,0 +1,24 @@
+pub mod bytecode;
+pub mod compiler;
+pub mod disasm;
+pub mod error;
+pub mod ffi;
+pub mod gc;
+pub mod input_stream;
+pub mod interpreter;
+pub mod optimize;
+pub mod parser;
+pub mod prelude;
+pub mod symbol;
+#[cfg(test)]
+mod testing;
+pub mod tokenizer;
+pub mod types;
+pub mod verify;
+pub mod vm;
+
+pub use error::{CompileError, RuntimeError};
+pub use ffi::{FromKythera, IntoKythera};
+pub use gc::GcStats;
+pub use interpreter::{Error, Interpreter};
+pub use vm::{KytheraVal, Limits};
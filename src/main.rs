@@ -1,20 +1,71 @@
-use std::io::Error;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
 
-mod input_stream;
-mod tokenizer;
-mod parser;
-mod vm;
+use kytherust::{bytecode, disasm, Error, Interpreter};
 
-fn main() -> Result<(), Error> {
-    let is = input_stream::InputStream::new_from_file("./main.ky")?;
-    let tokenizer = tokenizer::Tokenizer::new(is);
-    let mut parser = parser::Parser::new(tokenizer);
+const USAGE: &str = "Usage: kytherust [-O] [file.ky | file.kyc]
+       kytherust compile [-O] file.ky [-o file.kyc]
+       kytherust disasm [-O] (file.ky | file.kyc)
 
-    let program = parser.parse();
+  -O  optimize the compiled code";
 
-    for node in program {
-        println!("{:#?}", node);
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut interpreter = Interpreter::new();
+    if let Some(flag) = args.iter().position(|arg| arg == "-O") {
+        args.remove(flag);
+        interpreter.set_optimize(true);
     }
 
+    let result = match args.first().map(String::as_str) {
+        Some("compile") => compile(interpreter, &args[1..]),
+        Some("disasm") if args.len() == 2 => disasm(interpreter, &args[1]),
+        Some(path) if args.len() == 1 => run(interpreter, path),
+        None => run(interpreter, "./main.ky"),
+        Some(_) => fail(USAGE),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(mut interpreter: Interpreter, path: &str) -> Result<(), Error> {
+    println!("{}", interpreter.eval_file(path)?);
+    Ok(())
+}
+
+// compile a source file to a .kyc file, next to it unless -o says where
+fn compile(interpreter: Interpreter, args: &[String]) -> Result<(), Error> {
+    let (source, output) = match args {
+        [source] => (source, Path::new(source).with_extension("kyc")),
+        [source, flag, output] if flag == "-o" => (source, PathBuf::from(output)),
+        _ => fail(USAGE),
+    };
+
+    let bytes = interpreter.compile_bytecode(&fs::read_to_string(source)?)?;
+    fs::write(output, bytes)?;
     Ok(())
 }
+
+// print the instructions a source file compiles to, or those in a .kyc file
+fn disasm(interpreter: Interpreter, path: &str) -> Result<(), Error> {
+    let bytes = fs::read(path)?;
+    let listing = if bytes.starts_with(bytecode::MAGIC) {
+        let function = bytecode::deserialize(&bytes)?;
+        disasm::disassemble(&function, None)
+    } else {
+        interpreter.disassemble(&String::from_utf8_lossy(&bytes))?
+    };
+
+    print!("{}", listing);
+    Ok(())
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
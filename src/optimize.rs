@@ -0,0 +1,441 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::input_stream::Loc;
+use crate::verify;
+use crate::vm::{Function, Instruction, InternalVal};
+
+// an equivalent function (and functions it makes) with fewer instructions to execute. passes run until none
+// of them finds anything more to do:
+//
+// - peephole rules rewrite pairs of instructions, e.g. a store followed by a load of the same variable
+//   becomes Dup and the store, and Dup followed by Pop is removed
+// - jumps to jumps go straight to where the second jump goes, and jumps to Return become Return
+// - instructions that cannot be reached are removed
+//
+// instructions are removed by replacing them with Nop, and Nops are removed at the end of each round, moving
+// jump targets to the instructions after them.
+pub fn optimize(function: &Function) -> Function {
+    let optimized = optimize_nested(function);
+
+    // an optimized function that does not verify is a bug in the optimizer
+    if cfg!(debug_assertions) {
+        if let Err(e) = verify::verify(&optimized) {
+            panic!("Optimizer bug: invalid bytecode in {}", e);
+        }
+    }
+
+    optimized
+}
+
+fn optimize_nested(function: &Function) -> Function {
+    let mut code: Vec<Instruction> = function
+        .instructions
+        .iter()
+        .map(|inst| match inst {
+            Instruction::MakeClosure(nested) => Instruction::MakeClosure(Rc::new(optimize_nested(nested))),
+            inst => inst.clone(),
+        })
+        .collect();
+    // functions built into the VM have no locations to keep in step
+    let mut locs = function.locs.clone();
+    let has_locs = locs.len() == code.len();
+
+    loop {
+        let changed = peephole(&mut code, function) | thread_jumps(&mut code) | remove_unreachable(&mut code);
+        let removed = remove_nops(&mut code, if has_locs { Some(&mut locs) } else { None });
+        if !changed && !removed {
+            break;
+        }
+    }
+
+    Function {
+        name: function.name.clone(),
+        params: function.params.clone(),
+        slots: function.slots,
+        instructions: code,
+        constants: function.constants.clone(),
+        locs,
+        type_val: Rc::clone(&function.type_val),
+    }
+}
+
+fn jump_target(inst: &Instruction) -> Option<usize> {
+    match inst {
+        Instruction::Jump(target) | Instruction::JumpIf(target) | Instruction::JumpIfNot(target) => Some(*target),
+        _ => None,
+    }
+}
+
+fn jump_target_mut(inst: &mut Instruction) -> Option<&mut usize> {
+    match inst {
+        Instruction::Jump(target) | Instruction::JumpIf(target) | Instruction::JumpIfNot(target) => Some(target),
+        _ => None,
+    }
+}
+
+// rewrite pairs of instructions. the second of a pair must not be jumped to, since the rewrite would change
+// what a jump there does.
+fn peephole(code: &mut [Instruction], function: &Function) -> bool {
+    use Instruction::*;
+
+    let targets: HashSet<usize> = code.iter().filter_map(jump_target).collect();
+    let bool_constant = |index: &u16| match function.constants.get(*index as usize).map(|c| &c.val) {
+        Some(InternalVal::Bool(b)) => Some(*b),
+        _ => None,
+    };
+    let mut changed = false;
+
+    for i in 0..code.len() {
+        // a jump to the next instruction
+        if jump_target(&code[i]) == Some(i + 1) && matches!(code[i], Jump(_)) {
+            code[i] = Nop;
+            changed = true;
+        }
+
+        if i + 1 == code.len() || targets.contains(&(i + 1)) {
+            continue;
+        }
+
+        let rewrite = match (&code[i], &code[i + 1]) {
+            // storing a value and loading it again: keep a copy instead
+            (StoreLocal(a), LoadLocal(b)) if a == b => Some((Dup, StoreLocal(*a))),
+            (StoreCaptured(d, a), LoadCaptured(e, b)) if d == e && a == b => Some((Dup, StoreCaptured(*d, *a))),
+            (StoreGlobal(a), LoadGlobal(b)) if a == b => Some((Dup, StoreGlobal(a.clone()))),
+            (DeclareGlobal(a), LoadGlobal(b)) if a == b => Some((Dup, DeclareGlobal(a.clone()))),
+            // pushing a value only to pop it
+            (Dup, Pop) | (Constant(_), Pop) | (LoadLocal(_), Pop) => Some((Nop, Nop)),
+            // branching on the opposite of a value
+            (Not, JumpIf(target)) => Some((Nop, JumpIfNot(*target))),
+            (Not, JumpIfNot(target)) => Some((Nop, JumpIf(*target))),
+            // branching on a constant
+            (Constant(c), JumpIf(target)) => bool_constant(c).map(|b| (Nop, if b { Jump(*target) } else { Nop })),
+            (Constant(c), JumpIfNot(target)) => bool_constant(c).map(|b| (Nop, if b { Nop } else { Jump(*target) })),
+            _ => None,
+        };
+
+        if let Some((first, second)) = rewrite {
+            code[i] = first;
+            code[i + 1] = second;
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+// point jumps that land on a Jump at where that one goes, and replace jumps to Return with Return
+fn thread_jumps(code: &mut [Instruction]) -> bool {
+    let mut changed = false;
+
+    for i in 0..code.len() {
+        let Some(mut target) = jump_target(&code[i]) else {
+            continue;
+        };
+
+        // follow the chain, giving up on jumps that loop forever
+        let mut hops = 0;
+        while let Some(Instruction::Jump(next)) = code.get(target) {
+            if *next == target || hops == code.len() {
+                break;
+            }
+            target = *next;
+            hops += 1;
+        }
+
+        if matches!(code[i], Instruction::Jump(_)) && matches!(code.get(target), Some(Instruction::Return)) {
+            code[i] = Instruction::Return;
+            changed = true;
+        } else if let Some(old) = jump_target_mut(&mut code[i]).filter(|old| **old != target) {
+            *old = target;
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+// replace instructions that no path from the start reaches with Nop
+fn remove_unreachable(code: &mut [Instruction]) -> bool {
+    let mut reached = vec![false; code.len()];
+    let mut pending = vec![0];
+
+    while let Some(i) = pending.pop() {
+        if i >= code.len() || reached[i] {
+            continue;
+        }
+        reached[i] = true;
+
+        if let Some(target) = jump_target(&code[i]) {
+            pending.push(target);
+        }
+        if !matches!(code[i], Instruction::Jump(_) | Instruction::Return | Instruction::TailInvoke(_)) {
+            pending.push(i + 1);
+        }
+    }
+
+    let mut changed = false;
+    for (inst, reached) in code.iter_mut().zip(reached) {
+        if !reached && !matches!(inst, Instruction::Nop) {
+            *inst = Instruction::Nop;
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+// remove every Nop, along with its location. jumps to a Nop go to the instruction after it instead.
+fn remove_nops(code: &mut Vec<Instruction>, locs: Option<&mut Vec<Loc>>) -> bool {
+    // the index each instruction will have, or the index of the next kept instruction for a Nop
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for inst in code.iter() {
+        new_index.push(kept);
+        if !matches!(inst, Instruction::Nop) {
+            kept += 1;
+        }
+    }
+    new_index.push(kept);
+
+    if kept == code.len() {
+        return false;
+    }
+
+    let end = code.len();
+    for inst in code.iter_mut() {
+        if let Some(target) = jump_target_mut(inst) {
+            *target = new_index[(*target).min(end)];
+        }
+    }
+
+    if let Some(locs) = locs {
+        let mut insts = code.iter();
+        locs.retain(|_| !matches!(insts.next(), Some(Instruction::Nop)));
+    }
+    code.retain(|inst| !matches!(inst, Instruction::Nop));
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::testing::{compile, Output};
+    use crate::types::Type;
+    use crate::vm::{KytheraVal, Vm};
+
+    // the result and output of running a program, without and with optimization
+    fn both_ways(source: &str) -> [(String, String); 2] {
+        let function = compile(source);
+        let optimized = optimize(&function);
+        assert!(size(&optimized) <= size(&function));
+
+        [function, Rc::new(optimized)].map(|function| {
+            let output = Output::default();
+            let mut vm = Vm::with_output(Box::new(output.clone()));
+            let result = match vm.run(function) {
+                Ok(val) => val.to_string(),
+                Err(e) => e.to_string(),
+            };
+            (result, output.printed())
+        })
+    }
+
+    // instructions in a function and the functions it makes
+    fn size(function: &Function) -> usize {
+        function
+            .instructions
+            .iter()
+            .map(|inst| match inst {
+                Instruction::MakeClosure(nested) => 1 + size(nested),
+                _ => 1,
+            })
+            .sum()
+    }
+
+    fn optimized(constants: Vec<KytheraVal>, instructions: Vec<Instruction>) -> String {
+        let function = Function {
+            name: "test".to_string(),
+            params: Vec::new(),
+            slots: 1,
+            instructions,
+            constants,
+            locs: Vec::new(),
+            type_val: Rc::new(Type::Unit),
+        };
+
+        format!("{:?}", optimize(&function).instructions)
+    }
+
+    #[test]
+    fn main_ky_is_unchanged() {
+        let [plain, fast] = both_ways(&fs::read_to_string("./main.ky").unwrap());
+        assert_eq!(plain, fast);
+    }
+
+    #[test]
+    fn programs_are_unchanged() {
+        let programs = [
+            "let twice = (n: Int) => { let m = n * 2; return m; };
+let a = twice(4);
+a = a + twice(a);
+println(toString(Int, a));
+a;",
+            "let flags = [true, false];
+let check = (b: Bool) => { return !(b == !true || false) && !!b; };
+map(Bool, Bool, flags, check);",
+            "const Shape = { circle: Double | square: Double };
+let area = (s: Shape) => {
+    let x = 2.0;
+    return when s { circle(r) => r * r * 3.0, square(w) => w * w * x };
+};
+{ a = area(Shape.circle(1.0)), b = area(Shape.square(2.0)) };",
+            "let counter = () => {
+    let n = 0;
+    return () => { n = n + 1; return n; };
+};
+let next = counter();
+next();
+next() + next();",
+            "const IntResult = Result(Int, String);
+let half = (r: IntResult) => {
+    let n = r?;
+    let m = n / 2;
+    return IntResult.ok(m + 1);
+};
+{ a = half(IntResult.ok(4)), b = half(IntResult.err(\"odd\")) };",
+            "let zero = 0;\nlet x = 1 / zero;",
+        ];
+
+        for program in programs {
+            let [plain, fast] = both_ways(program);
+            assert_eq!(plain, fast, "{}", program);
+        }
+    }
+
+    #[test]
+    fn peephole_rules() {
+        use Instruction::*;
+
+        let int = || vec![KytheraVal::int(1), KytheraVal::bool(true), KytheraVal::bool(false)];
+
+        assert_eq!(
+            optimized(int(), vec![Constant(0), StoreLocal(0), LoadLocal(0), Return]),
+            "[Constant(0), Dup, StoreLocal(0), Return]"
+        );
+        assert_eq!(
+            optimized(int(), vec![Constant(0), DeclareGlobal("x".into()), LoadGlobal("x".into()), Return]),
+            "[Constant(0), Dup, DeclareGlobal(\"x\"), Return]"
+        );
+        assert_eq!(
+            optimized(int(), vec![Nop, Constant(0), Dup, Pop, Constant(0), Pop, Nop, Return]),
+            "[Constant(0), Return]"
+        );
+        assert_eq!(
+            optimized(int(), vec![LoadLocal(0), Not, JumpIf(5), Constant(0), Return, Constant(0), Return]),
+            "[LoadLocal(0), JumpIfNot(4), Constant(0), Return, Constant(0), Return]"
+        );
+
+        // branches on constants are decided
+        assert_eq!(
+            optimized(int(), vec![Constant(1), JumpIf(4), Constant(0), Return, Constant(1), Return]),
+            "[Constant(1), Return]"
+        );
+        assert_eq!(
+            optimized(int(), vec![Constant(2), JumpIf(4), Constant(0), Return, Constant(1), Return]),
+            "[Constant(0), Return]"
+        );
+
+        // a load that is jumped to can be reached without the store before it
+        assert_eq!(
+            optimized(
+                int(),
+                vec![
+                    LoadLocal(0),
+                    JumpIf(4),
+                    Constant(0),
+                    StoreLocal(0),
+                    LoadLocal(0),
+                    Return
+                ]
+            ),
+            "[LoadLocal(0), JumpIf(4), Constant(0), StoreLocal(0), LoadLocal(0), Return]"
+        );
+    }
+
+    #[test]
+    fn jump_threading() {
+        use Instruction::*;
+
+        let constants = || vec![KytheraVal::int(1), KytheraVal::int(2)];
+
+        // JumpIf to a Jump to a Jump goes straight to the end, and the Jumps are no longer reached
+        assert_eq!(
+            optimized(
+                constants(),
+                vec![
+                    LoadLocal(0),
+                    JumpIf(4),
+                    Constant(0),
+                    Return,
+                    Jump(5),
+                    Jump(6),
+                    Constant(1),
+                    Return
+                ]
+            ),
+            "[LoadLocal(0), JumpIf(4), Constant(0), Return, Constant(1), Return]"
+        );
+
+        // a jump to Return returns, which leaves the code after it unreachable
+        assert_eq!(
+            optimized(
+                constants(),
+                vec![Constant(0), Jump(4), Constant(1), Add, Return]
+            ),
+            "[Constant(0), Return]"
+        );
+
+        // loops are kept
+        assert_eq!(
+            optimized(constants(), vec![Nop, Constant(0), Pop, Jump(0)]),
+            "[Jump(0)]"
+        );
+    }
+
+    #[test]
+    fn unreachable_code_is_removed() {
+        use Instruction::*;
+
+        let nested = Function {
+            name: "nested".to_string(),
+            params: Vec::new(),
+            slots: 0,
+            instructions: vec![Constant(0), Return, Pop, Constant(0), Return],
+            constants: vec![KytheraVal::int(1)],
+            locs: Vec::new(),
+            type_val: Rc::new(Type::Int),
+        };
+
+        let function = Function {
+            name: "test".to_string(),
+            params: Vec::new(),
+            slots: 0,
+            instructions: vec![MakeClosure(Rc::new(nested)), Return, Constant(0), Return],
+            constants: vec![KytheraVal::int(1)],
+            locs: vec![Loc { line: 1, col: 1 }, Loc { line: 2, col: 1 }, Loc::default(), Loc::default()],
+            type_val: Rc::new(Type::Unit),
+        };
+
+        let optimized = optimize(&function);
+        assert_eq!(optimized.instructions.len(), 2);
+        assert_eq!(optimized.locs, vec![Loc { line: 1, col: 1 }, Loc { line: 2, col: 1 }]);
+        match &optimized.instructions[0] {
+            MakeClosure(nested) => assert_eq!(format!("{:?}", nested.instructions), "[Constant(0), Return]"),
+            inst => panic!("Expected MakeClosure but got {:?}.", inst),
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::error::{fail, CompileError};
+use crate::input_stream::{InputStream, Loc};
 use crate::tokenizer::*;
 use crate::tokenizer::Symbol::*;
 use crate::tokenizer::Keyword::*;
@@ -7,42 +9,61 @@ use std::collections::HashMap;
 use lazy_static::lazy_static;
 use crate::parser::AstNode::Access;
 
+// Binary operator table, from loosest to tightest binding. Each entry is (precedence, associativity);
+// an operator binds its operands tighter than any operator with a lower precedence. Prefix operators
+// (`!`, `~`, `typeof`) bind tighter than every binary operator, and postfix forms (calls, `.` and `[]`
+// access) bind tighter still.
+//
+//  1  = += -= *= /= %=    right
+//  3  ||                  left
+//  4  &&                  left
+//  5  |                   left
+//  6  ^                   left
+//  7  &                   left
+//  8  == !=               left
+//  9  < <= > >=           left
+// 10  << >>               left
+// 11  + -                 left
+// 12  * / %               left
 lazy_static! {
-    static ref OP_PRECEDENCE: HashMap<Symbol, u8> = {
+    static ref OP_PRECEDENCE: HashMap<Symbol, (u8, Associativity)> = {
         let mut map = HashMap::new();
 
-        // TODO macro to factor out the repeated code
-        map.insert(Equal, 1);
-        map.insert(PlusEqual, 1);
-        map.insert(MinusEqual, 1);
-        map.insert(StarEqual, 1);
-        map.insert(SlashEqual, 1);
-        map.insert(PercentEqual, 1);
-
-        map.insert(BarBar, 3);
-        map.insert(AndAnd, 4);
+        macro_rules! ops {
+            ($precedence:expr, $assoc:ident, $($sym:ident),+) => {
+                $(map.insert($sym, ($precedence, Associativity::$assoc));)+
+            };
+        }
 
-        map.insert(EqualEqual,8);
-        map.insert(BangEqual,8);
+        ops!(1, Right, Equal, PlusEqual, MinusEqual, StarEqual, SlashEqual, PercentEqual);
 
-        map.insert(Less,9);
-        map.insert(LessEqual,9);
-        map.insert(Greater,9);
-        map.insert(GreaterEqual,9);
+        ops!(3, Left, BarBar);
+        ops!(4, Left, AndAnd);
 
-        map.insert(Plus,11);
-        map.insert(Minus,11);
+        ops!(5, Left, Bar);
+        ops!(6, Left, Caret);
+        ops!(7, Left, And);
 
-        map.insert(Star,12);
-        map.insert(Slash,12);
-        map.insert(Percent,12);
+        ops!(8, Left, EqualEqual, BangEqual);
+        ops!(9, Left, Less, LessEqual, Greater, GreaterEqual);
+        ops!(10, Left, LessLess, GreaterGreater);
 
-        map.insert(Bang,14);
+        ops!(11, Left, Plus, Minus);
+        ops!(12, Left, Star, Slash, Percent);
 
         map
     };
 }
 
+// precedence of the assignment operators, which may not appear unparenthesized in some positions
+const ASSIGNMENT_PRECEDENCE: u8 = 1;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Associativity {
+    Left,
+    Right,
+}
+
 pub struct Parser {
     tok: Tokenizer,
 }
@@ -65,24 +86,32 @@ pub enum AstNode {
     },
     // necessary - a block is a node because it evaluates to a type
     Block {
-        body: Vec<AstNode>,
+        body: Vec<Statement>,
     },
     Literal(Literal),
     Declaration {
         op: Keyword,
-        id: String,
+        pattern: Pattern,
         value: Box<AstNode>,
     },
+    // not yet produced by the parser
+    #[allow(dead_code)]
     If {
         condition: Box<AstNode>,
         body: Box<AstNode>,
         else_body: Option<Box<AstNode>>,
     },
+    #[allow(dead_code)]
     While {
         condition: Box<AstNode>,
         body: Box<AstNode>,
     },
-    When,
+    // matches a value of a union type against its variants, e.g. when shape { circle(r) => r * r, else => 0 }
+    When {
+        subject: Box<AstNode>,
+        arms: Vec<WhenArm>,
+        else_body: Option<Box<AstNode>>,
+    },
     // break, return, continue
     Jump {
         op: Keyword,
@@ -91,11 +120,24 @@ pub enum AstNode {
     Typeof {
         operand: Box<AstNode>,
     },
+    // unwraps the ok value of a Result, or returns its err from the enclosing function, e.g. parse(s)?
+    Propagate {
+        operand: Box<AstNode>,
+    },
     Identifier(String),
     Access {
         target: Box<AstNode>,
         field: String,
     },
+    Index {
+        target: Box<AstNode>,
+        index: Box<AstNode>,
+    },
+    // attaches functions to a type, e.g. impl Point { norm = (self: Point) => { ... } }
+    Impl {
+        target: Box<AstNode>,
+        methods: Vec<(String, AstNode)>,
+    },
     // Import,
     // Export,
 }
@@ -107,10 +149,15 @@ pub enum Literal {
     Double(f64),
     String(String),
     Bool(bool),
-    Struct(HashMap<String, AstNode>),
-    StructType(HashMap<String, AstNode>),
+    // fields are kept in the order they were written
+    Struct(Vec<StructEntry>),
+    List(Vec<AstNode>),
+    StructType(Vec<(String, AstNode)>),
+    // variants are kept in the order they were written
+    UnionType(Vec<(String, AstNode)>),
     Fn {
         param_names: Vec<String>,
+        param_types: Vec<AstNode>,
         body: Box<AstNode>,
     },
     FnType {
@@ -119,28 +166,54 @@ pub enum Literal {
     },
 }
 
-fn is_binary(token: &Token) -> bool {
-    if let Token::Sym(sym) = token {
-        [
-            Bar,
-            And,
-            AndAnd,
-            BarBar,
-            EqualEqual,
-            BangEqual,
-            Less,
-            LessEqual,
-            Greater,
-            GreaterEqual,
-            Plus,
-            Minus,
-            Star,
-            Slash,
-            Percent,
-        ]
-            .contains(sym)
-    } else {
-        false
+#[derive(Debug, PartialEq, Clone)]
+pub enum StructEntry {
+    Field(String, AstNode),
+    // ...exp, copying every field of another struct. later entries override earlier ones.
+    Spread(AstNode),
+}
+
+// a statement in a program or block, along with where it starts in the source
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub node: AstNode,
+    pub loc: Loc,
+}
+
+// statements are equal if their code is, wherever it was written
+impl PartialEq for Statement {
+    fn eq(&self, other: &Statement) -> bool {
+        self.node == other.node
+    }
+}
+
+// one variant handled by a when, e.g. some(x) => x + 1. the pattern binds the value of the variant.
+#[derive(Debug, PartialEq, Clone)]
+pub struct WhenArm {
+    pub tag: String,
+    pub pattern: Option<Pattern>,
+    pub body: AstNode,
+}
+
+// the left-hand side of a declaration, binding a value or the parts of one to names
+#[derive(Debug, PartialEq, Clone)]
+pub enum Pattern {
+    Identifier(String),
+    // e.g. { x, y = py, pos = { z, } }, binding the listed fields. a field without a pattern is bound
+    // to a variable of the same name.
+    Struct(Vec<(String, Pattern)>),
+    // e.g. [a, b], binding the first elements of a list
+    List(Vec<Pattern>),
+}
+
+impl Pattern {
+    // every name bound by the pattern, in order
+    pub fn names(&self) -> Vec<&String> {
+        match self {
+            Pattern::Identifier(name) => vec![name],
+            Pattern::Struct(fields) => fields.iter().flat_map(|(_, pattern)| pattern.names()).collect(),
+            Pattern::List(elements) => elements.iter().flat_map(Pattern::names).collect(),
+        }
     }
 }
 
@@ -150,71 +223,76 @@ impl Parser {
     }
 
     // parse until EOF
-    pub fn parse(&mut self) -> Vec<AstNode> {
-        let mut program: Vec<AstNode> = Vec::new();
+    pub fn parse(&mut self) -> Result<Vec<Statement>, CompileError> {
+        let mut program: Vec<Statement> = Vec::new();
         while self.tok.peek().is_some() {
-            program.push(self.parse_exp(true));
+            let loc = self.tok.token_loc();
+            program.push(Statement {
+                node: self.parse_exp()?,
+                loc,
+            });
 
-            self.tok.consume_expect(&Token::Sym(Semicolon));
+            self.tok.consume_expect(&Token::Sym(Semicolon))?;
         }
 
-        program
+        Ok(program)
     }
 
-    // assemble composite expressions, e.g. binary exps, function calls, etc
-    fn parse_exp(&mut self, compose: bool) -> AstNode {
-        let exp = self.parse_exp_atom();
+    // parse a full expression, including any binary operators
+    fn parse_exp(&mut self) -> Result<AstNode, CompileError> {
+        let lhs = self.parse_unary()?;
+        self.make_binary(lhs, 0)
+    }
 
-        if !compose {
-            return exp;
+    // parse a prefix operator applied to an operand, or a postfix expression
+    fn parse_unary(&mut self) -> Result<AstNode, CompileError> {
+        match *self.tok.peek() {
+            Some(Token::Sym(op)) if op == Bang || op == Tilde => {
+                self.tok.consume_expect(&Token::Sym(op))?;
+                Ok(AstNode::Unary {
+                    op,
+                    operand: Box::from(self.parse_unary()?),
+                })
+            }
+            Some(Token::Kw(Typeof)) => {
+                self.tok.consume_expect(&Token::Kw(Typeof))?;
+                Ok(AstNode::Typeof {
+                    operand: Box::from(self.parse_unary()?),
+                })
+            }
+            _ => {
+                let atom = self.parse_exp_atom()?;
+                self.parse_postfix(atom)
+            }
         }
+    }
 
-        let mut composed: AstNode = exp;
+    // repeatedly grow an atom with calls, accesses and ? until none are available
+    fn parse_postfix(&mut self, atom: AstNode) -> Result<AstNode, CompileError> {
+        let mut composed = atom;
 
-        // repeatedly attempt to grow the expression to the right until none are available
         loop {
-            let next = self.tok.peek();
-            let mut finished = false;
-
-            match next {
-                Some(t) => {
-                    match t {
-                        t if t == &Token::Sym(LeftParen) => {
-                            composed = self.make_call(composed);
-                        }
-                        t if is_binary(t) => {
-                            composed = self.make_binary(composed, 0);
-                        }
-                        t if t == &Token::Sym(Dot) => {
-                            composed = self.make_dot_access(composed);
-                        }
-                        t if t == &Token::Sym(LeftBracket) => {
-                            composed = self.make_bracket_access(composed);
-                        }
-                        _ => {
-                            finished = true;
-                        }
+            composed = match self.tok.peek() {
+                Some(Token::Sym(LeftParen)) => self.make_call(composed)?,
+                Some(Token::Sym(Dot)) => self.make_dot_access(composed)?,
+                Some(Token::Sym(LeftBracket)) => self.make_bracket_access(composed)?,
+                Some(Token::Sym(Question)) => {
+                    self.tok.consume_expect(&Token::Sym(Question))?;
+                    AstNode::Propagate {
+                        operand: Box::from(composed),
                     }
                 }
-                None => {
-                    panic!("Unexpected EOF.")
-                }
-            }
-
-            if finished {
-                break;
-            }
+                _ => return Ok(composed),
+            };
         }
-
-        composed
     }
 
-    fn parse_exp_atom(&mut self) -> AstNode {
+    fn parse_exp_atom(&mut self) -> Result<AstNode, CompileError> {
         if let Some(token) = self.tok.peek() {
-            match token {
+            Ok(match token {
                 // n literal, fn type literal, or parenthesized expression
                 &Token::Sym(LeftParen) => {
-                    self.tok.consume_expect(&Token::Sym(LeftParen));
+                    self.tok.consume_expect(&Token::Sym(LeftParen))?;
                     if let Some(next_tok) = self.tok.peek() {
                         match next_tok {
                             // identifier means fn literal:
@@ -222,244 +300,238 @@ impl Parser {
                             //          ^-id we just peeked
                             // RightParen means fn literal with no args
                             &Token::Sym(RightParen) => {
-                                return self.parse_fn_literal(None);
+                                self.parse_fn_literal(None)?
                             }
                             _ => {
                                 // need to peek after the next exp, so hang on to it for now
-                                let next_exp = self.parse_exp(true);
+                                let next_exp = self.parse_exp()?;
 
                                 match self.tok.peek() {
                                     // comma means fn type literal:
-                                    //    e.g. (Int, Int,) => Int
+                                    //    e.g. (Int, Int) => Int
                                     // next_exp-^  ^- comma we just peeked
+                                    // a single parameter type needs a trailing comma, e.g. (Int,) => Int,
+                                    // since (Int) is just a parenthesized expression
                                     Some(Token::Sym(Comma)) => {
-                                        let mut param_types: Vec<AstNode> = Vec::new();
-                                        param_types.push(next_exp);
-                                        self.tok.consume_expect(&Token::Sym(Comma));
-
-                                        loop {
-                                            if let Some(Token::Sym(RightParen)) = self.tok.peek() {
-                                                break;
-                                            }
-
-                                            let param_type_exp = self.parse_exp(true);
-                                            param_types.push(param_type_exp);
-                                            self.tok.consume_expect(&Token::Sym(Comma));
-                                        }
-
-                                        self.tok.consume_expect(&Token::Sym(RightParen));
+                                        let param_types = self.parse_list_rest(next_exp, RightParen, |p| p.parse_exp())?;
 
-                                        self.tok.consume_expect(&Token::Sym(Equal));
-                                        self.tok.consume_expect(&Token::Sym(Greater));
+                                        self.tok.consume_expect(&Token::Sym(EqualGreater))?;
 
-                                        let return_type_exp = self.parse_exp(true);
+                                        let return_type_exp = self.parse_exp()?;
 
-                                        return AstNode::Literal(Literal::FnType {
+                                        AstNode::Literal(Literal::FnType {
                                             param_types,
                                             returns: Box::from(return_type_exp)
-                                        });
+                                        })
                                     }
                                     Some(Token::Sym(Colon)) => {
                                         if let AstNode::Identifier(ref param_name) = next_exp {
-                                            return self.parse_fn_literal(Some(param_name.clone()));
+                                            self.parse_fn_literal(Some(param_name.clone()))?
                                         } else {
-                                            panic!("Expecting identifier for first parameter in function definition but got {:?} at {}.", next_exp, self.tok.loc())
+                                            fail!("Expecting identifier for first parameter in function definition but got {:?} at {}.", next_exp, self.tok.loc())
                                         }
                                     }
                                     // next_exp is paren-wrapped expression
                                     //     e.g. (1);
                                     //  next_exp-^^- paren we just peeked
                                     Some(Token::Sym(RightParen)) => {
-                                        self.tok.consume_expect(&Token::Sym(RightParen));
-                                        return next_exp;
+                                        self.tok.consume_expect(&Token::Sym(RightParen))?;
+
+                                        if let Some(Token::Sym(EqualGreater)) = self.tok.peek() {
+                                            fail!("Expected ',' after the parameter type of a single-parameter function type, e.g. (Int,) => Int, at {}.", self.tok.loc())
+                                        }
+
+                                        next_exp
                                     }
                                     Some(_) => {
-                                        panic!("Expected identifier, \",\", or \")\" but got {:?} at {}.", next_exp, self.tok.loc())
+                                        fail!("Expected identifier, \",\", or \")\" but got {:?} at {}.", next_exp, self.tok.loc())
                                     }
                                     None => {
-                                        panic!("Unexpected EOF.")
+                                        fail!("Unexpected EOF.")
                                     }
                                 }
                             }
-                        };
+                        }
                     } else {
-                        panic!("Unexpected EOF.")
-                    };
+                        fail!("Unexpected EOF.")
+                    }
                 }
                 // list literal
                 &Token::Sym(LeftBracket) => {
-                    panic!("List literal is not yet implemented.")
+                    self.tok.consume_expect(&Token::Sym(LeftBracket))?;
+                    AstNode::Literal(Literal::List(self.parse_list(RightBracket, |p| p.parse_exp())?))
                 }
-                // code block, struct literal, or struct type literal
+                // code block, struct literal, struct type literal, or union type literal
                 &Token::Sym(LeftBrace) => {
-                    self.tok.consume_expect(&Token::Sym(LeftBrace));
+                    self.tok.consume_expect(&Token::Sym(LeftBrace))?;
+                    let first_loc = self.tok.token_loc();
+
+                    // spread means struct literal
+                    //    e.g. { ...myStruct, x = 2 }
+                    if let Some(Token::Sym(DotDotDot)) = self.tok.peek() {
+                        let first_entry = self.parse_struct_entry()?;
+                        return self.parse_struct_literal_rest(first_entry);
+                    }
 
-                    // hang onto first expression after brace
-                    let first_exp = self.parse_exp(true);
+                    // hang onto first expression after brace. assignment is left out, since its '=' would be
+                    // indistinguishable from the one in a struct literal
+                    let first_operand = self.parse_unary()?;
+                    let first_exp = self.make_binary(first_operand, ASSIGNMENT_PRECEDENCE + 1)?;
                     // look at token after first exp
                     match self.tok.peek() {
-                        // equals means struct literal
+                        // equals means struct literal, or a code block starting with an assignment
                         //    e.g. { x = 2, }
                         // first_exp-^ ^- equal we just peeked
                         &Some(Token::Sym(Equal)) => {
-                            let mut result: HashMap<String, AstNode> = HashMap::new();
-
-                            while let Some(token) = self.tok.peek() {
-                                match token {
-                                    // first run only, consume token following id in first_exp
-                                    Token::Sym(Equal) => {
-                                        self.tok.consume_expect(&Token::Sym(Equal));
-
-                                        let exp = self.parse_exp(true);
-                                        if let AstNode::Identifier(ref key) = first_exp {
-                                            result.insert(key.clone(), exp);
-
-                                            self.tok.consume_expect(&Token::Sym(Comma));
-                                        } else {
-                                            panic!("Expecting identifier for first entry in struct but got {:?} at {}.", first_exp, self.tok.loc())
-                                        }
-                                    }
-                                    Token::Id(k) => {
-                                        let key = k.to_string();
-
-                                        // self.tok.consume_expect(&Token::Id(key));
-                                        self.tok.consume();
-                                        self.tok.consume_expect(&Token::Sym(Equal));
-
-                                        let exp = self.parse_exp(true);
-                                        result.insert(key, exp);
-
-                                        self.tok.consume_expect(&Token::Sym(Comma));
-                                    }
-                                    &Token::Sym(RightBrace) => {
-                                        break;
-                                    }
-                                    _ => {
-                                        panic!("Expected struct field name or '}}' but got {:?} at {}", token, self.tok.loc())
-                                    }
-                                }
-                            };
+                            self.tok.consume_expect(&Token::Sym(Equal))?;
+                            let first_value = self.parse_exp()?;
+
+                            // semicolon means the first statement of a code block was an assignment
+                            //    e.g. { x = 2; }
+                            if let Some(Token::Sym(Semicolon)) = self.tok.peek() {
+                                self.tok.consume_expect(&Token::Sym(Semicolon))?;
+                                let first_stmt = AstNode::Binary {
+                                    lhs: Box::from(first_exp),
+                                    op: Equal,
+                                    rhs: Box::from(first_value),
+                                };
+                                return self.parse_started_block(Statement {
+                                    node: first_stmt,
+                                    loc: first_loc,
+                                });
+                            }
 
-                            self.tok.consume_expect(&Token::Sym(RightBrace));
-                            return AstNode::Literal(Literal::Struct(result));
+                            if let AstNode::Identifier(key) = first_exp {
+                                self.parse_struct_literal_rest(StructEntry::Field(key, first_value))?
+                            } else {
+                                fail!("Expecting identifier for first entry in struct but got {:?} at {}.", first_exp, self.tok.loc())
+                            }
                         }
-                        // colon means struct type literal
-                        //    e.g. { x: int, }
+                        // colon means struct type literal or union type literal
+                        //    e.g. { x: int, } or { some: Int | none: Unit }
                         // first_exp-^^- colon we just peeked
                         &Some(Token::Sym(Colon)) => {
-                            let mut result: HashMap<String, AstNode> = HashMap::new();
-
-                            while let Some(token) = self.tok.peek() {
-                                match token {
-                                    // first run only, consume token following id in first_exp
-                                    Token::Sym(Colon) => {
-                                        self.tok.consume_expect(&Token::Sym(Colon));
-
-                                        if let AstNode::Identifier(ref key) = first_exp {
-                                            let type_exp = self.parse_exp(true);
-                                            result.insert(key.clone(), type_exp);
-
-                                            self.tok.consume_expect(&Token::Sym(Comma));
-                                        } else {
-                                            panic!("Expecting identifier for first entry in struct but got {:?} at {}.", first_exp, self.tok.loc())
-                                        }
-                                    }
-                                    Token::Id(k) => {
-                                        let key = k.to_string();
-
-                                        self.tok.consume();
-                                        self.tok.consume_expect(&Token::Sym(Colon));
-
-                                        let exp = self.parse_exp(true);
-                                        result.insert(key, exp);
-
-                                        self.tok.consume_expect(&Token::Sym(Comma));
-                                    }
-                                    &Token::Sym(RightBrace) => {
-                                        break;
-                                    }
-                                    _ => {
-                                        panic!("Expected struct field name or '}}' but got {:?} at {}", token, self.tok.loc())
-                                    }
-                                }
+                            let key = if let AstNode::Identifier(key) = first_exp {
+                                key
+                            } else {
+                                fail!("Expecting identifier for first entry in struct but got {:?} at {}.", first_exp, self.tok.loc())
                             };
+                            self.tok.consume_expect(&Token::Sym(Colon))?;
+
+                            // the type is parsed without '|' first, since a '|' after it separates variants
+                            let first_type = self.parse_variant_type()?;
+                            if let Some(Token::Sym(Bar)) = self.tok.peek() {
+                                return self.parse_union_type_rest((key, first_type));
+                            }
+                            let first_field = (key, self.make_binary(first_type, 0)?);
 
-                            self.tok.consume_expect(&Token::Sym(RightBrace));
-                            return AstNode::Literal(Literal::StructType(result));
+                            let fields = self.parse_list_rest(first_field, RightBrace, |p| p.parse_field(Colon))?;
+                            self.check_duplicate_fields(&fields.iter().map(|(name, _)| name).collect::<Vec<&String>>())?;
+                            AstNode::Literal(Literal::StructType(fields))
+                        }
+                        // any other assignment operator means code block starting with an assignment
+                        //    e.g. { x += 2; }
+                        Some(Token::Sym(op)) if OP_PRECEDENCE.contains_key(op) => {
+                            let first_stmt = self.make_binary(first_exp, 0)?;
+                            self.tok.consume_expect(&Token::Sym(Semicolon))?;
+                            self.parse_started_block(Statement {
+                                node: first_stmt,
+                                loc: first_loc,
+                            })?
                         }
                         // semicolon means code block
                         //    e.g. { statement(); }
                         // first_exp-^^^^^^^^^^ ^- semicolon we just peeked
                         &Some(Token::Sym(Semicolon)) => {
-                            self.tok.consume_expect(&Token::Sym(Semicolon));
-                            return self.parse_started_block(first_exp);
+                            self.tok.consume_expect(&Token::Sym(Semicolon))?;
+                            self.parse_started_block(Statement {
+                                node: first_exp,
+                                loc: first_loc,
+                            })?
                         }
                         Some(tok) => {
-                            panic!("Expected ':', ';', or expression but got {:?} at {}", tok, self.tok.loc())
+                            fail!("Expected ':', ';', or expression but got {:?} at {}", tok, self.tok.loc())
                         }
                         None => {
-                            panic!("Expected ':', ';', or expression but got EOF.");
+                            fail!("Expected ':', ';', or expression but got EOF.");
                         }
-                    };
-                }
-                // unary
-                &Token::Sym(Bang) => {
-                    self.tok.consume_expect(&Token::Sym(Bang));
-                    AstNode::Unary {
-                        op: Bang,
-                        operand: Box::from(self.parse_exp_atom()),
-                    }
-                }
-                &Token::Kw(Typeof) => {
-                    self.tok.consume_expect(&Token::Kw(Typeof));
-                    AstNode::Typeof {
-                        operand: Box::from(self.parse_exp(true))
                     }
                 }
                 &Token::Kw(If) => {
-                    panic!("'if' is not yet implemented.")
+                    fail!("'if' is not yet implemented.")
+                }
+                &Token::Kw(When) => {
+                    self.tok.consume_expect(&Token::Kw(When))?;
+                    let subject = self.parse_exp()?;
+
+                    self.tok.consume_expect(&Token::Sym(LeftBrace))?;
+                    self.parse_when_arms(subject)?
                 }
                 // declaration
                 &Token::Kw(kw) if kw == Const || kw == Let => {
-                    self.tok.consume();
-                    if let Some(Token::Id(id)) = self.tok.consume() {
-                        self.tok.consume_expect(&Token::Sym(Equal));
-                        return AstNode::Declaration {
-                            op: kw,
-                            id,
-                            value: Box::from(self.parse_exp(true)),
-                        };
-                    } else {
-                        panic!("Expecting identifier but got {:?} at {}", self.tok.peek(), self.tok.loc())
+                    self.tok.consume()?;
+
+                    let pattern = self.parse_pattern()?;
+                    let names = pattern.names();
+                    for (i, name) in names.iter().enumerate() {
+                        if names[..i].contains(name) {
+                            fail!("Duplicate binding {} in declaration at {}.", name, self.tok.loc())
+                        }
+                    }
+
+                    self.tok.consume_expect(&Token::Sym(Equal))?;
+                    AstNode::Declaration {
+                        op: kw,
+                        pattern,
+                        value: Box::from(self.parse_exp()?),
+                    }
+                }
+                &Token::Kw(Impl) => {
+                    self.tok.consume_expect(&Token::Kw(Impl))?;
+                    let target = self.parse_exp()?;
+
+                    self.tok.consume_expect(&Token::Sym(LeftBrace))?;
+                    let methods = self.parse_list(RightBrace, |p| p.parse_field(Equal))?;
+                    self.check_duplicate_fields(&methods.iter().map(|(name, _)| name).collect::<Vec<&String>>())?;
+
+                    AstNode::Impl {
+                        target: Box::from(target),
+                        methods,
                     }
                 }
                 // control flow
                 &Token::Kw(op) if op == Return || op == Continue || op == Break => {
-                    self.tok.consume();
+                    self.tok.consume()?;
                     let next = if let Some(Token::Sym(Semicolon)) = self.tok.peek() {
                         // return without value implicitly returns unit
                         AstNode::Literal(Literal::Unit)
                     } else {
-                        self.parse_exp(true)
+                        self.parse_exp()?
                     };
-                    return AstNode::Jump {
+                    AstNode::Jump {
                         op,
                         result: Box::from(next),
-                    };
+                    }
                 }
                 // int literal
                 &Token::Int(n) => {
-                    self.tok.consume_expect(&Token::Int(n));
+                    self.tok.consume_expect(&Token::Int(n))?;
                     AstNode::Literal(Literal::Int(n))
                 }
+                // string literal
+                Token::Str(s) => {
+                    let s = s.clone();
+                    self.tok.consume_expect(&Token::Str(s.clone()))?;
+                    AstNode::Literal(Literal::String(s))
+                }
                 // double literal
                 &Token::Double(d) => {
-                    self.tok.consume_expect(&Token::Double(d));
+                    self.tok.consume_expect(&Token::Double(d))?;
                     AstNode::Literal(Literal::Double(d))
                 }
                 // built-in constants
                 Token::Id(_) => {
-                    if let Some(Token::Id(id)) = self.tok.consume() {
+                    if let Some(Token::Id(id)) = self.tok.consume()? {
                         match id.as_str() {
                             "true" => {
                                 AstNode::Literal(Literal::Bool(true))
@@ -476,145 +548,330 @@ impl Parser {
                             }
                         }
                     } else {
-                        panic!("Expecting identifier but got {:?} at {}", self.tok.peek(), self.tok.loc())
+                        fail!("Expecting identifier but got {:?} at {}", self.tok.peek(), self.tok.loc())
                     }
                 }
                 t => {
-                    panic!("Unexpected token: {:?} at {}", t, self.tok.loc())
+                    fail!("Unexpected token: {:?} at {}", t, self.tok.loc())
                 }
-            }
+            })
         } else {
-            panic!("Unexpected EOF.")
+            fail!("Unexpected EOF.")
         }
     }
 
-    fn make_binary(&mut self, lhs: AstNode, precedence: u8) -> AstNode {
-        if let &Some(Token::Sym(op)) = self.tok.peek() {
-            if !OP_PRECEDENCE.contains_key(&op) {
-                // not a binary op, just return lhs
-                return lhs;
-            }
+    // precedence climbing: fold operators binding at least as tightly as min_precedence into lhs
+    fn make_binary(&mut self, lhs: AstNode, min_precedence: u8) -> Result<AstNode, CompileError> {
+        let mut lhs = lhs;
 
-            let next_precedence = OP_PRECEDENCE[&op];
-            if next_precedence > precedence {
-                self.tok.consume();
-                let rhs_exp = self.parse_exp(true);
-                let rhs = self.make_binary(rhs_exp, next_precedence);
+        while let &Some(Token::Sym(op)) = self.tok.peek() {
+            let (precedence, assoc) = match OP_PRECEDENCE.get(&op) {
+                Some(&entry) => entry,
+                // not a binary op, so the expression ends here
+                None => break,
+            };
 
-                let binary = AstNode::Binary {
-                    lhs: Box::from(lhs),
-                    rhs: Box::from(rhs),
-                    op,
-                };
-
-                return self.make_binary(binary, precedence);
+            if precedence < min_precedence {
+                break;
             }
-        }
-
-        lhs
-    }
 
-    fn make_call(&mut self, target: AstNode) -> AstNode {
-        let mut args: Vec<AstNode> = Vec::new();
-        self.tok.consume_expect(&Token::Sym(LeftParen));
+            self.tok.consume()?;
 
-        while let Some(token) = self.tok.peek() {
-            if token == &Token::Sym(RightParen) {
-                break;
-            }
+            // a right-associative operator accepts another of itself on its right-hand side
+            let rhs_precedence = match assoc {
+                Associativity::Left => precedence + 1,
+                Associativity::Right => precedence,
+            };
 
-            args.push(self.parse_exp(true));
+            let rhs_operand = self.parse_unary()?;
+            let rhs = self.make_binary(rhs_operand, rhs_precedence)?;
 
-            self.tok.consume_expect(&Token::Sym(Comma));
+            lhs = AstNode::Binary {
+                lhs: Box::from(lhs),
+                op,
+                rhs: Box::from(rhs),
+            };
         }
 
-        self.tok.consume_expect(&Token::Sym(RightParen));
+        Ok(lhs)
+    }
 
-        AstNode::Call {
+    fn make_call(&mut self, target: AstNode) -> Result<AstNode, CompileError> {
+        self.tok.consume_expect(&Token::Sym(LeftParen))?;
+        let args = self.parse_list(RightParen, |p| p.parse_exp())?;
+
+        Ok(AstNode::Call {
             arguments: args,
             target: Box::from(target),
-        }
+        })
     }
 
-    fn make_dot_access(&mut self, target: AstNode) -> AstNode {
-        self.tok.consume_expect(&Token::Sym(Dot));
+    fn make_dot_access(&mut self, target: AstNode) -> Result<AstNode, CompileError> {
+        self.tok.consume_expect(&Token::Sym(Dot))?;
         if let Some(Token::Id(f)) = self.tok.peek() {
             let field = f.clone();
-            self.tok.consume_expect(&Token::Id(field.to_string()));
-            Access {
+            self.tok.consume_expect(&Token::Id(field.to_string()))?;
+            Ok(Access {
                 target: Box::from(target),
                 field,
-            }
+            })
         } else {
-            panic!("Expected field identifier but got {:?} at {}", self.tok.peek(), self.tok.loc());
+            fail!("Expected field identifier but got {:?} at {}", self.tok.peek(), self.tok.loc());
         }
     }
 
-    fn make_bracket_access(&mut self, _target: AstNode) -> AstNode {
-        panic!("Bracket access is not is not yet implemented.")
+    fn make_bracket_access(&mut self, target: AstNode) -> Result<AstNode, CompileError> {
+        self.tok.consume_expect(&Token::Sym(LeftBracket))?;
+        let index = self.parse_exp()?;
+        self.tok.consume_expect(&Token::Sym(RightBracket))?;
+
+        Ok(AstNode::Index {
+            target: Box::from(target),
+            index: Box::from(index),
+        })
+    }
+
+    fn parse_pattern(&mut self) -> Result<Pattern, CompileError> {
+        Ok(match self.tok.consume()? {
+            Some(Token::Id(name)) => Pattern::Identifier(name),
+            Some(Token::Sym(LeftBrace)) => {
+                let fields = self.parse_list(RightBrace, |p| p.parse_field_pattern())?;
+                self.check_duplicate_fields(&fields.iter().map(|(name, _)| name).collect::<Vec<&String>>())?;
+                Pattern::Struct(fields)
+            }
+            Some(Token::Sym(LeftBracket)) => Pattern::List(self.parse_list(RightBracket, |p| p.parse_pattern())?),
+            t => fail!("Expected identifier, '{{' or '[' but got {:?} at {}", t, self.tok.loc()),
+        })
+    }
+
+    // a field in a struct pattern, e.g. x or x = px
+    fn parse_field_pattern(&mut self) -> Result<(String, Pattern), CompileError> {
+        if let Some(Token::Id(name)) = self.tok.consume()? {
+            if let Some(Token::Sym(Equal)) = self.tok.peek() {
+                self.tok.consume_expect(&Token::Sym(Equal))?;
+                Ok((name, self.parse_pattern()?))
+            } else {
+                Ok((name.clone(), Pattern::Identifier(name)))
+            }
+        } else {
+            fail!("Expected field name but got {:?} at {}", self.tok.peek(), self.tok.loc())
+        }
     }
 
     // the parser should have already consumed the left-paren
     // if first_param_name is present, that should have been consumed as well (but not the colon after it)
-    fn parse_fn_literal(&mut self, first_param_name: Option<String>) -> AstNode {
-        let mut param_names: Vec<String> = Vec::new();
-        let mut param_types: Vec<AstNode> = Vec::new();
-
-        // if first parameter is provided, handle first type exp as well
-        if let Some(name) = first_param_name {
-            param_names.push(name.clone());
-            self.tok.consume_expect(&Token::Sym(Colon));
-            let first_type_exp = self.parse_exp(true);
-            param_types.push(first_type_exp);
-            self.tok.consume_expect(&Token::Sym(Comma));
+    fn parse_fn_literal(&mut self, first_param_name: Option<String>) -> Result<AstNode, CompileError> {
+        let params = match first_param_name {
+            // if first parameter is provided, handle first type exp as well
+            Some(name) => {
+                self.tok.consume_expect(&Token::Sym(Colon))?;
+                let first_type_exp = self.parse_exp()?;
+                self.parse_list_rest((name, first_type_exp), RightParen, |p| p.parse_field(Colon))?
+            }
+            None => self.parse_list(RightParen, |p| p.parse_field(Colon))?,
+        };
+
+        let (param_names, param_types) = params.into_iter().unzip();
+
+        self.tok.consume_expect(&Token::Sym(EqualGreater))?;
+
+        let body = self.parse_block()?;
+
+        Ok(AstNode::Literal(Literal::Fn {
+            param_names,
+            param_types,
+            body: Box::from(body),
+        }))
+    }
+
+    // parse a name and the expression after it, separated by sep, e.g. x: Int in a parameter list
+    fn parse_field(&mut self, sep: Symbol) -> Result<(String, AstNode), CompileError> {
+        if let Some(Token::Id(name)) = self.tok.consume()? {
+            self.tok.consume_expect(&Token::Sym(sep))?;
+            Ok((name, self.parse_exp()?))
+        } else {
+            fail!("Expected name before {:?} but got {:?} at {}", sep, self.tok.peek(), self.tok.loc())
         }
+    }
 
-        loop {
-            if let Some(Token::Sym(RightParen)) = self.tok.peek() {
-                break;
-            }
+    // parse the type of a variant in a union type literal, which stops before any '|'
+    fn parse_variant_type(&mut self) -> Result<AstNode, CompileError> {
+        let (bar_precedence, _) = OP_PRECEDENCE[&Bar];
+        let operand = self.parse_unary()?;
+        self.make_binary(operand, bar_precedence + 1)
+    }
 
-            if let Some(Token::Id(param_name)) = self.tok.peek() {
-                param_names.push(param_name.clone());
-                self.tok.consume(); // consume id
-                self.tok.consume_expect(&Token::Sym(Colon));
-                let param_type = self.parse_exp(true);
-                param_types.push(param_type);
+    // parse the variants of a union type literal after the first, up to and including the closing brace
+    fn parse_union_type_rest(&mut self, first_variant: (String, AstNode)) -> Result<AstNode, CompileError> {
+        let mut variants = vec![first_variant];
 
-                self.tok.consume_expect(&Token::Sym(Comma));
-            } else {
-                panic!("Expecting parameter name but got {:?} at {}", self.tok.peek(), self.tok.loc())
+        while let Some(Token::Sym(Bar)) = self.tok.peek() {
+            self.tok.consume_expect(&Token::Sym(Bar))?;
+
+            let tag = match self.tok.consume()? {
+                Some(Token::Id(tag)) => tag,
+                t => fail!("Expected variant name but got {:?} at {}", t, self.tok.loc()),
+            };
+            if variants.iter().any(|(existing, _)| *existing == tag) {
+                fail!("Duplicate variant {} in union ending at {}.", tag, self.tok.loc())
             }
+
+            self.tok.consume_expect(&Token::Sym(Colon))?;
+            variants.push((tag, self.parse_variant_type()?));
         }
 
-        self.tok.consume_expect(&Token::Sym(RightParen));
+        self.tok.consume_expect(&Token::Sym(RightBrace))?;
 
-        // =>
-        self.tok.consume_expect(&Token::Sym(Equal));
-        self.tok.consume_expect(&Token::Sym(Greater));
+        Ok(AstNode::Literal(Literal::UnionType(variants)))
+    }
 
-        let body = self.parse_block();
+    // parse the arms of a when, up to and including the closing brace. an else arm must come last.
+    fn parse_when_arms(&mut self, subject: AstNode) -> Result<AstNode, CompileError> {
+        let arms = self.parse_list(RightBrace, |p| {
+            let tag = match p.tok.consume()? {
+                Some(Token::Id(tag)) => Some(tag),
+                Some(Token::Kw(Else)) => None,
+                t => fail!("Expected variant name or 'else' but got {:?} at {}", t, p.tok.loc()),
+            };
+
+            // the value of the variant is only bound if there is a pattern for it
+            //    e.g. some(x) => x
+            let pattern = match (p.tok.peek(), &tag) {
+                (Some(Token::Sym(LeftParen)), Some(_)) => {
+                    p.tok.consume_expect(&Token::Sym(LeftParen))?;
+                    let pattern = p.parse_pattern()?;
+                    p.tok.consume_expect(&Token::Sym(RightParen))?;
+                    Some(pattern)
+                }
+                _ => None,
+            };
 
-        AstNode::Literal(Literal::Fn {
-            param_names,
-            body: Box::from(body),
+            p.tok.consume_expect(&Token::Sym(EqualGreater))?;
+            Ok((tag, pattern, p.parse_exp()?))
+        })?;
+
+        let mut when_arms = Vec::new();
+        let mut else_body = None;
+
+        for (tag, pattern, body) in arms {
+            if else_body.is_some() {
+                fail!("'else' must be the last arm of when at {}.", self.tok.loc())
+            }
+
+            match tag {
+                Some(tag) => {
+                    if when_arms.iter().any(|arm: &WhenArm| arm.tag == tag) {
+                        fail!("Duplicate arm {} in when at {}.", tag, self.tok.loc())
+                    }
+
+                    when_arms.push(WhenArm { tag, pattern, body });
+                }
+                None => else_body = Some(Box::from(body)),
+            }
+        }
+
+        Ok(AstNode::When {
+            subject: Box::from(subject),
+            arms: when_arms,
+            else_body,
         })
     }
 
-    fn parse_block(&mut self) -> AstNode {
-        self.tok.consume_expect(&Token::Sym(LeftBrace));
+    // parse the entries of a struct literal after the first, up to and including the closing brace
+    fn parse_struct_literal_rest(&mut self, first_entry: StructEntry) -> Result<AstNode, CompileError> {
+        let entries = self.parse_list_rest(first_entry, RightBrace, |p| p.parse_struct_entry())?;
 
-        let first_stmt = self.parse_exp(true);
-        self.tok.consume_expect(&Token::Sym(Semicolon));
+        let fields: Vec<&String> = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                StructEntry::Field(name, _) => Some(name),
+                StructEntry::Spread(_) => None,
+            })
+            .collect();
+        self.check_duplicate_fields(&fields)?;
 
-        return self.parse_started_block(first_stmt);
+        Ok(AstNode::Literal(Literal::Struct(entries)))
+    }
+
+    fn parse_struct_entry(&mut self) -> Result<StructEntry, CompileError> {
+        if let Some(Token::Sym(DotDotDot)) = self.tok.peek() {
+            self.tok.consume_expect(&Token::Sym(DotDotDot))?;
+            Ok(StructEntry::Spread(self.parse_exp()?))
+        } else {
+            let (name, value) = self.parse_field(Equal)?;
+            Ok(StructEntry::Field(name, value))
+        }
+    }
+
+    fn check_duplicate_fields(&self, fields: &[&String]) -> Result<(), CompileError> {
+        for (i, name) in fields.iter().enumerate() {
+            if fields[..i].contains(name) {
+                fail!("Duplicate field {} in struct ending at {}.", name, self.tok.loc())
+            }
+        }
+
+        Ok(())
+    }
+
+    // parse a comma-separated list up to and including the closing symbol end.
+    // the opening symbol should have already been consumed. a trailing comma is allowed.
+    fn parse_list<T>(
+        &mut self,
+        end: Symbol,
+        parse_item: impl FnMut(&mut Parser) -> Result<T, CompileError>,
+    ) -> Result<Vec<T>, CompileError> {
+        if self.tok.peek() == &Some(Token::Sym(end)) {
+            self.tok.consume_expect(&Token::Sym(end))?;
+            return Ok(Vec::new());
+        }
+
+        let mut parse_item = parse_item;
+        let first = parse_item(self)?;
+        self.parse_list_rest(first, end, parse_item)
+    }
+
+    // like parse_list, but with the first item already parsed
+    fn parse_list_rest<T>(
+        &mut self,
+        first: T,
+        end: Symbol,
+        mut parse_item: impl FnMut(&mut Parser) -> Result<T, CompileError>,
+    ) -> Result<Vec<T>, CompileError> {
+        let mut items = vec![first];
+
+        while let Some(Token::Sym(Comma)) = self.tok.peek() {
+            self.tok.consume_expect(&Token::Sym(Comma))?;
+
+            if self.tok.peek() == &Some(Token::Sym(end)) {
+                break;
+            }
+
+            items.push(parse_item(self)?);
+        }
+
+        self.tok.consume_expect(&Token::Sym(end))?;
+
+        Ok(items)
+    }
+
+    fn parse_block(&mut self) -> Result<AstNode, CompileError> {
+        self.tok.consume_expect(&Token::Sym(LeftBrace))?;
+
+        let loc = self.tok.token_loc();
+        let first_stmt = self.parse_exp()?;
+        self.tok.consume_expect(&Token::Sym(Semicolon))?;
+
+        self.parse_started_block(Statement {
+            node: first_stmt,
+            loc,
+        })
     }
 
     // parses block with first brace, statement, and semicolon already consumed.
     // only uniquely used in parse_exp_atom, but made a function to factor out common code.
     // see &Token::Sym(LeftBrace) match entry in parse_exp_atom
-    fn parse_started_block(&mut self, first_stmt: AstNode) -> AstNode {
-        let mut body: Vec<AstNode> = Vec::new();
+    fn parse_started_block(&mut self, first_stmt: Statement) -> Result<AstNode, CompileError> {
+        let mut body: Vec<Statement> = Vec::new();
 
         body.push(first_stmt);
 
@@ -623,14 +880,421 @@ impl Parser {
                 break;
             }
 
-            body.push(self.parse_exp(true));
-            self.tok.consume_expect(&Token::Sym(Semicolon));
+            let loc = self.tok.token_loc();
+            body.push(Statement {
+                node: self.parse_exp()?,
+                loc,
+            });
+            self.tok.consume_expect(&Token::Sym(Semicolon))?;
         }
 
-        self.tok.consume_expect(&Token::Sym(RightBrace));
+        self.tok.consume_expect(&Token::Sym(RightBrace))?;
 
-        AstNode::Block {
+        Ok(AstNode::Block {
             body,
+        })
+    }
+}
+
+// parse a whole program
+pub fn parse(input: InputStream) -> Result<Vec<Statement>, CompileError> {
+    Parser::new(Tokenizer::new(input)?).parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn parse(input: &str) -> Vec<AstNode> {
+        parse_statements(input).into_iter().map(|statement| statement.node).collect()
+    }
+
+    fn parse_statements(input: &str) -> Vec<Statement> {
+        testing::parse(input)
+    }
+
+    // statements compare equal regardless of where they are, so tests can leave the location out
+    fn stmt(node: AstNode) -> Statement {
+        Statement {
+            node,
+            loc: Loc::default(),
+        }
+    }
+
+    fn int(n: i32) -> AstNode {
+        AstNode::Literal(Literal::Int(n))
+    }
+
+    fn id(name: &str) -> AstNode {
+        AstNode::Identifier(name.to_string())
+    }
+
+    fn binary(lhs: AstNode, op: Symbol, rhs: AstNode) -> AstNode {
+        AstNode::Binary {
+            lhs: Box::from(lhs),
+            op,
+            rhs: Box::from(rhs),
+        }
+    }
+
+    #[test]
+    fn arithmetic_precedence() {
+        // compound1 = ((1 + (2 * 3)) + 4)
+        // compound2 = ((1 + 2) - (((3 * 4) / 5) % 6))
+        let program = parse("compound1 = 1 + 2 * 3 + 4;
+compound2 = 1 + 2 - 3 * 4 / 5 % 6;");
+
+        assert_eq!(program, vec![
+            binary(
+                id("compound1"),
+                Equal,
+                binary(binary(int(1), Plus, binary(int(2), Star, int(3))), Plus, int(4)),
+            ),
+            binary(
+                id("compound2"),
+                Equal,
+                binary(
+                    binary(int(1), Plus, int(2)),
+                    Minus,
+                    binary(binary(binary(int(3), Star, int(4)), Slash, int(5)), Percent, int(6)),
+                ),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn assignment_is_right_associative() {
+        assert_eq!(parse("a = b += c = 1;"), vec![
+            binary(id("a"), Equal, binary(id("b"), PlusEqual, binary(id("c"), Equal, int(1)))),
+        ]);
+    }
+
+    #[test]
+    fn logical_and_bitwise_precedence() {
+        // || < && < | < & < ==
+        assert_eq!(parse("a || b && c | d & e == f;"), vec![
+            binary(
+                id("a"),
+                BarBar,
+                binary(
+                    id("b"),
+                    AndAnd,
+                    binary(id("c"), Bar, binary(id("d"), And, binary(id("e"), EqualEqual, id("f")))),
+                ),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn bitwise_and_shift_precedence() {
+        assert_eq!(parse("a | b ^ c & d;"), vec![
+            binary(id("a"), Bar, binary(id("b"), Caret, binary(id("c"), And, id("d")))),
+        ]);
+
+        assert_eq!(parse("1 + 2 << 3 < 4 >> ~5;"), vec![
+            binary(
+                binary(binary(int(1), Plus, int(2)), LessLess, int(3)),
+                Less,
+                binary(int(4), GreaterGreater, AstNode::Unary {
+                    op: Tilde,
+                    operand: Box::from(int(5)),
+                }),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn postfix_binds_tighter_than_binary() {
+        let call = AstNode::Call {
+            target: Box::from(id("f")),
+            arguments: vec![int(1)],
+        };
+        let access = AstNode::Access {
+            target: Box::from(call.clone()),
+            field: "y".to_string(),
+        };
+
+        assert_eq!(parse("x = 2 * f(1,).y + f(1,);"), vec![
+            binary(id("x"), Equal, binary(binary(int(2), Star, access), Plus, call)),
+        ]);
+    }
+
+    #[test]
+    fn prefix_binds_tighter_than_binary() {
+        let not = |operand| AstNode::Unary {
+            op: Bang,
+            operand: Box::from(operand),
+        };
+
+        assert_eq!(parse("!a || !b.c == typeof d;"), vec![
+            binary(
+                not(id("a")),
+                BarBar,
+                binary(
+                    not(AstNode::Access {
+                        target: Box::from(id("b")),
+                        field: "c".to_string(),
+                    }),
+                    EqualEqual,
+                    AstNode::Typeof {
+                        operand: Box::from(id("d")),
+                    },
+                ),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn optional_trailing_commas() {
+        let call = |arguments| AstNode::Call {
+            target: Box::from(id("f")),
+            arguments,
+        };
+
+        assert_eq!(parse("f(); f(3); f(3,); f(3, 4); f(3, 4,);"), vec![
+            call(vec![]),
+            call(vec![int(3)]),
+            call(vec![int(3)]),
+            call(vec![int(3), int(4)]),
+            call(vec![int(3), int(4)]),
+        ]);
+
+        let fn_literal = |param_names: Vec<&str>| AstNode::Literal(Literal::Fn {
+            param_names: param_names.iter().map(|name| name.to_string()).collect(),
+            param_types: param_names.iter().map(|_| id("Int")).collect(),
+            body: Box::from(AstNode::Block { body: vec![stmt(int(1))] }),
+        });
+
+        assert_eq!(parse("(x: Int) => { 1; }; (x: Int,) => { 1; }; (x: Int, y: Int) => { 1; };"), vec![
+            fn_literal(vec!["x"]),
+            fn_literal(vec!["x"]),
+            fn_literal(vec!["x", "y"]),
+        ]);
+
+        let fields = || vec![("x".to_string(), int(1)), ("y".to_string(), int(2))];
+        let entries = || vec![
+            StructEntry::Field("x".to_string(), int(1)),
+            StructEntry::Field("y".to_string(), int(2)),
+        ];
+
+        assert_eq!(parse("{ x = 1, y = 2 }; { x = 1, y = 2, }; { x: 1, y: 2 }; { x: 1, y: 2, };"), vec![
+            AstNode::Literal(Literal::Struct(entries())),
+            AstNode::Literal(Literal::Struct(entries())),
+            AstNode::Literal(Literal::StructType(fields())),
+            AstNode::Literal(Literal::StructType(fields())),
+        ]);
+    }
+
+    #[test]
+    fn struct_fields_keep_source_order() {
+        let fields = vec![("y".to_string(), int(1)), ("x".to_string(), int(2))];
+        let entries = fields
+            .iter()
+            .map(|(name, value)| StructEntry::Field(name.clone(), value.clone()))
+            .collect();
+
+        assert_eq!(parse("{ y = 1, x = 2 };"), vec![AstNode::Literal(Literal::Struct(entries))]);
+        assert_eq!(parse("{ y: 1, x: 2 };"), vec![AstNode::Literal(Literal::StructType(fields))]);
+    }
+
+    #[test]
+    fn struct_spread() {
+        assert_eq!(parse("{ ...a, x = 1, ...b.c };"), vec![
+            AstNode::Literal(Literal::Struct(vec![
+                StructEntry::Spread(id("a")),
+                StructEntry::Field("x".to_string(), int(1)),
+                StructEntry::Spread(AstNode::Access {
+                    target: Box::from(id("b")),
+                    field: "c".to_string(),
+                }),
+            ])),
+        ]);
+
+        // a spread can override fields, but fields written out still can't repeat each other
+        parse("{ x = 1, ...a, y = 2 };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate field x in struct")]
+    fn duplicate_struct_field_around_spread() {
+        parse("{ x = 1, ...a, x = 2 };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate field x in struct")]
+    fn duplicate_struct_field() {
+        parse("{ x = 1, y = 2, x = 3 };");
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate field x in struct")]
+    fn duplicate_struct_type_field() {
+        parse("{ x: Int, x: Double };");
+    }
+
+    #[test]
+    fn fn_types() {
+        let fn_type = |param_types| AstNode::Literal(Literal::FnType {
+            param_types,
+            returns: Box::from(id("Int")),
+        });
+
+        assert_eq!(parse("(Int,) => Int; (Int, Int) => Int; (Int, Int,) => Int; (Int);"), vec![
+            fn_type(vec![id("Int")]),
+            fn_type(vec![id("Int"), id("Int")]),
+            fn_type(vec![id("Int"), id("Int")]),
+            id("Int"),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected ',' after the parameter type of a single-parameter function type")]
+    fn single_param_fn_type_needs_comma() {
+        parse("(Int) => Int;");
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(parse("[1, 2,][0];"), vec![
+            AstNode::Index {
+                target: Box::from(AstNode::Literal(Literal::List(vec![int(1), int(2)]))),
+                index: Box::from(int(0)),
+            },
+        ]);
+    }
+
+    #[test]
+    fn propagate() {
+        assert_eq!(parse("!f()?.ok;"), vec![
+            AstNode::Unary {
+                op: Bang,
+                operand: Box::from(Access {
+                    target: Box::from(AstNode::Propagate {
+                        operand: Box::from(AstNode::Call {
+                            target: Box::from(id("f")),
+                            arguments: vec![],
+                        }),
+                    }),
+                    field: "ok".to_string(),
+                }),
+            },
+        ]);
+    }
+
+    #[test]
+    fn destructuring_declarations() {
+        let declaration = |pattern| AstNode::Declaration {
+            op: Let,
+            pattern,
+            value: Box::from(id("v")),
+        };
+        let bind = |name: &str| Pattern::Identifier(name.to_string());
+
+        assert_eq!(parse("let { x, y = py, } = v; let [a, b] = v; let { pos = [{ x }, z], } = v;"), vec![
+            declaration(Pattern::Struct(vec![("x".to_string(), bind("x")), ("y".to_string(), bind("py"))])),
+            declaration(Pattern::List(vec![bind("a"), bind("b")])),
+            declaration(Pattern::Struct(vec![(
+                "pos".to_string(),
+                Pattern::List(vec![Pattern::Struct(vec![("x".to_string(), bind("x"))]), bind("z")]),
+            )])),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate binding x in declaration")]
+    fn duplicate_binding_in_pattern() {
+        parse("let { x, y = [x] } = v;");
+    }
+
+    #[test]
+    fn impl_blocks() {
+        let method = AstNode::Literal(Literal::Fn {
+            param_names: vec!["self".to_string()],
+            param_types: vec![id("Point")],
+            body: Box::from(AstNode::Block { body: vec![stmt(id("self"))] }),
+        });
+
+        assert_eq!(parse("impl Point { id = (self: Point) => { self; }, };"), vec![
+            AstNode::Impl {
+                target: Box::from(id("Point")),
+                methods: vec![("id".to_string(), method)],
+            },
+        ]);
+    }
+
+    #[test]
+    fn union_types() {
+        assert_eq!(parse("{ some: Int | none: Unit }; { x: (a | b), y: a | b };"), vec![
+            AstNode::Literal(Literal::UnionType(vec![
+                ("some".to_string(), id("Int")),
+                ("none".to_string(), id("Unit")),
+            ])),
+            AstNode::Literal(Literal::StructType(vec![
+                ("x".to_string(), binary(id("a"), Bar, id("b"))),
+                ("y".to_string(), binary(id("a"), Bar, id("b"))),
+            ])),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate variant a in union")]
+    fn duplicate_variant() {
+        parse("{ a: Int | b: Int | a: Unit };");
+    }
+
+    #[test]
+    fn when() {
+        assert_eq!(parse("when x { some(y) => y, none => 0, else => 1 };"), vec![
+            AstNode::When {
+                subject: Box::from(id("x")),
+                arms: vec![
+                    WhenArm {
+                        tag: "some".to_string(),
+                        pattern: Some(Pattern::Identifier("y".to_string())),
+                        body: id("y"),
+                    },
+                    WhenArm {
+                        tag: "none".to_string(),
+                        pattern: None,
+                        body: int(0),
+                    },
+                ],
+                else_body: Some(Box::from(int(1))),
+            },
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "'else' must be the last arm of when")]
+    fn when_else_must_be_last() {
+        parse("when x { else => 1, none => 0 };");
+    }
+
+    #[test]
+    fn block_starting_with_assignment() {
+        assert_eq!(parse("{ x = 1; y += x; };"), vec![
+            AstNode::Block {
+                body: vec![
+                    stmt(binary(id("x"), Equal, int(1))),
+                    stmt(binary(id("y"), PlusEqual, id("x"))),
+                ],
+            },
+        ]);
+    }
+
+    #[test]
+    fn statement_locations() {
+        let program = parse_statements("let x = 1;\n  {\n    x;\n    x = 2; };");
+        let locs: Vec<Loc> = program.iter().map(|statement| statement.loc).collect();
+        assert_eq!(locs, vec![Loc { line: 1, col: 1 }, Loc { line: 2, col: 3 }]);
+
+        match &program[1].node {
+            AstNode::Block { body } => {
+                let locs: Vec<Loc> = body.iter().map(|statement| statement.loc).collect();
+                assert_eq!(locs, vec![Loc { line: 3, col: 5 }, Loc { line: 4, col: 5 }]);
+            }
+            node => panic!("Expected block but got {:?}.", node),
         }
     }
 }
@@ -0,0 +1,433 @@
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::error::{ErrorKind, RuntimeError};
+use crate::types::{Fields, Type};
+use crate::vm::{InternalVal, KytheraVal, NativeFn, Vm};
+
+// a function implemented in Rust that every program can use, along with its Kythera type
+pub struct Builtin {
+    pub name: &'static str,
+    pub type_val: Type,
+    pub function: NativeFn,
+}
+
+fn builtin<F>(name: &'static str, params: Vec<Type>, returns: Type, function: F) -> Builtin
+where
+    F: Fn(&mut Vm, Vec<KytheraVal>) -> Result<KytheraVal, RuntimeError> + 'static,
+{
+    Builtin {
+        name,
+        type_val: Type::Fn {
+            params,
+            returns: Box::from(returns),
+        },
+        function: Rc::new(function),
+    }
+}
+
+fn var(name: &str) -> Type {
+    Type::Var(name.to_string())
+}
+
+fn type_param(name: &str) -> Type {
+    Type::TypeParam(name.to_string())
+}
+
+fn list_of(element_type: Type) -> Type {
+    Type::List(Box::from(element_type))
+}
+
+fn fn_of(params: Vec<Type>, returns: Type) -> Type {
+    Type::Fn {
+        params,
+        returns: Box::from(returns),
+    }
+}
+
+// a list holding the same type of elements as list
+fn list_like(list: &KytheraVal, elements: Vec<KytheraVal>) -> KytheraVal {
+    KytheraVal {
+        val: InternalVal::List(Rc::new(elements)),
+        type_val: Rc::clone(&list.type_val),
+    }
+}
+
+fn native_error(message: String) -> RuntimeError {
+    ErrorKind::Native(message).into()
+}
+
+fn parse_result<T>(s: &str, ok_type: Type, parsed: Option<T>, make: fn(T) -> KytheraVal) -> KytheraVal {
+    let result_type = Type::result(ok_type.clone(), Type::String);
+
+    match parsed {
+        Some(val) => KytheraVal::variant(result_type, "ok".to_string(), make(val)),
+        None => KytheraVal::variant(
+            result_type,
+            "err".to_string(),
+            KytheraVal::string(format!("Could not parse {} as {}.", s, ok_type)),
+        ),
+    }
+}
+
+// functions available as globals
+pub fn functions() -> Vec<Builtin> {
+    vec![
+        // conversions and output
+        builtin("print", vec![Type::String], Type::Unit, |vm, args| {
+            vm.write_output(args[0].as_str()?)
+                .map_err(|e| native_error(format!("Could not print: {}.", e)))?;
+            Ok(KytheraVal::unit())
+        }),
+        builtin("println", vec![Type::String], Type::Unit, |vm, args| {
+            vm.write_output(&format!("{}\n", args[0].as_str()?))
+                .map_err(|e| native_error(format!("Could not print: {}.", e)))?;
+            Ok(KytheraVal::unit())
+        }),
+        builtin("toString", vec![type_param("T"), var("T")], Type::String, |_, args| {
+            Ok(KytheraVal::string(args[1].to_string()))
+        }),
+        builtin("parseInt", vec![Type::String], Type::result(Type::Int, Type::String), |_, args| {
+            let s = args[0].as_str()?;
+            Ok(parse_result(s, Type::Int, s.trim().parse().ok(), KytheraVal::int))
+        }),
+        builtin("parseDouble", vec![Type::String], Type::result(Type::Double, Type::String), |_, args| {
+            let s = args[0].as_str()?;
+            Ok(parse_result(s, Type::Double, s.trim().parse().ok(), KytheraVal::double))
+        }),
+        // math
+        builtin("abs", vec![Type::Int], Type::Int, |_, args| {
+            let n = args[0].as_int()?.checked_abs().ok_or(ErrorKind::IntegerOverflow)?;
+            Ok(KytheraVal::int(n))
+        }),
+        builtin("min", vec![Type::Int, Type::Int], Type::Int, |_, args| {
+            Ok(KytheraVal::int(args[0].as_int()?.min(args[1].as_int()?)))
+        }),
+        builtin("max", vec![Type::Int, Type::Int], Type::Int, |_, args| {
+            Ok(KytheraVal::int(args[0].as_int()?.max(args[1].as_int()?)))
+        }),
+        builtin("sqrt", vec![Type::Double], Type::Double, |_, args| {
+            Ok(KytheraVal::double(args[0].as_double()?.sqrt()))
+        }),
+        builtin("floor", vec![Type::Double], Type::Int, |_, args| {
+            let d = args[0].as_double()?.floor();
+
+            // NaN fails both comparisons
+            if d >= f64::from(i32::MIN) && d <= f64::from(i32::MAX) {
+                Ok(KytheraVal::int(d as i32))
+            } else {
+                Err(ErrorKind::IntegerOverflow.into())
+            }
+        }),
+        builtin("pow", vec![Type::Double, Type::Double], Type::Double, |_, args| {
+            Ok(KytheraVal::double(args[0].as_double()?.powf(args[1].as_double()?)))
+        }),
+        // lists, which are never changed in place
+        builtin(
+            "push",
+            vec![type_param("T"), list_of(var("T")), var("T")],
+            list_of(var("T")),
+            |_, args| {
+                let mut elements = args[1].as_list()?.to_vec();
+                elements.push(args[2].clone());
+                Ok(list_like(&args[1], elements))
+            },
+        ),
+        builtin(
+            "pop",
+            vec![type_param("T"), list_of(var("T"))],
+            Type::Struct(
+                vec![("list".to_string(), list_of(var("T"))), ("last".to_string(), var("T"))]
+                    .into_iter()
+                    .collect(),
+            ),
+            |_, args| {
+                let mut elements = args[1].as_list()?.to_vec();
+                let last = elements
+                    .pop()
+                    .ok_or_else(|| native_error("Cannot pop from an empty list.".to_string()))?;
+
+                let fields: Fields<KytheraVal> =
+                    vec![("list".to_string(), list_like(&args[1], elements)), ("last".to_string(), last)]
+                        .into_iter()
+                        .collect();
+                Ok(KytheraVal::structure(fields))
+            },
+        ),
+        builtin(
+            "map",
+            vec![
+                type_param("T"),
+                type_param("U"),
+                list_of(var("T")),
+                fn_of(vec![var("T")], var("U")),
+            ],
+            list_of(var("U")),
+            |vm, args| {
+                let element_type = args[1].as_type()?;
+                let elements = args[2]
+                    .as_list()?
+                    .iter()
+                    .map(|element| vm.call(&args[3], vec![element.clone()]))
+                    .collect::<Result<_, _>>()?;
+
+                Ok(KytheraVal::list(element_type, elements))
+            },
+        ),
+        builtin(
+            "filter",
+            vec![type_param("T"), list_of(var("T")), fn_of(vec![var("T")], Type::Bool)],
+            list_of(var("T")),
+            |vm, args| {
+                let mut elements = Vec::new();
+                for element in args[1].as_list()? {
+                    if vm.call(&args[2], vec![element.clone()])?.as_bool()? {
+                        elements.push(element.clone());
+                    }
+                }
+
+                Ok(list_like(&args[1], elements))
+            },
+        ),
+        builtin(
+            "fold",
+            vec![
+                type_param("T"),
+                type_param("U"),
+                list_of(var("T")),
+                var("U"),
+                fn_of(vec![var("U"), var("T")], var("U")),
+            ],
+            var("U"),
+            |vm, args| {
+                let mut acc = args[3].clone();
+                for element in args[2].as_list()? {
+                    acc = vm.call(&args[4], vec![acc, element.clone()])?;
+                }
+
+                Ok(acc)
+            },
+        ),
+    ]
+}
+
+// the length of s in characters, which is what string indices count
+fn char_len(s: &str) -> Result<i32, ErrorKind> {
+    i32::try_from(s.chars().count()).map_err(|_| ErrorKind::IntegerOverflow)
+}
+
+// functions attached to built-in types, called as methods
+pub fn methods() -> Vec<(Type, Builtin)> {
+    vec![
+        (
+            Type::String,
+            builtin("len", vec![Type::String], Type::Int, |_, args| {
+                Ok(KytheraVal::int(char_len(args[0].as_str()?)?))
+            }),
+        ),
+        (
+            Type::String,
+            builtin("concat", vec![Type::String, Type::String], Type::String, |_, args| {
+                Ok(KytheraVal::string(format!("{}{}", args[0].as_str()?, args[1].as_str()?)))
+            }),
+        ),
+        (
+            Type::String,
+            builtin(
+                "slice",
+                vec![Type::String, Type::Int, Type::Int],
+                Type::String,
+                |_, args| {
+                    let s = args[0].as_str()?;
+                    let (start, end) = (args[1].as_int()?, args[2].as_int()?);
+                    let len = char_len(s)?;
+
+                    // characters from start up to but not including end
+                    let out_of_bounds = |index| ErrorKind::StringIndexOutOfBounds {
+                        index,
+                        len: len as usize,
+                    };
+                    if start < 0 || start > len {
+                        return Err(out_of_bounds(start).into());
+                    }
+                    if end < start || end > len {
+                        return Err(out_of_bounds(end).into());
+                    }
+
+                    let sliced = s.chars().skip(start as usize).take((end - start) as usize).collect();
+                    Ok(KytheraVal::string(sliced))
+                },
+            ),
+        ),
+        (
+            Type::String,
+            builtin(
+                "split",
+                vec![Type::String, Type::String],
+                list_of(Type::String),
+                |_, args| {
+                    let separator = args[1].as_str()?;
+                    if separator.is_empty() {
+                        return Err(native_error("Cannot split on an empty separator.".to_string()));
+                    }
+
+                    let parts = args[0]
+                        .as_str()?
+                        .split(separator)
+                        .map(|part| KytheraVal::string(part.to_string()))
+                        .collect();
+                    Ok(KytheraVal::list(Type::String, parts))
+                },
+            ),
+        ),
+        (
+            Type::String,
+            builtin("contains", vec![Type::String, Type::String], Type::Bool, |_, args| {
+                Ok(KytheraVal::bool(args[0].as_str()?.contains(args[1].as_str()?)))
+            }),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TraceFrame;
+    use crate::input_stream::Loc;
+    use crate::testing::{self, try_run, try_run_on, Output};
+
+    fn run(input: &str) -> String {
+        testing::run(input).to_string()
+    }
+
+    #[test]
+    fn print() {
+        let output = Output::default();
+        let mut vm = Vm::with_output(Box::new(output.clone()));
+        try_run_on(&mut vm, "print(\"a\"); println(\"b\"); println(toString(Int, 1 + 2));").unwrap();
+        assert_eq!(output.printed(), "ab\n3\n");
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(
+            run("[toString(Double, 1.5), toString(List(Int), [1, 2]), toString({ a: Int | b: Unit }, { a: Int | b: Unit }.b)];"),
+            "[1.5, [1, 2], b]"
+        );
+        assert_eq!(
+            run("{ a = parseInt(\" 42\"), b = parseInt(\"4x\"), c = parseDouble(\"2.5\"), d = parseDouble(\"\") };"),
+            "{ a = ok(42), b = err(Could not parse 4x as Int.), c = ok(2.5), d = err(Could not parse  as Double.) }"
+        );
+        assert_eq!(
+            run("const sum = (a: String, b: String) => {
+    return Result(Int, String).ok(parseInt(a)? + parseInt(b)?);
+};
+[sum(\"1\", \"2\"), sum(\"1\", \"two\")];"),
+            "[ok(3), err(Could not parse two as Int.)]"
+        );
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            run("let s = \"a,b,c\";
+{ len = s.len(), parts = s.split(\",\"), has = s.contains(\"b\"), sliced = s.slice(2, 5), joined = s.concat(\"!\") };"),
+            "{ len = 5, parts = [a, b, c], has = true, sliced = b,c, joined = a,b,c! }"
+        );
+        assert_eq!(run("\"héllo\".slice(1, 2);"), "é");
+    }
+
+    #[test]
+    fn math() {
+        assert_eq!(
+            run("[abs(0 - 3), min(2, 5), max(2, 5), floor(2.7), floor(0.0 - 2.5)];"),
+            "[3, 2, 5, 2, -3]"
+        );
+        assert_eq!(run("[sqrt(16.0), pow(2.0, 10.0)];"), "[4.0, 1024.0]");
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            run("let xs = [1, 2, 3, 4];
+let popped = pop(Int, xs);
+{
+    pushed = push(Int, xs, 5),
+    popped = popped,
+    mapped = map(Int, Bool, xs, (x: Int) => { return x > 2; }),
+    filtered = filter(Int, xs, (x: Int) => { return x % 2 == 0; }),
+    folded = fold(Int, String, xs, \"\", (acc: String, x: Int) => { return acc + toString(Int, x); }),
+    original = xs,
+};"),
+            "{ pushed = [1, 2, 3, 4, 5], popped = { list = [1, 2, 3], last = 4 }, mapped = [false, false, true, true], \
+filtered = [2, 4], folded = 1234, original = [1, 2, 3, 4] }"
+        );
+    }
+
+    #[test]
+    fn callbacks_capture_and_nest() {
+        assert_eq!(
+            run("let scale = 10;
+let grid = [[1, 2], [3]];
+map(List(Int), Int, grid, (row: List(Int)) => {
+    return fold(Int, Int, map(Int, Int, row, (x: Int) => { return x * scale; }), 0, (a: Int, b: Int) => {
+        return a + b;
+    });
+});"),
+            "[30, 30]"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected Double but got Int for argument.")]
+    fn argument_types_are_checked() {
+        run("sqrt(2);");
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error: expected (Int,) => Int but got (Int,) => Bool for argument.")]
+    fn callback_types_are_checked() {
+        run("map(Int, Int, [1], (x: Int) => { return true; });");
+    }
+
+    fn frame(function: &str, loc: Option<(u32, u32)>) -> TraceFrame {
+        TraceFrame {
+            function: function.to_string(),
+            loc: loc.map(|(line, col)| Loc { line, col }),
+        }
+    }
+
+    #[test]
+    fn errors_in_natives() {
+        let err = try_run("let empty = filter(Int, [1], (x: Int) => { return false; });\npop(Int, empty);").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Native("Cannot pop from an empty list.".to_string()));
+        assert_eq!(err.trace, vec![frame("pop", None), frame("<main>", Some((2, 1)))]);
+
+        let err = try_run("\"abc\".slice(2, 1);").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::StringIndexOutOfBounds { index: 1, len: 3 });
+        assert_eq!(err.kind.to_string(), "Index 1 out of bounds for string of length 3.");
+        assert_eq!(err.trace, vec![frame("slice", None), frame("<main>", Some((1, 1)))]);
+    }
+
+    #[test]
+    fn errors_in_callbacks() {
+        let err = try_run("let zero = 0;
+const inverse = (x: Int) => {
+    return 100 / (x - zero);
+};
+let f = () => { return map(Int, Int, [1, 0], inverse); };
+f();")
+        .unwrap_err();
+
+        assert_eq!(err.kind, ErrorKind::DivisionByZero);
+        assert_eq!(
+            err.trace,
+            vec![
+                frame("inverse", Some((3, 5))),
+                frame("map", None),
+                frame("f", Some((5, 17))),
+                frame("<main>", Some((6, 1))),
+            ]
+        );
+    }
+}
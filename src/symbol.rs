@@ -0,0 +1,145 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+// an interned name, e.g. of a field, variant, method or global. symbols for the same name share one copy of
+// it, so comparing and hashing them does not look at the name itself.
+#[derive(Clone)]
+pub struct Symbol(Rc<str>);
+
+// every name that some symbol may still use. names no symbol uses any more are dropped from time to time, so
+// programs that keep making up new names don't keep them all alive.
+#[derive(Default)]
+struct Interner {
+    names: HashSet<Rc<str>>,
+    // how many names were left after they were last pruned
+    live: usize,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+impl Interner {
+    fn intern(&mut self, name: &str) -> Rc<str> {
+        if let Some(name) = self.names.get(name) {
+            return Rc::clone(name);
+        }
+
+        // a name only the interner holds is not used by any symbol
+        if self.names.len() >= (self.live * 2).max(64) {
+            self.names.retain(|name| Rc::strong_count(name) > 1);
+            self.live = self.names.len();
+        }
+
+        let name: Rc<str> = Rc::from(name);
+        self.names.insert(Rc::clone(&name));
+        name
+    }
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        Symbol(INTERNER.with(|interner| interner.borrow_mut().intern(name)))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    // a number identifying the name, the same for every symbol for it while any of them is alive
+    pub fn id(&self) -> usize {
+        Rc::as_ptr(&self.0).cast::<u8>() as usize
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id().hash(state);
+    }
+}
+
+impl From<&Symbol> for Symbol {
+    fn from(symbol: &Symbol) -> Symbol {
+        symbol.clone()
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Symbol {
+        Symbol::intern(name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Symbol {
+        Symbol::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Symbol {
+        Symbol::intern(&name)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning() {
+        let a = Symbol::intern("field");
+        assert_eq!(a, Symbol::intern("field"));
+        assert_ne!(a, Symbol::intern("other"));
+        assert_eq!(a.as_str(), "field");
+        assert_eq!(a, "field");
+        assert_eq!(format!("{} {:?}", a, a), "field \"field\"");
+    }
+
+    #[test]
+    fn unused_names_are_freed() {
+        let kept = Symbol::intern("kept");
+        for i in 0..1000 {
+            Symbol::intern(&format!("name{}", i));
+        }
+
+        let count = INTERNER.with(|interner| interner.borrow().names.len());
+        assert!(count < 200, "{} names interned", count);
+        assert_eq!(kept, Symbol::intern("kept"));
+    }
+}
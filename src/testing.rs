@@ -0,0 +1,53 @@
+// helpers shared by the tests of the other modules
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::compiler::Compiler;
+use crate::error::RuntimeError;
+use crate::input_stream::InputStream;
+use crate::parser::{self, Statement};
+use crate::vm::{Function, KytheraVal, Vm};
+
+// collects what a program prints, so it can be read after the VM has taken the writer
+#[derive(Clone, Default)]
+pub struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Output {
+    pub fn printed(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn parse(input: &str) -> Vec<Statement> {
+    parser::parse(InputStream::new_from_string(input)).unwrap_or_else(|e| panic!("{}", e))
+}
+
+pub fn compile(input: &str) -> Rc<Function> {
+    Compiler::new().compile(&parse(input)).unwrap_or_else(|e| panic!("{}", e))
+}
+
+// run a program on a VM, where it can use what the host defined on it
+pub fn try_run_on(vm: &mut Vm, input: &str) -> Result<KytheraVal, RuntimeError> {
+    let function = Compiler::for_vm(vm).compile(&parse(input)).unwrap_or_else(|e| panic!("{}", e));
+    vm.run(function)
+}
+
+pub fn try_run(input: &str) -> Result<KytheraVal, RuntimeError> {
+    try_run_on(&mut Vm::new(), input)
+}
+
+pub fn run(input: &str) -> KytheraVal {
+    try_run(input).unwrap_or_else(|e| panic!("{}", e))
+}
@@ -1,4 +1,5 @@
-use crate::input_stream::InputStream;
+use crate::error::{fail, CompileError};
+use crate::input_stream::{InputStream, Loc};
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
@@ -24,6 +25,7 @@ pub enum Keyword {
     Typeof,
     Import,
     Export,
+    Impl,
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Hash)]
@@ -39,6 +41,9 @@ pub enum Symbol {
     And,
     BarBar,
     AndAnd,
+    Caret,
+    LessLess,
+    GreaterGreater,
 
     EqualEqual,
     // EqualEqualEqual,
@@ -56,8 +61,10 @@ pub enum Symbol {
     Percent,
 
     Bang,
+    Tilde,
 
     Dot,
+    DotDotDot,
     LeftParen,
     RightParen,
     LeftBrace,
@@ -68,9 +75,13 @@ pub enum Symbol {
     Comma,
     Semicolon,
     Colon,
+    EqualGreater,
+    Question,
 }
 pub struct Tokenizer {
     current: Option<Token>,
+    // where the current token starts
+    current_loc: Loc,
     stream: InputStream,
 }
 
@@ -93,41 +104,47 @@ macro_rules! some_kw_tok {
 }
 
 impl Tokenizer {
-    pub fn new(stream: InputStream) -> Tokenizer {
+    pub fn new(stream: InputStream) -> Result<Tokenizer, CompileError> {
         let mut tok = Tokenizer {
             current: None,
+            current_loc: Loc::default(),
             stream,
         };
 
         // step into first token
-        tok.advance();
+        tok.advance()?;
 
-        tok
+        Ok(tok)
     }
 
-    pub fn loc(&self) -> String {
+    pub fn loc(&self) -> Loc {
         self.stream.loc()
     }
 
+    // the location of the start of the current token
+    pub fn token_loc(&self) -> Loc {
+        self.current_loc
+    }
+
     // read current token without consuming it
     pub fn peek(&self) -> &Option<Token> {
         &self.current
     }
 
     // read current token and move to next
-    pub fn consume(&mut self) -> Option<Token> {
+    pub fn consume(&mut self) -> Result<Option<Token>, CompileError> {
         let current = self.current.take();
-        self.advance();
+        self.advance()?;
 
-        current
+        Ok(current)
     }
 
-    // like peek, but panic if expected is not present
-    pub fn expect(&self, expected: &Token) -> &Option<Token> {
+    // like peek, but fail if expected is not present
+    pub fn expect(&self, expected: &Token) -> Result<&Option<Token>, CompileError> {
         let t = self.peek();
 
         if t.as_ref() != Some(expected) {
-            panic!(
+            fail!(
                 "Expected {:?} but got {:?} at {}.",
                 expected,
                 t,
@@ -135,17 +152,17 @@ impl Tokenizer {
             );
         }
 
-        t
+        Ok(t)
     }
 
-    // like consume, but panic if expected is not present
-    pub fn consume_expect(&mut self, expected: &Token) -> Option<Token> {
-        self.expect(expected);
+    // like consume, but fail if expected is not present
+    pub fn consume_expect(&mut self, expected: &Token) -> Result<Option<Token>, CompileError> {
+        self.expect(expected)?;
         self.consume()
     }
 
     // parse one token from input stream
-    fn advance(&mut self) {
+    fn advance(&mut self) -> Result<(), CompileError> {
         // clear non-tokens (whitespace and comments)
         loop {
             match self.stream.peek() {
@@ -158,22 +175,25 @@ impl Tokenizer {
                                 (self.stream.peek(), self.stream.peek_next())
                             {
                                 if n == "*" && m == "/" {
-                                    self.stream.consume_expect("*");
-                                    self.stream.consume_expect("/");
+                                    self.stream.consume_expect("*")?;
+                                    self.stream.consume_expect("/")?;
                                     break;
                                 }
                             }
 
                             if self.stream.eof() {
-                                panic!("Unexpected EOF.")
+                                fail!("Unexpected EOF.")
                             }
 
                             self.stream.consume();
                         },
                         // single-line comment
                         "/" => {
-                            self.stream.read_while(|s| s != "\n");
-                            self.stream.consume_expect("\n");
+                            self.stream.read_while(|s| s != "\n")?;
+                            // the last line may end without a newline
+                            if !self.stream.eof() {
+                                self.stream.consume_expect("\n")?;
+                            }
                         }
                         // anything else, treat it as a / token and continue to token parsing
                         _ => break,
@@ -181,30 +201,36 @@ impl Tokenizer {
                 }
                 // whitespace
                 Some(c) if as_char!(c).is_whitespace() => {
-                    self.stream.read_while(|s| as_char!(s).is_whitespace());
+                    self.stream.read_while(|s| as_char!(s).is_whitespace())?;
                 }
                 _ => break, // no non-tokens ahead, move on to actual parsing
             }
         }
 
+        self.current_loc = self.stream.loc();
+
         // check for EOF
         if self.stream.eof() {
             self.current = None;
-            return;
+            return Ok(());
         }
 
         macro_rules! sym_or_sym_and {
             ($and:expr, $sym_name:ident, $sym_and_name:ident) => {
+                sym_or_sym_and!($sym_name, $and => $sym_and_name)
+            };
+            // a symbol that may be followed by any of several others to form a longer symbol
+            ($sym_name:ident, $($and:expr => $sym_and_name:ident),+) => {
                 if let Some(c) = self.stream.peek() {
                     match c.as_str() {
-                        $and => {
-                            self.stream.consume_expect($and);
+                        $($and => {
+                            self.stream.consume_expect($and)?;
                             some_sym_tok!($sym_and_name)
-                        }
+                        })+
                         _ => some_sym_tok!($sym_name), // if anything else, treat it as just sym
                     }
                 } else {
-                    panic!("Unexpected EOF.")
+                    fail!("Unexpected EOF.")
                 }
             };
         }
@@ -214,10 +240,10 @@ impl Tokenizer {
             // string literal
             "\"" => {
                 // TODO escaped
-                let val = self.stream.read_while(|s| s != "\"");
+                let val = self.stream.read_while(|s| s != "\"")?;
 
                 // eat "
-                self.stream.consume_expect("\"");
+                self.stream.consume_expect("\"")?;
 
                 Some(Token::Str(val))
             }
@@ -231,13 +257,26 @@ impl Tokenizer {
             "|" => sym_or_sym_and!("|", Bar, BarBar),
             "&" => sym_or_sym_and!("&", And, AndAnd),
 
-            "=" => sym_or_sym_and!("=", Equal, EqualEqual),
+            "=" => sym_or_sym_and!(Equal, "=" => EqualEqual, ">" => EqualGreater),
             "!" => sym_or_sym_and!("=", Bang, BangEqual),
 
-            "<" => sym_or_sym_and!("=", Less, LessEqual),
-            ">" => sym_or_sym_and!("=", Greater, GreaterEqual),
+            "<" => sym_or_sym_and!(Less, "=" => LessEqual, "<" => LessLess),
+            ">" => sym_or_sym_and!(Greater, "=" => GreaterEqual, ">" => GreaterGreater),
+
+            "^" => some_sym_tok!(Caret),
+            "~" => some_sym_tok!(Tilde),
 
-            "." => some_sym_tok!(Dot),
+            "." => {
+                if self.stream.peek().as_deref() == Some(".")
+                    && self.stream.peek_next().as_deref() == Some(".")
+                {
+                    self.stream.consume_expect(".")?;
+                    self.stream.consume_expect(".")?;
+                    some_sym_tok!(DotDotDot)
+                } else {
+                    some_sym_tok!(Dot)
+                }
+            }
             "(" => some_sym_tok!(LeftParen),
             ")" => some_sym_tok!(RightParen),
             "{" => some_sym_tok!(LeftBrace),
@@ -248,6 +287,7 @@ impl Tokenizer {
             "," => some_sym_tok!(Comma),
             ";" => some_sym_tok!(Semicolon),
             ":" => some_sym_tok!(Colon),
+            "?" => some_sym_tok!(Question),
             t if as_char!(t).is_digit(10) => {
                 let mut output: Vec<String> = vec![t.to_string()];
                 let mut has_dec = false;
@@ -256,13 +296,13 @@ impl Tokenizer {
                     if as_char!(s).is_digit(10) || s == "." {
                         if s == "." {
                             if has_dec {
-                                panic!("Unexpected '.' at {}", self.stream.loc())
+                                fail!("Unexpected '.' at {}", self.stream.loc())
                             }
 
                             has_dec = true;
                         }
 
-                        self.stream.consume_expect(&s);
+                        self.stream.consume_expect(&s)?;
                         output.push(s);
                     } else {
                         break;
@@ -272,13 +312,15 @@ impl Tokenizer {
                 let result = output.join("");
 
                 if has_dec {
-                    Some(Token::Double(result.parse::<f64>().unwrap_or_else(|_| {
-                        panic!("Could not parse number: {}", result)
-                    })))
+                    match result.parse::<f64>() {
+                        Ok(d) => Some(Token::Double(d)),
+                        Err(_) => fail!("Could not parse number: {}", result),
+                    }
                 } else {
-                    Some(Token::Int(result.parse::<i32>().unwrap_or_else(|_| {
-                        panic!("Could not parse integer: {}", result)
-                    })))
+                    match result.parse::<i32>() {
+                        Ok(n) => Some(Token::Int(n)),
+                        Err(_) => fail!("Could not parse integer: {}", result),
+                    }
                 }
             }
             t => {
@@ -291,7 +333,7 @@ impl Tokenizer {
                             break;
                         }
 
-                        self.stream.consume_expect(&s);
+                        self.stream.consume_expect(&s)?;
                         word.push(s);
                     }
 
@@ -311,11 +353,14 @@ impl Tokenizer {
                     "typeof" => some_kw_tok!(Typeof),
                     "import" => some_kw_tok!(Import),
                     "export" => some_kw_tok!(Export),
+                    "impl" => some_kw_tok!(Impl),
                     // user-identified keyword
                     id => Some(Token::Id(id.to_string())),
                 }
             }
         };
+
+        Ok(())
     }
 }
 
@@ -323,21 +368,20 @@ impl Tokenizer {
 mod tests {
     use super::*;
     use crate::input_stream;
-    use std::io::Error;
 
     #[test]
     fn tokens() -> Result<(), String> {
         let input = "\"string literal\"
 = += -= *= /= %=
-| & || &&
+| & || && ^ << >>
 == != < > <= >=
 + - * / %
-!
-. ( ) [ ] { }
-, ; :
+! ~
+. ... ( ) [ ] { }
+, ; : => ?
 
 42
-3.14159
+1.25
 
 const
 let
@@ -351,10 +395,11 @@ continue
 typeof
 import
 export
+impl
 
 myVar";
 
-        let mut tokenizer = Tokenizer::new(input_stream::InputStream::new_from_string(input));
+        let mut tokenizer = Tokenizer::new(input_stream::InputStream::new_from_string(input)).map_err(|e| e.to_string())?;
 
         let expected = vec![
             Some(Token::Str("string literal".to_string())),
@@ -368,6 +413,9 @@ myVar";
             some_sym_tok!(And),
             some_sym_tok!(BarBar),
             some_sym_tok!(AndAnd),
+            some_sym_tok!(Caret),
+            some_sym_tok!(LessLess),
+            some_sym_tok!(GreaterGreater),
             some_sym_tok!(EqualEqual),
             some_sym_tok!(BangEqual),
             some_sym_tok!(Less),
@@ -380,7 +428,9 @@ myVar";
             some_sym_tok!(Slash),
             some_sym_tok!(Percent),
             some_sym_tok!(Bang),
+            some_sym_tok!(Tilde),
             some_sym_tok!(Dot),
+            some_sym_tok!(DotDotDot),
             some_sym_tok!(LeftParen),
             some_sym_tok!(RightParen),
             some_sym_tok!(LeftBracket),
@@ -390,8 +440,10 @@ myVar";
             some_sym_tok!(Comma),
             some_sym_tok!(Semicolon),
             some_sym_tok!(Colon),
+            some_sym_tok!(EqualGreater),
+            some_sym_tok!(Question),
             Some(Token::Int(42)),
-            Some(Token::Double(3.14159)),
+            Some(Token::Double(1.25)),
             some_kw_tok!(Const),
             some_kw_tok!(Let),
             some_kw_tok!(If),
@@ -404,13 +456,14 @@ myVar";
             some_kw_tok!(Typeof),
             some_kw_tok!(Import),
             some_kw_tok!(Export),
+            some_kw_tok!(Impl),
             Some(Token::Id("myVar".to_string()))
         ];
         let mut expected_iter = expected.iter();
 
         // assert_eq!(tokenizer.consume(), Some(Token::Str("string literal".to_string())));
 
-        while let (got, Some(expected)) = (tokenizer.consume(), expected_iter.next()) {
+        while let (got, Some(expected)) = (tokenizer.consume().map_err(|e| e.to_string())?, expected_iter.next()) {
             println!("{:?} vs {:?}", got, expected);
             assert_eq!(&got, expected);
         }
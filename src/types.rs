@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+
+use crate::symbol::Symbol;
+
+// a Kythera type. types are values in Kythera, so this is both what the compiler checks expressions
+// against and what a type value holds at runtime.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[allow(clippy::enum_variant_names)]
+pub enum Type {
+    Unit,
+    Int,
+    Double,
+    Bool,
+    String,
+    // the type of types, e.g. typeof Int
+    Type,
+    Struct(Fields<Type>),
+    // a tagged union, holding exactly one of its variants along with a value of that variant's type, e.g.
+    // { some: Int | none: Unit }
+    Union(Fields<Type>),
+    List(Box<Type>),
+    Fn {
+        params: Vec<Type>,
+        returns: Box<Type>,
+    },
+    // a parameter of type Type that the types of later parameters or the return type refer to, e.g. T in
+    // (T: Type, x: T) => T. only appears in the parameters of a function type.
+    TypeParam(String),
+    // the type held by the type parameter with the given name, which is not known until the function is
+    // called
+    Var(String),
+}
+
+// types print the way they would be written in Kythera source
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Unit => write!(f, "Unit"),
+            Type::Int => write!(f, "Int"),
+            Type::Double => write!(f, "Double"),
+            Type::Bool => write!(f, "Bool"),
+            Type::String => write!(f, "String"),
+            Type::Type => write!(f, "Type"),
+            Type::Struct(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(name, field_type)| format!("{}: {}", name, field_type))
+                    .collect();
+
+                write!(f, "{{ {} }}", fields.join(", "))
+            }
+            Type::Union(variants) => {
+                let variants: Vec<String> = variants
+                    .iter()
+                    .map(|(tag, variant_type)| format!("{}: {}", tag, variant_type))
+                    .collect();
+
+                write!(f, "{{ {} }}", variants.join(" | "))
+            }
+            Type::List(element) => write!(f, "List({})", element),
+            Type::Fn { params, returns } => {
+                let params: Vec<String> = params.iter().map(Type::to_string).collect();
+
+                // a single parameter type needs a trailing comma to not be a parenthesized expression
+                if params.len() == 1 {
+                    write!(f, "({},) => {}", params[0], returns)
+                } else {
+                    write!(f, "({}) => {}", params.join(", "), returns)
+                }
+            }
+            Type::TypeParam(name) => write!(f, "{}: Type", name),
+            Type::Var(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Type {
+    // the type built by Result(ok_type, error_type)
+    pub fn result(ok_type: Type, error_type: Type) -> Type {
+        Type::Union(vec![("ok", ok_type), ("err", error_type)].into_iter().collect())
+    }
+
+    // whether the type held by the type parameter name appears in this type
+    pub fn mentions(&self, name: &str) -> bool {
+        match self {
+            Type::Var(var) => var == name,
+            Type::Struct(fields) | Type::Union(fields) => fields.iter().any(|(_, t)| t.mentions(name)),
+            Type::List(element) => element.mentions(name),
+            Type::Fn { params, returns } => {
+                !params.contains(&Type::TypeParam(name.to_string()))
+                    && (params.iter().any(|t| t.mentions(name)) || returns.mentions(name))
+            }
+            _ => false,
+        }
+    }
+
+    // whether the type refers to a type parameter of a function it is not part of, so is not fully known
+    // until that function is called
+    pub fn is_generic(&self) -> bool {
+        self.has_free_vars(&[])
+    }
+
+    fn has_free_vars(&self, bound: &[&str]) -> bool {
+        match self {
+            Type::Var(name) => !bound.contains(&name.as_str()),
+            Type::Struct(fields) | Type::Union(fields) => fields.iter().any(|(_, t)| t.has_free_vars(bound)),
+            Type::List(element) => element.has_free_vars(bound),
+            Type::Fn { params, returns } => {
+                let mut bound = bound.to_vec();
+                for param in params {
+                    if let Type::TypeParam(name) = param {
+                        bound.push(name);
+                    }
+                }
+
+                params.iter().any(|t| t.has_free_vars(&bound)) || returns.has_free_vars(&bound)
+            }
+            _ => false,
+        }
+    }
+
+    // replace the types held by type parameters with the types given for them
+    pub fn substitute(&self, args: &[(String, Type)]) -> Type {
+        match self {
+            Type::Var(var) => args
+                .iter()
+                .find(|(name, _)| name == var)
+                .map_or_else(|| self.clone(), |(_, t)| t.clone()),
+            Type::Struct(fields) => Type::Struct(
+                fields
+                    .iter()
+                    .map(|(name, t)| (name.clone(), t.substitute(args)))
+                    .collect(),
+            ),
+            Type::Union(variants) => Type::Union(
+                variants
+                    .iter()
+                    .map(|(tag, t)| (tag.clone(), t.substitute(args)))
+                    .collect(),
+            ),
+            Type::List(element) => Type::List(Box::from(element.substitute(args))),
+            Type::Fn { params, returns } => {
+                // type parameters of the inner function shadow those being substituted
+                let args: Vec<(String, Type)> = args
+                    .iter()
+                    .filter(|(name, _)| !params.contains(&Type::TypeParam(name.clone())))
+                    .cloned()
+                    .collect();
+
+                Type::Fn {
+                    params: params.iter().map(|t| t.substitute(&args)).collect(),
+                    returns: Box::from(returns.substitute(&args)),
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+}
+
+// the fields of a struct or struct type, kept in the order they were written. two sets of fields are
+// equal if they have the same names with equal values, regardless of order.
+#[derive(Debug, Clone)]
+pub struct Fields<T> {
+    entries: Vec<(Symbol, T)>,
+}
+
+impl<T> Default for Fields<T> {
+    fn default() -> Fields<T> {
+        Fields::new()
+    }
+}
+
+impl<T> Fields<T> {
+    pub fn new() -> Fields<T> {
+        Fields {
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, name: impl Into<Symbol>) -> Option<&T> {
+        let name = name.into();
+        self.entries
+            .iter()
+            .find(|(field_name, _)| *field_name == name)
+            .map(|(_, val)| val)
+    }
+
+    // add a field at the end, or replace the value of an existing field without moving it.
+    // returns the value that was replaced, if any.
+    pub fn insert(&mut self, name: impl Into<Symbol>, val: T) -> Option<T> {
+        let name = name.into();
+        match self.entries.iter_mut().find(|(field_name, _)| *field_name == name) {
+            Some((_, existing)) => Some(std::mem::replace(existing, val)),
+            None => {
+                self.entries.push((name, val));
+                None
+            }
+        }
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (Symbol, T)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<T: PartialEq> PartialEq for Fields<T> {
+    fn eq(&self, other: &Fields<T>) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(name, val)| other.get(name) == Some(val))
+    }
+}
+
+impl<T: Eq> Eq for Fields<T> {}
+
+// fields hash the same in any order, like they compare equal. only their names are hashed, which tells most
+// types apart without looking at every field's type.
+impl<T> Hash for Fields<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let names = self.iter().fold(0usize, |names, (name, _)| names.wrapping_add(name.id()));
+        self.len().hash(state);
+        names.hash(state);
+    }
+}
+
+impl<K: Into<Symbol>, T> FromIterator<(K, T)> for Fields<T> {
+    fn from_iter<I: IntoIterator<Item = (K, T)>>(iter: I) -> Fields<T> {
+        let mut fields = Fields::new();
+        for (name, val) in iter {
+            fields.insert(name, val);
+        }
+
+        fields
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Fields<T> {
+    type Item = &'a (Symbol, T);
+    type IntoIter = std::slice::Iter<'a, (Symbol, T)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// functions attached to types with impl, keyed by the type they were attached to
+#[derive(Debug, Clone)]
+pub struct Methods<T> {
+    entries: HashMap<Type, Fields<T>>,
+}
+
+impl<T> Default for Methods<T> {
+    fn default() -> Methods<T> {
+        Methods::new()
+    }
+}
+
+impl<T> Methods<T> {
+    pub fn new() -> Methods<T> {
+        Methods {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, type_val: &Type, name: impl Into<Symbol>) -> Option<&T> {
+        self.entries.get(type_val).and_then(|methods| methods.get(name))
+    }
+
+    pub fn insert(&mut self, type_val: Type, name: impl Into<Symbol>, method: T) {
+        self.entries.entry(type_val).or_default().insert(name, method);
+    }
+}
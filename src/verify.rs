@@ -0,0 +1,270 @@
+use std::fmt;
+
+use crate::vm::{Function, Instruction};
+
+// checks that a function's instructions (and those of the functions it makes) can run without the VM
+// finding them malformed: every jump lands on an instruction, constants and slots exist, the stack never
+// underflows and has the same depth whichever way an instruction is reached, and every path ends in a
+// Return. instructions that cannot be reached are not checked. the types of the values instructions work on
+// are not checked, so those mistakes are left for the VM to report.
+pub fn verify(function: &Function) -> Result<(), VerifyError> {
+    verify_nested(function, &mut Vec::new())
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    // the name of the function holding the bad instruction
+    pub function: String,
+    pub offset: usize,
+    pub problem: Problem,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    JumpOutOfRange(usize),
+    StackUnderflow,
+    // the instruction is reached with different stack depths
+    InconsistentStack { expected: usize, found: usize },
+    // execution can run past the last instruction
+    MissingReturn,
+    InvalidConstant(u16),
+    InvalidSlot(u16),
+    InvalidCapture(u16, u16),
+    // a list literal with no elements, whose element type is unknown
+    EmptyList,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:04}: ", self.function, self.offset)?;
+
+        match &self.problem {
+            Problem::JumpOutOfRange(target) => write!(f, "jump to {:04}, past the end of the function.", target),
+            Problem::StackUnderflow => write!(f, "pops more values than the stack holds."),
+            Problem::InconsistentStack { expected, found } => write!(
+                f,
+                "reached with {} values on the stack, but {} on another path.",
+                found, expected
+            ),
+            Problem::MissingReturn => write!(f, "runs past the end of the function without returning."),
+            Problem::InvalidConstant(index) => write!(f, "no constant at index {}.", index),
+            Problem::InvalidSlot(slot) => write!(f, "no variable in slot {}.", slot),
+            Problem::InvalidCapture(depth, slot) => {
+                write!(f, "no variable in slot {} of the function {} out.", slot, depth)
+            }
+            Problem::EmptyList => write!(f, "makes a list with no elements."),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// outer holds the functions this one is nested in, innermost last
+fn verify_nested<'a>(function: &'a Function, outer: &mut Vec<&'a Function>) -> Result<(), VerifyError> {
+    let error = |offset, problem| VerifyError {
+        function: function.name.clone(),
+        offset,
+        problem,
+    };
+
+    let instructions = &function.instructions;
+    // the stack depth each instruction is reached with, once it has been reached
+    let mut depths: Vec<Option<usize>> = vec![None; instructions.len()];
+    let mut pending = vec![(0, 0)];
+
+    while let Some((offset, depth)) = pending.pop() {
+        let inst = instructions.get(offset).ok_or_else(|| error(offset, Problem::MissingReturn))?;
+        match depths[offset] {
+            Some(expected) if expected != depth => {
+                return Err(error(offset, Problem::InconsistentStack { expected, found: depth }));
+            }
+            Some(_) => continue,
+            None => depths[offset] = Some(depth),
+        }
+
+        match inst {
+            Instruction::Constant(index) if *index as usize >= function.constants.len() => {
+                return Err(error(offset, Problem::InvalidConstant(*index)));
+            }
+            Instruction::StoreLocal(slot) | Instruction::LoadLocal(slot) if *slot as usize >= function.slots => {
+                return Err(error(offset, Problem::InvalidSlot(*slot)));
+            }
+            Instruction::StoreCaptured(up, slot) | Instruction::LoadCaptured(up, slot) => {
+                let scope = (*up as usize).checked_sub(1).and_then(|i| outer.iter().rev().nth(i));
+                if scope.is_none_or(|scope| *slot as usize >= scope.slots) {
+                    return Err(error(offset, Problem::InvalidCapture(*up, *slot)));
+                }
+            }
+            Instruction::MakeList(0) => return Err(error(offset, Problem::EmptyList)),
+            _ => {}
+        }
+
+        let (pops, pushes) = stack_effect(inst);
+        let depth = depth.checked_sub(pops).ok_or_else(|| error(offset, Problem::StackUnderflow))? + pushes;
+
+        match inst {
+            Instruction::Return | Instruction::TailInvoke(_) => {}
+            Instruction::Jump(target) | Instruction::JumpIf(target) | Instruction::JumpIfNot(target) => {
+                if *target >= instructions.len() {
+                    return Err(error(offset, Problem::JumpOutOfRange(*target)));
+                }
+
+                pending.push((*target, depth));
+                if !matches!(inst, Instruction::Jump(_)) {
+                    pending.push((offset + 1, depth));
+                }
+            }
+            _ => pending.push((offset + 1, depth)),
+        }
+    }
+
+    // only check the functions this one can make
+    outer.push(function);
+    let reachable = instructions.iter().zip(&depths).filter(|(_, depth)| depth.is_some());
+    for (inst, _) in reachable {
+        if let Instruction::MakeClosure(nested) = inst {
+            verify_nested(nested, outer)?;
+        }
+    }
+    outer.pop();
+
+    Ok(())
+}
+
+// how many values an instruction pops off the stack and then pushes onto it
+fn stack_effect(inst: &Instruction) -> (usize, usize) {
+    match inst {
+        Instruction::Nop | Instruction::Jump(_) => (0, 0),
+        Instruction::Constant(_)
+        | Instruction::MakeClosure(_)
+        | Instruction::LoadGlobal(_)
+        | Instruction::LoadLocal(_)
+        | Instruction::LoadCaptured(_, _) => (0, 1),
+        Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Mod
+        | Instruction::Equal
+        | Instruction::NotEqual
+        | Instruction::Less
+        | Instruction::LessEqual
+        | Instruction::Greater
+        | Instruction::GreaterEqual
+        | Instruction::Or
+        | Instruction::And
+        | Instruction::Xor
+        | Instruction::ShiftLeft
+        | Instruction::ShiftRight
+        | Instruction::Spread
+        | Instruction::Index
+        | Instruction::MakeVariant(_) => (2, 1),
+        Instruction::Not
+        | Instruction::Field(_)
+        | Instruction::MakeListType
+        | Instruction::IsVariant(_)
+        | Instruction::Payload
+        | Instruction::Typeof => (1, 1),
+        Instruction::Invoke(n) | Instruction::MakeFnType(n) => (n + 1, 1),
+        Instruction::MakeList(n) => (*n, 1),
+        Instruction::MakeStruct(names) | Instruction::MakeStructType(names) | Instruction::MakeUnionType(names) => {
+            (names.len(), 1)
+        }
+        Instruction::Impl(names) => (names.len() + 1, 0),
+        Instruction::TailInvoke(n) => (n + 1, 0),
+        Instruction::Dup => (1, 2),
+        Instruction::Pop
+        | Instruction::JumpIf(_)
+        | Instruction::JumpIfNot(_)
+        | Instruction::Return
+        | Instruction::DeclareGlobal(_)
+        | Instruction::StoreGlobal(_)
+        | Instruction::StoreLocal(_)
+        | Instruction::StoreCaptured(_, _) => (1, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::testing::compile;
+    use crate::types::Type;
+    use crate::vm::KytheraVal;
+
+    fn function(slots: usize, instructions: Vec<Instruction>) -> Function {
+        Function {
+            name: "test".to_string(),
+            params: Vec::new(),
+            slots,
+            instructions,
+            constants: vec![KytheraVal::bool(true)],
+            locs: Vec::new(),
+            type_val: Rc::new(Type::Unit),
+        }
+    }
+
+    fn problem(slots: usize, instructions: Vec<Instruction>) -> (usize, Problem) {
+        let e = verify(&function(slots, instructions)).unwrap_err();
+        (e.offset, e.problem)
+    }
+
+    #[test]
+    fn compiled_programs_verify() {
+        let source = fs::read_to_string("./main.ky").unwrap();
+        assert_eq!(verify(&compile(&source)), Ok(()));
+    }
+
+    #[test]
+    fn branches_that_agree() {
+        use Instruction::*;
+
+        // unreachable code after the Return is not checked
+        let instructions = vec![Constant(0), JumpIf(4), Constant(0), Jump(5), Constant(0), Return, Pop];
+        assert_eq!(verify(&function(0, instructions)), Ok(()));
+    }
+
+    #[test]
+    fn malformed_functions() {
+        use Instruction::*;
+
+        assert_eq!(problem(0, vec![Jump(2), Return]), (0, Problem::JumpOutOfRange(2)));
+        assert_eq!(problem(0, vec![Constant(0), Add, Return]), (1, Problem::StackUnderflow));
+        assert_eq!(problem(0, vec![Return]), (0, Problem::StackUnderflow));
+        assert_eq!(problem(0, vec![Constant(0), Pop]), (2, Problem::MissingReturn));
+        assert_eq!(problem(0, vec![Constant(1), Return]), (0, Problem::InvalidConstant(1)));
+        assert_eq!(problem(1, vec![LoadLocal(1), Return]), (0, Problem::InvalidSlot(1)));
+        assert_eq!(problem(1, vec![LoadCaptured(1, 0), Return]), (0, Problem::InvalidCapture(1, 0)));
+        assert_eq!(problem(0, vec![MakeList(0), Return]), (0, Problem::EmptyList));
+
+        // the true branch skips pushing a value
+        assert_eq!(
+            problem(0, vec![Constant(0), JumpIf(3), Constant(0), Constant(0), Return]),
+            (3, Problem::InconsistentStack { expected: 1, found: 0 })
+        );
+
+        // a loop that pushes a value each time around
+        assert_eq!(
+            problem(0, vec![Constant(0), Jump(0)]),
+            (0, Problem::InconsistentStack { expected: 0, found: 1 })
+        );
+    }
+
+    #[test]
+    fn nested_functions_are_verified() {
+        use Instruction::*;
+
+        let inner = function(0, vec![LoadCaptured(1, 1), Return]);
+        let outer = function(2, vec![MakeClosure(Rc::new(inner)), Return]);
+        assert_eq!(verify(&outer), Ok(()));
+
+        let inner = function(0, vec![LoadCaptured(1, 2), Return]);
+        let outer = function(2, vec![MakeClosure(Rc::new(inner)), Return]);
+        assert_eq!(
+            verify(&outer).unwrap_err().to_string(),
+            "test at 0000: no variable in slot 2 of the function 1 out."
+        );
+    }
+}
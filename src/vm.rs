@@ -1,23 +1,332 @@
-use std::rc::Rc;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::error::{ErrorKind, RuntimeError, TraceFrame};
+use crate::ffi::{HostFn, IntoKythera};
+use crate::gc::{GcStats, Heap};
+use crate::input_stream::Loc;
+use crate::prelude;
+use crate::symbol::Symbol;
+use crate::types::{Fields, Methods, Type};
 
-#[derive(Clone)]
-struct KytheraVal {
-    val: InternalVal,
-    type_val: Rc<KytheraVal>,
+#[derive(Clone, Debug, PartialEq)]
+pub struct KytheraVal {
+    pub val: InternalVal,
+    pub type_val: Rc<Type>,
 }
 
-#[derive(Clone)]
-enum InternalVal {
+#[derive(Clone, Debug)]
+pub enum InternalVal {
     Unit,
     Int(i32),
     Double(f64),
-    String(String),
+    String(Rc<String>),
     Bool(bool),
+    Type(Rc<Type>),
+    Struct(Rc<Fields<KytheraVal>>),
+    List(Rc<Vec<KytheraVal>>),
+    // a value of a union type: the tag of its variant and the value of that variant
+    Variant(Symbol, Rc<KytheraVal>),
+    Fn(Rc<Closure>),
+    Native(Rc<Native>),
+}
+
+impl PartialEq for InternalVal {
+    fn eq(&self, other: &InternalVal) -> bool {
+        match (self, other) {
+            (InternalVal::Unit, InternalVal::Unit) => true,
+            (InternalVal::Int(a), InternalVal::Int(b)) => a == b,
+            (InternalVal::Double(a), InternalVal::Double(b)) => a == b,
+            (InternalVal::String(a), InternalVal::String(b)) => a == b,
+            (InternalVal::Bool(a), InternalVal::Bool(b)) => a == b,
+            (InternalVal::Type(a), InternalVal::Type(b)) => a == b,
+            (InternalVal::Struct(a), InternalVal::Struct(b)) => a == b,
+            (InternalVal::List(a), InternalVal::List(b)) => a == b,
+            (InternalVal::Variant(tag_a, a), InternalVal::Variant(tag_b, b)) => tag_a == tag_b && a == b,
+            // functions are only equal to themselves
+            (InternalVal::Fn(a), InternalVal::Fn(b)) => Rc::ptr_eq(a, b),
+            (InternalVal::Native(a), InternalVal::Native(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+// the types of values of built-in types, which are shared so that making such a value does not allocate
+struct BuiltinTypes {
+    unit: Rc<Type>,
+    int: Rc<Type>,
+    double: Rc<Type>,
+    string: Rc<Type>,
+    bool: Rc<Type>,
+    type_type: Rc<Type>,
+}
+
+thread_local! {
+    static BUILTIN_TYPES: BuiltinTypes = BuiltinTypes {
+        unit: Rc::new(Type::Unit),
+        int: Rc::new(Type::Int),
+        double: Rc::new(Type::Double),
+        string: Rc::new(Type::String),
+        bool: Rc::new(Type::Bool),
+        type_type: Rc::new(Type::Type),
+    };
+}
+
+fn builtin_type(select: fn(&BuiltinTypes) -> &Rc<Type>) -> Rc<Type> {
+    BUILTIN_TYPES.with(|types| Rc::clone(select(types)))
+}
+
+impl KytheraVal {
+    pub fn unit() -> KytheraVal {
+        KytheraVal {
+            val: InternalVal::Unit,
+            type_val: builtin_type(|types| &types.unit),
+        }
+    }
+
+    pub fn int(n: i32) -> KytheraVal {
+        KytheraVal {
+            val: InternalVal::Int(n),
+            type_val: builtin_type(|types| &types.int),
+        }
+    }
+
+    pub fn double(d: f64) -> KytheraVal {
+        KytheraVal {
+            val: InternalVal::Double(d),
+            type_val: builtin_type(|types| &types.double),
+        }
+    }
+
+    pub fn string(s: String) -> KytheraVal {
+        KytheraVal {
+            val: InternalVal::String(Rc::new(s)),
+            type_val: builtin_type(|types| &types.string),
+        }
+    }
+
+    pub fn bool(b: bool) -> KytheraVal {
+        KytheraVal {
+            val: InternalVal::Bool(b),
+            type_val: builtin_type(|types| &types.bool),
+        }
+    }
+
+    pub fn type_val(t: Type) -> KytheraVal {
+        KytheraVal {
+            val: InternalVal::Type(Rc::new(t)),
+            type_val: builtin_type(|types| &types.type_type),
+        }
+    }
+
+    pub fn structure(fields: Fields<KytheraVal>) -> KytheraVal {
+        let field_types = fields
+            .iter()
+            .map(|(name, field)| (name.clone(), field.type_val.as_ref().clone()))
+            .collect();
+
+        KytheraVal {
+            val: InternalVal::Struct(Rc::new(fields)),
+            type_val: Rc::new(Type::Struct(field_types)),
+        }
+    }
+
+    // elements must all be of the given type
+    pub fn list(element_type: Type, elements: Vec<KytheraVal>) -> KytheraVal {
+        KytheraVal {
+            val: InternalVal::List(Rc::new(elements)),
+            type_val: Rc::new(Type::List(Box::from(element_type))),
+        }
+    }
+
+    // the union type must have a variant with the given tag, of the type of value
+    pub fn variant(union_type: Type, tag: impl Into<Symbol>, value: KytheraVal) -> KytheraVal {
+        KytheraVal {
+            val: InternalVal::Variant(tag.into(), Rc::new(value)),
+            type_val: Rc::new(union_type),
+        }
+    }
+
+    // a function implemented in Rust, of the given type
+    pub fn native(name: &str, type_val: Type, function: NativeFn) -> KytheraVal {
+        KytheraVal {
+            val: InternalVal::Native(Rc::new(Native {
+                name: name.to_string(),
+                function,
+                receiver: None,
+            })),
+            type_val: Rc::new(type_val),
+        }
+    }
+
+    pub fn as_int(&self) -> Result<i32, ErrorKind> {
+        if let InternalVal::Int(n) = self.val {
+            Ok(n)
+        } else {
+            Err(self.mismatch("Int"))
+        }
+    }
+
+    pub fn as_double(&self) -> Result<f64, ErrorKind> {
+        if let InternalVal::Double(d) = self.val {
+            Ok(d)
+        } else {
+            Err(self.mismatch("Double"))
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, ErrorKind> {
+        if let InternalVal::String(s) = &self.val {
+            Ok(s)
+        } else {
+            Err(self.mismatch("String"))
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&[KytheraVal], ErrorKind> {
+        if let InternalVal::List(elements) = &self.val {
+            Ok(elements)
+        } else {
+            Err(self.mismatch("List"))
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, ErrorKind> {
+        if let InternalVal::Bool(b) = self.val {
+            Ok(b)
+        } else {
+            Err(self.mismatch("Bool"))
+        }
+    }
+
+    pub fn as_type(&self) -> Result<Type, ErrorKind> {
+        if let InternalVal::Type(t) = &self.val {
+            Ok(t.as_ref().clone())
+        } else {
+            Err(self.mismatch("Type"))
+        }
+    }
+
+    pub fn mismatch(&self, expected: &str) -> ErrorKind {
+        ErrorKind::TypeMismatch {
+            expected: expected.to_string(),
+            got: self.type_val.as_ref().clone(),
+        }
+    }
 }
 
-enum Instruction {
+impl fmt::Display for KytheraVal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.val {
+            InternalVal::Unit => write!(f, "unit"),
+            InternalVal::Int(n) => write!(f, "{}", n),
+            InternalVal::Double(d) => write!(f, "{:?}", d),
+            InternalVal::String(s) => write!(f, "{}", s),
+            InternalVal::Bool(b) => write!(f, "{}", b),
+            InternalVal::Type(t) => write!(f, "{}", t),
+            InternalVal::Struct(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(name, field)| format!("{} = {}", name, field))
+                    .collect();
+
+                write!(f, "{{ {} }}", fields.join(", "))
+            }
+            InternalVal::List(elements) => {
+                let elements: Vec<String> = elements.iter().map(KytheraVal::to_string).collect();
+                write!(f, "[{}]", elements.join(", "))
+            }
+            // variants print the way they are constructed, e.g. some(1) or none
+            InternalVal::Variant(tag, value) => match value.val {
+                InternalVal::Unit => write!(f, "{}", tag),
+                _ => write!(f, "{}({})", tag, value),
+            },
+            InternalVal::Fn(_) | InternalVal::Native(_) => write!(f, "<fn {}>", self.type_val),
+        }
+    }
+}
+
+// compiled code for a function (or for a whole program, which takes no parameters)
+#[derive(Debug)]
+pub struct Function {
+    // the name the function was declared with, for stack traces
+    pub name: String,
+    pub params: Vec<String>,
+    // how many variables a call needs slots for, starting with its parameters. a program's variables are
+    // globals, so it needs none.
+    pub slots: usize,
+    pub instructions: Vec<Instruction>,
+    // the values pushed by Constant instructions
+    pub constants: Vec<KytheraVal>,
+    // where in the source each instruction came from. empty for functions built into the VM.
+    pub locs: Vec<Loc>,
+    pub type_val: Rc<Type>,
+}
+
+// a function paired with the scope it was created in
+pub struct Closure {
+    function: Rc<Function>,
+    pub(crate) scope: Rc<RefCell<Scope>>,
+    // for a method accessed through a value, the value, which is passed as the first argument
+    pub(crate) receiver: Option<KytheraVal>,
+}
+
+// scopes can (and usually do) contain closures that point back to them, so only print the function
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Closure").field("function", &self.function).finish()
+    }
+}
+
+// the Rust code behind a native function. it is given the VM so it can call back into Kythera functions.
+pub type NativeFn = Rc<dyn Fn(&mut Vm, Vec<KytheraVal>) -> Result<KytheraVal, RuntimeError>>;
+
+pub struct Native {
+    pub name: String,
+    pub function: NativeFn,
+    // the value a method was accessed through, passed as its first argument
+    pub receiver: Option<KytheraVal>,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Native").field("name", &self.name).finish()
+    }
+}
+
+// the variables of the global scope or of a function call. globals are looked up by name, since the host and
+// later programs can add to them; the compiler gives each variable of a function a slot.
+pub struct Scope {
+    pub(crate) vars: HashMap<Symbol, KytheraVal>,
+    pub(crate) slots: Vec<KytheraVal>,
+    pub(crate) parent: Option<Rc<RefCell<Scope>>>,
+}
+
+impl Scope {
+    fn new(slots: Vec<KytheraVal>, parent: Option<Rc<RefCell<Scope>>>) -> Scope {
+        Scope {
+            vars: HashMap::new(),
+            slots,
+            parent,
+        }
+    }
+
+    fn slot(&mut self, slot: u16) -> Result<&mut KytheraVal, ErrorKind> {
+        self.slots.get_mut(slot as usize).ok_or(ErrorKind::InvalidSlot(slot))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Instruction {
     Nop,
+    Constant(u16),
+    // ..., => ..., the constant at the given index in the function's constants
     Add,
     Sub,
     // ..., a, b => ..., (a - b)
@@ -26,79 +335,1048 @@ enum Instruction {
     // ..., a, b => ..., (a / b)
     Mod,
     // ..., a, b => ..., (a % b)
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    // ..., a, b => ..., (a < b)
     Not,
-    // ..., a => ..., !a
+    // ..., a => ..., !a, bitwise on Int
     Or,
     And,
-    Invoke,
+    Xor,
+    // ..., a, b => ..., (a & b), logical on Bool (always evaluating both a and b) and bitwise on Int
+    ShiftLeft,
+    ShiftRight,
+    // ..., a, b => ..., (a >> b), where b must not be negative. bits shifted past either end are
+    // discarded and >> copies the sign bit, so shifting by 32 or more gives 0 (or -1 for a negative a
+    // shifted right).
+    Invoke(usize),
     // ..., a1, ..., an, f => ..., f(a1, ..., an)
-    Field(String),
+    TailInvoke(usize),
+    // ..., a1, ..., an, f => returns f(a1, ..., an), with the call to f taking the place of the current one
+    // instead of returning to it, so that tail recursion does not grow the frame stack. the current function
+    // is gone from the trace of any error raised during the call.
+    Field(Symbol),
     // ..., v => ..., v.f
+    MakeStruct(Vec<Symbol>),
+    // ..., v1, ..., vn => ..., { f1 = v1, ..., fn = vn, }
+    Spread,
+    // ..., a, b => ..., { ...a, ...b }
+    MakeList(usize),
+    // ..., v1, ..., vn => ..., [v1, ..., vn], where n > 0
+    Index,
+    // ..., l, i => ..., l[i]
+    MakeStructType(Vec<Symbol>),
+    // ..., t1, ..., tn => ..., { f1: t1, ..., fn: tn, }
+    MakeFnType(usize),
+    // ..., t1, ..., tn, r => ..., (t1, ..., tn,) => r
+    MakeListType,
+    // ..., t => ..., List(t)
+    MakeUnionType(Vec<Symbol>),
+    // ..., t1, ..., tn => ..., { v1: t1 | ... | vn: tn }
+    MakeVariant(Symbol),
+    // ..., v, u => ..., the variant of union type u with the given tag holding v
+    IsVariant(Symbol),
+    // ..., v => ..., whether v is the variant with the given tag
+    Payload,
+    // ..., v => ..., the value held by variant v
+    Impl(Vec<Symbol>),
+    // ..., t, f1, ..., fn => ..., attaching f1, ..., fn to type t under the given names
+    MakeClosure(Rc<Function>),
+    // ..., => ..., f, capturing the current scope
     Pop,
     Dup,
+    // ..., a => ..., a, a
     Jump(usize),
-    // jump to instruction
+    // continue at the instruction with the given index. jump targets are absolute: the index counts from the
+    // start of the function's instructions, not from the jump, and the target itself is executed next.
     JumpIf(usize),
-    // jump to instruction if value on top of stack is true
+    // ..., b => ..., continuing at the instruction with the given index if b is true and at the next one
+    // otherwise
+    JumpIfNot(usize),
+    // ..., b => ..., the same as JumpIf but jumping if b is false. only emitted by the optimizer.
     Return,
     // return top value on stack
-    Store(String),
-    // store top value on stack to variable slot, consuming it
-    Load(String),
-    // push value in variable slot to stack
+    DeclareGlobal(Symbol),
+    // bind top value on stack to a new global, consuming it
+    StoreGlobal(Symbol),
+    // store top value on stack to a global, consuming it
+    LoadGlobal(Symbol),
+    // push value of a global to stack
+    StoreLocal(u16),
+    // store top value on stack to a slot of the current call, consuming it
+    LoadLocal(u16),
+    // push value in a slot of the current call to stack
+    StoreCaptured(u16, u16),
+    // store top value on stack to a slot of the scope the given number of functions out, consuming it
+    LoadCaptured(u16, u16),
+    // push value in a slot of the scope the given number of functions out to stack
     Typeof, // ..., a => ..., typeof(a)
 }
 
+// what the VM must do after a frame executes an instruction
+enum Step {
+    Continue,
+    Call(Rc<Closure>, Vec<KytheraVal>),
+    CallNative(Rc<Native>, Vec<KytheraVal>),
+    // a call replacing the frame making it
+    TailCall(Rc<Closure>, Vec<KytheraVal>),
+    TailCallNative(Rc<Native>, Vec<KytheraVal>),
+    Return(KytheraVal),
+}
+
 struct Frame {
     stack: Vec<KytheraVal>,
-    instructions: Vec<Instruction>,
-    scope: HashMap<String, KytheraVal>,
+    function: Rc<Function>,
+    scope: Rc<RefCell<Scope>>,
+    globals: Rc<RefCell<Scope>>,
     pc: usize,
 }
 
 impl Frame {
-    fn new(from: Vec<Instruction>) -> Frame {
+    fn new(function: Rc<Function>, scope: Rc<RefCell<Scope>>, globals: Rc<RefCell<Scope>>) -> Frame {
         Frame {
             stack: Vec::new(),
-            instructions: from,
-            scope: HashMap::new(),
+            function,
+            scope,
+            globals,
             pc: 0,
         }
     }
 
-    fn run(&mut self) {}
+    // the scope of the function the given number of functions out from this one
+    fn outer(&self, depth: u16) -> Option<Rc<RefCell<Scope>>> {
+        let mut scope = Rc::clone(&self.scope);
+        for _ in 0..depth {
+            let parent = scope.borrow().parent.clone()?;
+            scope = parent;
+        }
+
+        Some(scope)
+    }
+
+    fn pop(&mut self) -> Result<KytheraVal, ErrorKind> {
+        self.stack.pop().ok_or(ErrorKind::StackUnderflow)
+    }
+
+    // pop the top n values, in the order they were pushed
+    fn pop_n(&mut self, n: usize) -> Result<Vec<KytheraVal>, ErrorKind> {
+        let start = self.stack.len().checked_sub(n).ok_or(ErrorKind::StackUnderflow)?;
+        Ok(self.stack.split_off(start))
+    }
+
+    // where in the source the instruction at pc came from, if known
+    fn loc(&self, pc: usize) -> Option<Loc> {
+        self.function.locs.get(pc).copied()
+    }
 
-    fn step(&mut self) {
-        let inst = self.instructions.get(self.pc).expect("Execution ended without halting properly");
+    fn step(&mut self, methods: &mut Methods<KytheraVal>) -> Result<Step, ErrorKind> {
+        let function = Rc::clone(&self.function);
+        let inst = function.instructions.get(self.pc).ok_or(ErrorKind::MissingReturn)?;
+        // the instruction to execute after this one. only jumps change it, and it is only stored once the
+        // instruction succeeds, so errors are reported at the instruction that failed.
+        let mut next = self.pc + 1;
 
         match inst {
             Instruction::Nop => {}
-            Instruction::Add => {
-                let a = self.stack.pop().expect("");
-                let b = self.stack.pop().expect("");
+            Instruction::Constant(index) => {
+                let val = function.constants.get(*index as usize).ok_or(ErrorKind::InvalidConstant(*index))?;
+                self.stack.push(val.clone());
+            }
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Mod
+            | Instruction::Equal
+            | Instruction::NotEqual
+            | Instruction::Less
+            | Instruction::LessEqual
+            | Instruction::Greater
+            | Instruction::GreaterEqual
+            | Instruction::Or
+            | Instruction::And
+            | Instruction::Xor
+            | Instruction::ShiftLeft
+            | Instruction::ShiftRight => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.stack.push(binary(inst, a, b)?);
+            }
+            Instruction::Not => {
+                let a = self.pop()?;
+                self.stack.push(not(a)?);
             }
-            Instruction::Invoke => {
-                let f = self.stack.pop().expect("");
+            Instruction::Invoke(arg_count) | Instruction::TailInvoke(arg_count) => {
+                let f = self.pop()?;
+                let args = self.pop_n(*arg_count)?;
+                check_arity(&f, args.len())?;
 
-                // read function type to see parameter count
-                // pop parameters
+                let tail = matches!(inst, Instruction::TailInvoke(_));
+                let step = match f.val {
+                    InternalVal::Fn(closure) if tail => Step::TailCall(closure, args),
+                    InternalVal::Native(native) if tail => Step::TailCallNative(native, args),
+                    InternalVal::Fn(closure) => Step::Call(closure, args),
+                    InternalVal::Native(native) => Step::CallNative(native, args),
+                    _ => return Err(f.mismatch("function")),
+                };
+                // the call returns to the instruction after this one
+                self.pc = next;
+                return Ok(step);
+            }
+            Instruction::Field(name) => {
+                let target = self.pop()?;
+
+                let field = match &target.val {
+                    InternalVal::Struct(fields) => fields.get(name).cloned(),
+                    // a variant of a union type, e.g. Option.none, or a function constructing one, e.g. Option.some,
+                    // or else a function attached to the type itself, e.g. Point.new
+                    InternalVal::Type(t) => variant_constructor(t, name).or_else(|| methods.get(t, name).cloned()),
+                    _ => None,
+                };
+                let field = match (field, &target.val) {
+                    (None, InternalVal::Type(_)) => None,
+                    // a method, bound to the value it was accessed through
+                    (None, _) => match methods.get(&target.type_val, name) {
+                        Some(method) => Some(bind(method, target.clone())?),
+                        None => None,
+                    },
+                    (field, _) => field,
+                };
 
-                // execute function with parameters in scope
+                self.stack.push(field.ok_or_else(|| ErrorKind::MissingField {
+                    target: target.type_val.as_ref().clone(),
+                    field: name.to_string(),
+                })?);
+            }
+            Instruction::Impl(names) => {
+                let functions = self.pop_n(names.len())?;
+                let target = self.pop()?.as_type()?;
+
+                for (name, function) in names.iter().zip(functions) {
+                    // a method is called with the value it was accessed through as its first argument
+                    let is_method = match (&function.val, function.type_val.as_ref()) {
+                        (InternalVal::Fn(_) | InternalVal::Native(_), Type::Fn { params, .. }) => !params.is_empty(),
+                        _ => false,
+                    };
+                    if !is_method {
+                        return Err(function.mismatch("method"));
+                    }
+
+                    methods.insert(target.clone(), name.clone(), function);
+                }
+            }
+            Instruction::MakeStruct(names) => {
+                let vals = self.pop_n(names.len())?;
+                self.stack
+                    .push(KytheraVal::structure(names.iter().cloned().zip(vals).collect()));
+            }
+            Instruction::Spread => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+
+                match (&a.val, &b.val) {
+                    (InternalVal::Struct(a), InternalVal::Struct(b)) => {
+                        let mut fields = a.as_ref().clone();
+                        for (name, field) in b.iter() {
+                            fields.insert(name.clone(), field.clone());
+                        }
+
+                        self.stack.push(KytheraVal::structure(fields));
+                    }
+                    _ => return Err(invalid_operands(inst, &a, &b)),
+                }
+            }
+            Instruction::MakeList(len) => {
+                let elements = self.pop_n(*len)?;
+                let element_type = elements.first().ok_or(ErrorKind::EmptyList)?.type_val.as_ref().clone();
+                self.stack.push(KytheraVal::list(element_type, elements));
+            }
+            Instruction::Index => {
+                let index = self.pop()?;
+                let target = self.pop()?;
+
+                match (&target.val, &index.val) {
+                    (InternalVal::List(elements), InternalVal::Int(i)) => {
+                        let element = usize::try_from(*i)
+                            .ok()
+                            .and_then(|i| elements.get(i))
+                            .ok_or(ErrorKind::IndexOutOfBounds {
+                                index: *i,
+                                len: elements.len(),
+                            })?;
+
+                        self.stack.push(element.clone());
+                    }
+                    _ => return Err(invalid_operands(inst, &target, &index)),
+                }
+            }
+            Instruction::MakeStructType(names) => {
+                let types = self.pop_n(names.len())?;
+                let fields = names
+                    .iter()
+                    .cloned()
+                    .zip(types.iter())
+                    .map(|(name, t)| Ok((name, t.as_type()?)))
+                    .collect::<Result<_, ErrorKind>>()?;
+                self.stack.push(KytheraVal::type_val(Type::Struct(fields)));
+            }
+            Instruction::MakeFnType(param_count) => {
+                let returns = self.pop()?.as_type()?;
+                let params = self.pop_n(*param_count)?;
+                self.stack.push(KytheraVal::type_val(Type::Fn {
+                    params: params.iter().map(KytheraVal::as_type).collect::<Result<_, _>>()?,
+                    returns: Box::from(returns),
+                }));
+            }
+            Instruction::MakeListType => {
+                let element_type = self.pop()?.as_type()?;
+                self.stack.push(KytheraVal::type_val(Type::List(Box::from(element_type))));
+            }
+            Instruction::MakeUnionType(tags) => {
+                let types = self.pop_n(tags.len())?;
+                let variants = tags
+                    .iter()
+                    .cloned()
+                    .zip(types.iter())
+                    .map(|(name, t)| Ok((name, t.as_type()?)))
+                    .collect::<Result<_, ErrorKind>>()?;
+                self.stack.push(KytheraVal::type_val(Type::Union(variants)));
+            }
+            Instruction::MakeVariant(tag) => {
+                let union_type = self.pop()?.as_type()?;
+                let value = self.pop()?;
+                self.stack.push(KytheraVal::variant(union_type, tag.clone(), value));
+            }
+            Instruction::IsVariant(tag) => {
+                let v = self.pop()?;
+
+                match &v.val {
+                    InternalVal::Variant(v_tag, _) => self.stack.push(KytheraVal::bool(v_tag == tag)),
+                    _ => return Err(v.mismatch("union")),
+                }
+            }
+            Instruction::Payload => {
+                let v = self.pop()?;
+
+                match &v.val {
+                    InternalVal::Variant(_, value) => self.stack.push(value.as_ref().clone()),
+                    _ => return Err(v.mismatch("union")),
+                }
+            }
+            Instruction::MakeClosure(function) => {
+                self.stack.push(KytheraVal {
+                    val: InternalVal::Fn(Rc::new(Closure {
+                        function: Rc::clone(function),
+                        scope: Rc::clone(&self.scope),
+                        receiver: None,
+                    })),
+                    type_val: Rc::clone(&function.type_val),
+                });
             }
-            Instruction::Field(name) => {}
             Instruction::Pop => {
-                self.stack.pop();
+                self.pop()?;
+            }
+            Instruction::Dup => {
+                let top = self.stack.last().ok_or(ErrorKind::StackUnderflow)?.clone();
+                self.stack.push(top);
+            }
+            Instruction::Jump(t) => next = *t,
+            Instruction::JumpIf(t) => {
+                if self.pop()?.as_bool()? {
+                    next = *t;
+                }
+            }
+            Instruction::JumpIfNot(t) => {
+                if !self.pop()?.as_bool()? {
+                    next = *t;
+                }
+            }
+            Instruction::Return => {
+                return Ok(Step::Return(self.pop()?));
+            }
+            Instruction::DeclareGlobal(name) => {
+                let val = self.pop()?;
+                self.globals.borrow_mut().vars.insert(name.clone(), val);
+            }
+            Instruction::StoreGlobal(name) => {
+                let val = self.pop()?;
+                let mut globals = self.globals.borrow_mut();
+                let global = globals
+                    .vars
+                    .get_mut(name)
+                    .ok_or_else(|| ErrorKind::UndefinedVariable(name.to_string()))?;
+                *global = val;
+            }
+            Instruction::LoadGlobal(name) => {
+                let val = self.globals.borrow().vars.get(name).cloned();
+                self.stack.push(val.ok_or_else(|| ErrorKind::UndefinedVariable(name.to_string()))?);
+            }
+            Instruction::StoreLocal(slot) => {
+                let val = self.pop()?;
+                *self.scope.borrow_mut().slot(*slot)? = val;
             }
-            Instruction::Dup => {}
-            Instruction::Jump(t) => {
-                self.pc = t.to_owned();
+            Instruction::LoadLocal(slot) => {
+                let val = self.scope.borrow_mut().slot(*slot)?.clone();
+                self.stack.push(val);
             }
-            Instruction::Return => {}
-            _ => {
-                panic!("Unimplemented instruction.");
+            Instruction::StoreCaptured(depth, slot) => {
+                let val = self.pop()?;
+                let scope = self.outer(*depth).ok_or(ErrorKind::InvalidSlot(*slot))?;
+                *scope.borrow_mut().slot(*slot)? = val;
+            }
+            Instruction::LoadCaptured(depth, slot) => {
+                let scope = self.outer(*depth).ok_or(ErrorKind::InvalidSlot(*slot))?;
+                let val = scope.borrow_mut().slot(*slot)?.clone();
+                self.stack.push(val);
+            }
+            Instruction::Typeof => {
+                let a = self.pop()?;
+                self.stack.push(KytheraVal::type_val(a.type_val.as_ref().clone()));
             }
         }
 
-        self.pc += 1;
+        self.pc = next;
+
+        Ok(Step::Continue)
+    }
+}
+
+// the variant of a union type with the given tag if it holds unit, e.g. Option.none, otherwise a function
+// constructing the variant from a value, e.g. Option.some
+fn variant_constructor(union_type: &Rc<Type>, tag: &Symbol) -> Option<KytheraVal> {
+    let variant_type = match union_type.as_ref() {
+        Type::Union(variants) => variants.get(tag)?,
+        _ => return None,
+    };
+
+    if *variant_type == Type::Unit {
+        return Some(KytheraVal::variant(union_type.as_ref().clone(), tag, KytheraVal::unit()));
+    }
+
+    let function = Function {
+        name: tag.to_string(),
+        params: vec!["value".to_string()],
+        slots: 1,
+        instructions: vec![
+            Instruction::LoadLocal(0),
+            Instruction::Constant(0),
+            Instruction::MakeVariant(tag.clone()),
+            Instruction::Return,
+        ],
+        constants: vec![KytheraVal {
+            val: InternalVal::Type(Rc::clone(union_type)),
+            type_val: builtin_type(|types| &types.type_type),
+        }],
+        locs: Vec::new(),
+        type_val: Rc::new(Type::Fn {
+            params: vec![variant_type.clone()],
+            returns: Box::from(union_type.as_ref().clone()),
+        }),
+    };
+
+    Some(KytheraVal {
+        type_val: Rc::clone(&function.type_val),
+        val: InternalVal::Fn(Rc::new(Closure {
+            function: Rc::new(function),
+            scope: Rc::new(RefCell::new(Scope::new(Vec::new(), None))),
+            receiver: None,
+        })),
+    })
+}
+
+// whether f is a function taking the given number of arguments
+fn check_arity(f: &KytheraVal, got: usize) -> Result<(), ErrorKind> {
+    match f.type_val.as_ref() {
+        Type::Fn { params, .. } if params.len() == got => Ok(()),
+        Type::Fn { params, .. } => Err(ErrorKind::ArityMismatch {
+            expected: params.len(),
+            got,
+        }),
+        _ => Err(f.mismatch("function")),
+    }
+}
+
+// bind a method to the value it was accessed through
+fn bind(method: &KytheraVal, receiver: KytheraVal) -> Result<KytheraVal, ErrorKind> {
+    let (params, returns) = match method.type_val.as_ref() {
+        Type::Fn { params, returns } if !params.is_empty() => (params, returns),
+        _ => return Err(method.mismatch("method")),
+    };
+
+    let val = match &method.val {
+        InternalVal::Fn(closure) => InternalVal::Fn(Rc::new(Closure {
+            function: Rc::clone(&closure.function),
+            scope: Rc::clone(&closure.scope),
+            receiver: Some(receiver),
+        })),
+        InternalVal::Native(native) => InternalVal::Native(Rc::new(Native {
+            name: native.name.clone(),
+            function: Rc::clone(&native.function),
+            receiver: Some(receiver),
+        })),
+        _ => return Err(method.mismatch("method")),
+    };
+
+    Ok(KytheraVal {
+        val,
+        type_val: Rc::new(Type::Fn {
+            params: params[1..].to_vec(),
+            returns: returns.clone(),
+        }),
+    })
+}
+
+// apply a binary operator instruction to its operands. the compiler uses this to evaluate constant
+// expressions, so errors are returned rather than raised.
+pub fn binary(inst: &Instruction, a: KytheraVal, b: KytheraVal) -> Result<KytheraVal, ErrorKind> {
+    match inst {
+        Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div | Instruction::Mod => {
+            arithmetic(inst, a, b)
+        }
+        Instruction::Equal => Ok(KytheraVal::bool(a == b)),
+        Instruction::NotEqual => Ok(KytheraVal::bool(a != b)),
+        Instruction::Less | Instruction::LessEqual | Instruction::Greater | Instruction::GreaterEqual => {
+            comparison(inst, a, b)
+        }
+        Instruction::Or | Instruction::And | Instruction::Xor | Instruction::ShiftLeft | Instruction::ShiftRight => {
+            bitwise(inst, a, b)
+        }
+        _ => unreachable!("{:?} is not a binary operator.", inst),
+    }
+}
+
+fn invalid_operands(inst: &Instruction, a: &KytheraVal, b: &KytheraVal) -> ErrorKind {
+    ErrorKind::InvalidOperands {
+        op: format!("{:?}", inst),
+        lhs: a.type_val.as_ref().clone(),
+        rhs: b.type_val.as_ref().clone(),
+    }
+}
+
+// logical not on Bool, bitwise not on Int
+pub fn not(a: KytheraVal) -> Result<KytheraVal, ErrorKind> {
+    match a.val {
+        InternalVal::Bool(a) => Ok(KytheraVal::bool(!a)),
+        InternalVal::Int(a) => Ok(KytheraVal::int(!a)),
+        _ => Err(a.mismatch("Bool or Int")),
+    }
+}
+
+fn arithmetic(inst: &Instruction, a: KytheraVal, b: KytheraVal) -> Result<KytheraVal, ErrorKind> {
+    match (&a.val, &b.val) {
+        (InternalVal::Int(a), InternalVal::Int(b)) => {
+            let result = match inst {
+                Instruction::Add => a.checked_add(*b),
+                Instruction::Sub => a.checked_sub(*b),
+                Instruction::Mul => a.checked_mul(*b),
+                Instruction::Div | Instruction::Mod if *b == 0 => return Err(ErrorKind::DivisionByZero),
+                Instruction::Div => a.checked_div(*b),
+                Instruction::Mod => a.checked_rem(*b),
+                _ => unreachable!(),
+            };
+
+            result.map(KytheraVal::int).ok_or(ErrorKind::IntegerOverflow)
+        }
+        (InternalVal::Double(a), InternalVal::Double(b)) => Ok(KytheraVal::double(match inst {
+            Instruction::Add => a + b,
+            Instruction::Sub => a - b,
+            Instruction::Mul => a * b,
+            Instruction::Div => a / b,
+            Instruction::Mod => a % b,
+            _ => unreachable!(),
+        })),
+        (InternalVal::String(a), InternalVal::String(b)) if matches!(inst, Instruction::Add) => {
+            Ok(KytheraVal::string(format!("{}{}", a, b)))
+        }
+        _ => Err(invalid_operands(inst, &a, &b)),
+    }
+}
+
+fn bitwise(inst: &Instruction, a: KytheraVal, b: KytheraVal) -> Result<KytheraVal, ErrorKind> {
+    match (inst, &a.val, &b.val) {
+        (Instruction::Or, InternalVal::Bool(a), InternalVal::Bool(b)) => Ok(KytheraVal::bool(a | b)),
+        (Instruction::And, InternalVal::Bool(a), InternalVal::Bool(b)) => Ok(KytheraVal::bool(a & b)),
+        (Instruction::Xor, InternalVal::Bool(a), InternalVal::Bool(b)) => Ok(KytheraVal::bool(a ^ b)),
+        (Instruction::Or, InternalVal::Int(a), InternalVal::Int(b)) => Ok(KytheraVal::int(a | b)),
+        (Instruction::And, InternalVal::Int(a), InternalVal::Int(b)) => Ok(KytheraVal::int(a & b)),
+        (Instruction::Xor, InternalVal::Int(a), InternalVal::Int(b)) => Ok(KytheraVal::int(a ^ b)),
+        (Instruction::ShiftLeft, InternalVal::Int(a), InternalVal::Int(b))
+        | (Instruction::ShiftRight, InternalVal::Int(a), InternalVal::Int(b)) => {
+            if *b < 0 {
+                return Err(ErrorKind::NegativeShift(*b));
+            }
+
+            // shifting by the full width or more leaves nothing but the fill bits
+            let amount = (*b).min(31) as u32;
+            Ok(KytheraVal::int(match inst {
+                Instruction::ShiftLeft if *b > 31 => 0,
+                Instruction::ShiftLeft => a << amount,
+                _ => a >> amount,
+            }))
+        }
+        _ => Err(invalid_operands(inst, &a, &b)),
+    }
+}
+
+fn comparison(inst: &Instruction, a: KytheraVal, b: KytheraVal) -> Result<KytheraVal, ErrorKind> {
+    let ordering = match (&a.val, &b.val) {
+        (InternalVal::Int(a), InternalVal::Int(b)) => a.partial_cmp(b),
+        (InternalVal::Double(a), InternalVal::Double(b)) => a.partial_cmp(b),
+        (InternalVal::String(a), InternalVal::String(b)) => a.partial_cmp(b),
+        _ => return Err(invalid_operands(inst, &a, &b)),
+    };
+
+    // comparisons involving NaN are always false
+    Ok(KytheraVal::bool(ordering.is_some_and(|ordering| match inst {
+        Instruction::Less => ordering.is_lt(),
+        Instruction::LessEqual => ordering.is_le(),
+        Instruction::Greater => ordering.is_gt(),
+        Instruction::GreaterEqual => ordering.is_ge(),
+        _ => unreachable!(),
+    })))
+}
+
+// bounds on what a program may use, for running code that is not trusted. None means no bound. fuel and
+// timeout apply to each run or call from the host as a whole, the others to what is in use at any one time.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    // instructions executed
+    pub fuel: Option<u64>,
+    // frames on the call stack at once, including the program's
+    pub max_call_depth: Option<usize>,
+    // values on the operand stacks of all frames at once
+    pub max_stack: Option<usize>,
+    // bytes held at once by strings, structs, lists, variants, closures and scopes, including globals. what is
+    // allocated is added up until it passes the limit, and only then is garbage collected and what is still
+    // alive measured, so a program can free memory and reuse it.
+    pub max_heap: Option<usize>,
+    // wall-clock time
+    pub timeout: Option<Duration>,
+}
+
+// how much of its limits the current run has used
+#[derive(Default)]
+struct Usage {
+    steps: u64,
+    stack: usize,
+    // the size of the heap when it was last measured, plus what was allocated since. unlike the rest, this
+    // carries over from one run to the next.
+    heap: usize,
+    deadline: Option<Instant>,
+}
+
+// how many instructions to execute between looking at the clock
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+// roughly how many bytes were allocated for a value, not counting the values it holds
+fn allocation_size(val: &KytheraVal) -> usize {
+    match &val.val {
+        InternalVal::String(s) => s.len(),
+        InternalVal::Struct(fields) => fields.len() * mem::size_of::<(Symbol, KytheraVal)>(),
+        InternalVal::List(elements) => elements.len() * mem::size_of::<KytheraVal>(),
+        InternalVal::Variant(_, _) => mem::size_of::<KytheraVal>(),
+        InternalVal::Fn(_) => mem::size_of::<Closure>(),
+        InternalVal::Native(_) => mem::size_of::<Native>(),
+        _ => 0,
+    }
+}
+
+pub struct Vm {
+    frames: Vec<Frame>,
+    globals: Rc<RefCell<Scope>>,
+    methods: Methods<KytheraVal>,
+    // where print and println write to
+    output: Box<dyn Write>,
+    // globals the host has defined, which the compiler needs to be told about
+    defined: Vec<String>,
+    limits: Limits,
+    usage: Usage,
+    heap: Heap,
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Vm {
+        Vm::with_output(Box::new(io::stdout()))
+    }
+
+    pub fn with_output(output: Box<dyn Write>) -> Vm {
+        let mut globals = Scope::new(Vec::new(), None);
+
+        // built-in types
+        for t in [Type::Unit, Type::Int, Type::Double, Type::Bool, Type::String, Type::Type] {
+            globals.vars.insert(Symbol::from(t.to_string()), KytheraVal::type_val(t));
+        }
+
+        let globals = Rc::new(RefCell::new(globals));
+        let mut heap = Heap::new();
+        heap.track(&globals);
+
+        // built-in type constructors, which are ordinary functions from types to types
+        let type_constructors = [
+            ("List", vec!["T"], Instruction::MakeListType),
+            ("Result", vec!["T", "E"], Instruction::MakeUnionType(vec![Symbol::from("ok"), Symbol::from("err")])),
+        ];
+        for (name, params, make_type) in type_constructors {
+            let mut instructions: Vec<Instruction> = (0..params.len() as u16).map(Instruction::LoadLocal).collect();
+            instructions.push(make_type);
+            instructions.push(Instruction::Return);
+
+            let function = Function {
+                name: name.to_string(),
+                params: params.iter().map(|param| param.to_string()).collect(),
+                slots: params.len(),
+                instructions,
+                constants: Vec::new(),
+                locs: Vec::new(),
+                type_val: Rc::new(Type::Fn {
+                    params: vec![Type::Type; params.len()],
+                    returns: Box::from(Type::Type),
+                }),
+            };
+            let constructor = KytheraVal {
+                type_val: Rc::clone(&function.type_val),
+                val: InternalVal::Fn(Rc::new(Closure {
+                    function: Rc::new(function),
+                    scope: Rc::clone(&globals),
+                    receiver: None,
+                })),
+            };
+            globals.borrow_mut().vars.insert(Symbol::from(name), constructor);
+        }
+
+        let mut methods = Methods::new();
+
+        for builtin in prelude::functions() {
+            let native = KytheraVal::native(builtin.name, builtin.type_val, builtin.function);
+            globals.borrow_mut().vars.insert(Symbol::from(builtin.name), native);
+        }
+        for (target, builtin) in prelude::methods() {
+            let native = KytheraVal::native(builtin.name, builtin.type_val, builtin.function);
+            methods.insert(target, builtin.name, native);
+        }
+
+        Vm {
+            frames: Vec::new(),
+            globals,
+            methods,
+            output,
+            defined: Vec::new(),
+            limits: Limits::default(),
+            usage: Usage::default(),
+            heap,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    // free every value only kept alive by reference cycles. this also happens on its own as programs run.
+    pub fn gc(&mut self) -> GcStats {
+        self.heap.collect()
+    }
+
+    // statistics as of the last collection
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.stats()
+    }
+
+    // make a value available to programs as a constant global
+    pub fn define(&mut self, name: &str, val: KytheraVal) {
+        self.globals.borrow_mut().vars.insert(Symbol::from(name), val);
+
+        if !self.defined.iter().any(|defined| defined == name) {
+            self.defined.push(name.to_string());
+        }
+    }
+
+    pub fn define_value<T: IntoKythera>(&mut self, name: &str, val: T) {
+        self.define(name, val.into_kythera());
+    }
+
+    // make a Rust function available to programs, with a Kythera type following from its Rust type
+    pub fn define_fn<Args, F: HostFn<Args>>(&mut self, name: &str, f: F) {
+        self.define(name, f.into_native(name));
+    }
+
+    // the names and types of the globals the host has defined
+    pub fn defined(&self) -> Vec<(String, Type)> {
+        let globals = self.globals.borrow();
+
+        self.defined
+            .iter()
+            .map(|name| (name.clone(), globals.vars[&Symbol::from(name)].type_val.as_ref().clone()))
+            .collect()
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<KytheraVal> {
+        self.globals.borrow().vars.get(&Symbol::from(name)).cloned()
+    }
+
+    pub fn write_output(&mut self, text: &str) -> io::Result<()> {
+        self.output.write_all(text.as_bytes())?;
+        self.output.flush()
+    }
+
+    // run a compiled program in the global scope, returning the value it returns or the error that stopped
+    // it
+    pub fn run(&mut self, program: Rc<Function>) -> Result<KytheraVal, RuntimeError> {
+        self.start();
+        let depth = self.frames.len();
+        self.frames.push(Frame::new(program, Rc::clone(&self.globals), Rc::clone(&self.globals)));
+        self.execute(depth)
+    }
+
+    // call a Kythera or native function with the given arguments, returning the value it returns
+    pub fn call(&mut self, f: &KytheraVal, args: Vec<KytheraVal>) -> Result<KytheraVal, RuntimeError> {
+        if let Err(kind) = check_arity(f, args.len()) {
+            return Err(RuntimeError {
+                kind,
+                trace: self.trace(false),
+            });
+        }
+
+        self.start();
+
+        match &f.val {
+            InternalVal::Fn(closure) => {
+                let depth = self.frames.len();
+                if let Err(kind) = self.enter(closure, args) {
+                    return Err(RuntimeError {
+                        kind,
+                        trace: self.trace(false),
+                    });
+                }
+
+                self.execute(depth)
+            }
+            InternalVal::Native(native) => self.call_native(native, args),
+            _ => unreachable!("{} was checked to be a function.", f.type_val),
+        }
+    }
+
+    // step through frames until the one above depth returns. if an error stops it, every frame above depth
+    // is discarded.
+    fn execute(&mut self, depth: usize) -> Result<KytheraVal, RuntimeError> {
+        loop {
+            if let Err(kind) = self.charge() {
+                return Err(self.fail(kind, true, depth));
+            }
+
+            let frame = self.frames.last_mut().expect("No frame to execute.");
+            let allocates = matches!(
+                frame.function.instructions.get(frame.pc),
+                Some(
+                    Instruction::Add
+                        | Instruction::MakeStruct(_)
+                        | Instruction::Spread
+                        | Instruction::MakeList(_)
+                        | Instruction::MakeVariant(_)
+                        | Instruction::MakeClosure(_)
+                )
+            );
+            let stack_before = frame.stack.len();
+            let step = frame.step(&mut self.methods);
+            self.usage.stack = self.usage.stack + frame.stack.len() - stack_before;
+
+            // a native called in tail position returns its result straight away
+            let step = match step {
+                Ok(Step::TailCallNative(native, args)) => match self.call_native(&native, args) {
+                    Ok(val) => match self.allocate(allocation_size(&val), false) {
+                        Ok(()) => Ok(Step::Return(val)),
+                        Err(kind) => return Err(self.fail(kind, false, depth)),
+                    },
+                    Err(e) => return Err(self.unwind(e, depth)),
+                },
+                step => step,
+            };
+
+            let result = match step {
+                Ok(Step::Continue) => {
+                    let frame = self.frames.last().expect("No frame to execute.");
+                    let allocated = match frame.stack.last() {
+                        Some(val) if allocates => allocation_size(val),
+                        _ => 0,
+                    };
+                    self.allocate(allocated, true).and_then(|_| self.check_stack())
+                }
+                Ok(Step::Call(closure, args)) => self.enter(&closure, args),
+                // the callee returns to whatever the current frame would have returned to
+                Ok(Step::TailCall(closure, args)) => {
+                    let frame = self.frames.pop().expect("No frame to call from.");
+                    self.usage.stack -= frame.stack.len();
+                    self.enter(&closure, args)
+                }
+                Ok(Step::TailCallNative(..)) => unreachable!("Native tail calls return straight away."),
+                Ok(Step::CallNative(native, args)) => match self.call_native(&native, args) {
+                    Ok(val) => self.allocate(allocation_size(&val), false).and_then(|_| self.push(val)),
+                    Err(e) => return Err(self.unwind(e, depth)),
+                },
+                Ok(Step::Return(val)) => {
+                    let frame = self.frames.pop().expect("No frame to return from.");
+                    self.usage.stack -= frame.stack.len();
+
+                    if self.frames.len() == depth {
+                        return Ok(val);
+                    }
+
+                    self.push(val)
+                }
+                Err(kind) => return Err(self.fail(kind, true, depth)),
+            };
+
+            // errors from calling, returning or allocating happen after the Invoke has moved on
+            if let Err(kind) = result {
+                return Err(self.fail(kind, false, depth));
+            }
+        }
+    }
+
+    // reset usage for a run or call from the host, which nothing else is running under
+    fn start(&mut self) {
+        if self.frames.is_empty() {
+            self.usage = Usage {
+                heap: self.usage.heap,
+                deadline: self.limits.timeout.map(|timeout| Instant::now() + timeout),
+                ..Usage::default()
+            };
+        }
+    }
+
+    // account for executing one instruction
+    fn charge(&mut self) -> Result<(), ErrorKind> {
+        if let Some(deadline) = self.usage.deadline {
+            if self.usage.steps.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Err(ErrorKind::DeadlineExceeded(self.limits.timeout.unwrap_or_default()));
+            }
+        }
+
+        self.usage.steps += 1;
+        match self.limits.fuel {
+            Some(fuel) if self.usage.steps > fuel => Err(ErrorKind::OutOfFuel(fuel)),
+            _ => Ok(()),
+        }
+    }
+
+    // account for bytes just allocated, for something a frame or scope already holds if held is set
+    fn allocate(&mut self, bytes: usize, held: bool) -> Result<(), ErrorKind> {
+        self.usage.heap += bytes;
+        match self.limits.max_heap {
+            Some(max) if self.usage.heap > max => {
+                self.heap.collect();
+                let live = self.heap.size(self.frames.iter().flat_map(|frame| &frame.stack));
+                self.usage.heap = if held { live } else { live + bytes };
+
+                if self.usage.heap > max {
+                    Err(ErrorKind::HeapExceeded(max))
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_stack(&self) -> Result<(), ErrorKind> {
+        match self.limits.max_stack {
+            Some(max) if self.usage.stack > max => Err(ErrorKind::StackOverflow(max)),
+            _ => Ok(()),
+        }
+    }
+
+    fn fail(&mut self, kind: ErrorKind, faulted: bool, depth: usize) -> RuntimeError {
+        let e = RuntimeError {
+            kind,
+            trace: self.trace(faulted),
+        };
+        self.unwind(e, depth)
+    }
+
+    // discard every frame above depth, which an error has stopped
+    fn unwind(&mut self, e: RuntimeError, depth: usize) -> RuntimeError {
+        for frame in self.frames.drain(depth..) {
+            self.usage.stack -= frame.stack.len();
+        }
+
+        e
+    }
+
+    // push a frame for a call to a Kythera function
+    fn enter(&mut self, closure: &Closure, args: Vec<KytheraVal>) -> Result<(), ErrorKind> {
+        if let Some(max) = self.limits.max_call_depth {
+            if self.frames.len() >= max {
+                return Err(ErrorKind::CallDepthExceeded(max));
+            }
+        }
+
+        // parameters take the first slots, and the variables declared in the function's body the rest
+        let mut slots = Vec::with_capacity(closure.function.slots);
+        slots.extend(closure.receiver.iter().cloned().chain(args));
+        slots.resize(closure.function.slots.max(slots.len()), KytheraVal::unit());
+        self.allocate(mem::size_of::<Scope>() + slots.len() * mem::size_of::<KytheraVal>(), false)?;
+
+        let scope = Rc::new(RefCell::new(Scope::new(slots, Some(Rc::clone(&closure.scope)))));
+        if self.heap.track(&scope) {
+            self.heap.collect();
+        }
+
+        self.frames.push(Frame::new(Rc::clone(&closure.function), scope, Rc::clone(&self.globals)));
+        Ok(())
+    }
+
+    fn call_native(&mut self, native: &Native, args: Vec<KytheraVal>) -> Result<KytheraVal, RuntimeError> {
+        let args = native.receiver.iter().cloned().chain(args).collect();
+        let function = Rc::clone(&native.function);
+
+        function(self, args).map_err(|mut e| {
+            // an error raised by the native itself has no trace yet
+            if e.trace.is_empty() {
+                e.trace = self.trace(false);
+            }
+
+            // natives have no frame, so they go in the trace just above the frame that called them
+            let at = e.trace.len().saturating_sub(self.frames.len());
+            e.trace.insert(
+                at,
+                TraceFrame {
+                    function: native.name.clone(),
+                    loc: None,
+                },
+            );
+            e
+        })
+    }
+
+    fn push(&mut self, val: KytheraVal) -> Result<(), ErrorKind> {
+        self.frames.last_mut().expect("No frame to return to.").stack.push(val);
+        self.usage.stack += 1;
+        self.check_stack()
+    }
+
+    // where each frame is, innermost first. the top frame is at the faulting instruction if faulted is set;
+    // every other frame has moved past the Invoke it is waiting on.
+    fn trace(&self, faulted: bool) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, frame)| {
+                let pc = if faulted && i == 0 { frame.pc } else { frame.pc.saturating_sub(1) };
+                TraceFrame {
+                    function: frame.function.name.clone(),
+                    loc: frame.loc(pc),
+                }
+            })
+            .collect()
     }
 }